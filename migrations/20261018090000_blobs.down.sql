@@ -0,0 +1,3 @@
+alter table story_files drop column sha256;
+
+drop table blobs;
@@ -0,0 +1,12 @@
+create table blobs (
+    sha256 text primary key,
+    storage_id uuid not null,
+    size bigint not null,
+    ref_count bigint not null default 0,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+alter table story_files add column sha256 text references blobs(sha256);
+
+create index story_files_sha256_index ON story_files USING btree(sha256);
@@ -0,0 +1,3 @@
+drop index blobs_storage_id_index;
+
+drop index story_files_storage_id_index;
@@ -0,0 +1,3 @@
+create index story_files_storage_id_index ON story_files USING btree(storage_id);
+
+create index blobs_storage_id_index ON blobs USING btree(storage_id);
@@ -0,0 +1,10 @@
+create table storage_deletions (
+    storage_id uuid primary key,
+    attempts integer not null default 0,
+    last_error text,
+    next_attempt_at timestamptz not null default now(),
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index storage_deletions_next_attempt_at_index ON storage_deletions USING btree(next_attempt_at);
@@ -0,0 +1,7 @@
+create table storage_migrations (
+    destination text not null,
+    storage_id uuid not null,
+    size bigint not null,
+    created_at timestamptz not null default now(),
+    primary key (destination, storage_id)
+);
@@ -0,0 +1,8 @@
+create table thumbnails (
+    source_id uuid primary key,
+    storage_id uuid not null unique,
+    content_type text not null,
+    size bigint not null,
+    sha256 text not null,
+    created_at timestamptz not null default now()
+);
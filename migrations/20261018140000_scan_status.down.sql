@@ -0,0 +1,3 @@
+drop index story_files_pending_scan_index;
+
+alter table story_files drop column scan_status;
@@ -0,0 +1,4 @@
+alter table story_files add column scan_status text not null default 'clean';
+alter table story_files alter column scan_status set default 'pending';
+
+create index story_files_pending_scan_index ON story_files USING btree(created_at) WHERE scan_status = 'pending';
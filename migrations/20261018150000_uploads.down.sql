@@ -0,0 +1,3 @@
+drop table upload_chunks;
+
+drop table uploads;
@@ -0,0 +1,25 @@
+create table uploads (
+    id uuid default gen_random_uuid() primary key,
+    story_id uuid references stories(id) not null,
+    name text not null,
+    content_type text not null,
+    upload_length bigint not null,
+    upload_offset bigint not null default 0,
+    expires_at timestamptz not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index uploads_story_id_index ON uploads USING btree(story_id);
+create index uploads_expires_at_index ON uploads USING btree(expires_at);
+
+create table upload_chunks (
+    upload_id uuid references uploads(id) not null,
+    upload_offset bigint not null,
+    storage_id uuid not null,
+    size bigint not null,
+    created_at timestamptz not null default now(),
+    primary key (upload_id, upload_offset)
+);
+
+create index upload_chunks_storage_id_index ON upload_chunks USING btree(storage_id);
@@ -0,0 +1,3 @@
+drop table file_versions;
+
+alter table story_files drop column version;
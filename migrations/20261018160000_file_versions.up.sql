@@ -0,0 +1,15 @@
+alter table story_files add column version integer not null default 1;
+
+create table file_versions (
+    file_id uuid references story_files(id) not null,
+    version integer not null,
+    storage_id uuid not null,
+    size bigint not null,
+    content_type text not null,
+    sha256 text references blobs(sha256),
+    scan_status text not null,
+    replaced_at timestamptz not null default now(),
+    primary key (file_id, version)
+);
+
+create index file_versions_storage_id_index ON file_versions USING btree(storage_id);
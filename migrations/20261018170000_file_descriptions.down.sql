@@ -0,0 +1 @@
+alter table story_files drop column description;
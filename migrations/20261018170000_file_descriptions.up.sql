@@ -0,0 +1 @@
+alter table story_files add column description text;
@@ -0,0 +1 @@
+drop table presigned_uploads;
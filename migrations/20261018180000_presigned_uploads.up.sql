@@ -0,0 +1,9 @@
+create table presigned_uploads (
+    storage_id uuid primary key,
+    story_id uuid references stories(id) not null,
+    expires_at timestamptz not null,
+    created_at timestamptz not null default now()
+);
+
+create index presigned_uploads_story_id_index ON presigned_uploads USING btree(story_id);
+create index presigned_uploads_expires_at_index ON presigned_uploads USING btree(expires_at);
@@ -0,0 +1 @@
+alter table uploads drop column claimed_at;
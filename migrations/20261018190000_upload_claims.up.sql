@@ -0,0 +1 @@
+alter table uploads add column claimed_at timestamptz;
@@ -0,0 +1,11 @@
+create table storage_repairs (
+    replica text not null,
+    storage_id uuid not null,
+    repair text not null,
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    queued_at timestamptz not null default clock_timestamp(),
+    primary key (replica, storage_id)
+);
+
+create index storage_repairs_next_attempt_at_index ON storage_repairs USING btree(replica, next_attempt_at);
@@ -0,0 +1,5 @@
+drop index storage_repairs_next_attempt_at_index;
+create index storage_repairs_next_attempt_at_index ON storage_repairs USING btree(replica, next_attempt_at);
+
+alter table storage_repairs drop column failed_at;
+alter table storage_repairs drop column last_error;
@@ -0,0 +1,5 @@
+alter table storage_repairs add column last_error text;
+alter table storage_repairs add column failed_at timestamptz;
+
+drop index storage_repairs_next_attempt_at_index;
+create index storage_repairs_next_attempt_at_index ON storage_repairs USING btree(replica, next_attempt_at) WHERE failed_at IS NULL;
@@ -0,0 +1,7 @@
+create table scan_failures (
+    storage_id uuid primary key,
+    attempts integer not null default 1,
+    last_error text not null,
+    next_attempt_at timestamptz not null,
+    updated_at timestamptz not null default now()
+);
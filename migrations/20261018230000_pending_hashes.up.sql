@@ -0,0 +1,8 @@
+create table pending_hashes (
+    storage_id uuid primary key,
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null,
+    created_at timestamptz not null default now()
+);
+
+create index pending_hashes_next_attempt_at_index ON pending_hashes USING btree(next_attempt_at);
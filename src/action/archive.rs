@@ -0,0 +1,100 @@
+use super::file::verify;
+use crate::{
+    api::Ctx,
+    domain::{unique_names, StoryFile, StoryId},
+    Error, Result,
+};
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use axum::{body::Body, response::AppendHeaders};
+use futures_util::{future, stream, AsyncWriteExt, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+use tokio_util::io::ReaderStream;
+
+// The buffer size between the archive writer and the response body.
+const PIPE_LEN: usize = 64 * 1024;
+
+/// Stream a ZIP archive of the files of a story. Entries are written as their contents are read
+/// from storage, so the archive is never held in memory.
+pub struct DownloadArchive;
+impl DownloadArchive {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+    ) -> Result<(AppendHeaders<Vec<(String, String)>>, Body)> {
+        let story = ctx.repo.fetch_story(story_id).await?;
+        let mut files = ctx.repo.list_files(story_id).await?;
+
+        // Leave out files that haven't passed a malware scan
+        files.retain(|file| match file.check_scanned() {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::warn!("leaving file out of archive: {}", err);
+                false
+            }
+        });
+        let names = unique_names(files.iter().map(|file| file.name.as_str()));
+        let entries = files.into_iter().zip(names).collect();
+
+        // Write the archive in the background, failing the body if the writer fails
+        let (reader, writer) = tokio::io::duplex(PIPE_LEN);
+        let task = tokio::spawn(write_archive(ctx, entries, writer));
+        let result = stream::once(async move {
+            match task.await {
+                Ok(result) => result.err(),
+                Err(err) => Some(Error::internal(err.to_string())),
+            }
+        })
+        .filter_map(future::ready)
+        .map(Err);
+        let stream = ReaderStream::new(reader).map_err(Error::from).chain(result);
+
+        let name = story.name.replace(['"', '\\', '/'], "_");
+        let headers = vec![
+            ("content-type".into(), "application/zip".into()),
+            (
+                "content-disposition".into(),
+                format!("attachment; filename=\"{name}.zip\""),
+            ),
+        ];
+        Ok((AppendHeaders(headers), Body::from_stream(stream)))
+    }
+}
+
+/// Write files to a ZIP archive, in order, under the given entry names.
+async fn write_archive(
+    ctx: Arc<Ctx>,
+    entries: Vec<(StoryFile, String)>,
+    writer: DuplexStream,
+) -> Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (file, name) in entries {
+        let entry = ZipEntryBuilder::new(name.into(), compression(&file.content_type))
+            .last_modification_date(file.updated_at.into());
+        let mut stream = ctx.storage.read_stream(&file.storage_id, None).await?;
+        if let Some(sha256) = file.sha256 {
+            stream = verify(stream, file.id, sha256);
+        }
+        let mut entry_writer = zip.write_entry_stream(entry).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            entry_writer.write_all(&chunk).await?;
+        }
+        entry_writer.close().await?;
+    }
+    zip.close().await?;
+    Ok(())
+}
+
+/// Compress text, and store other contents as is, since most binary formats are compressed.
+fn compression(content_type: &str) -> Compression {
+    let text = content_type.starts_with("text/")
+        || matches!(
+            content_type,
+            "application/json" | "application/xml" | "image/svg+xml"
+        );
+    if text {
+        Compression::Deflate
+    } else {
+        Compression::Stored
+    }
+}
@@ -1,15 +1,29 @@
 use crate::{
+    action::{scan::StartScan, storage::PurgeStorage},
     api::Ctx,
-    domain::{StoryFile, StoryFileId, StoryId},
+    domain::{
+        peek, sniff_content_type, ByteRange, ByteStream, FileUpdate, PresignedUrl, Quota, Quotas,
+        StorageId, StoryFile, StoryFileId, StoryId, Usage,
+    },
     Error, Result,
 };
-use axum::extract::Multipart;
-use futures_util::TryFutureExt;
-use std::sync::Arc;
+use axum::{body::Body, extract::Multipart, http::StatusCode, response::AppendHeaders};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt, TryFutureExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
 
 // Defaults for file uploads
-const FILE: &str = "file.dat";
-const OCTET: &str = "application/octet-stream";
+pub(super) const FILE: &str = "file.dat";
+pub(super) const OCTET: &str = "application/octet-stream";
+
+// The number of leading bytes used to detect the content type of uploads
+const SNIFF_LEN: usize = 8192;
+
+// How long presigned urls remain valid
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(900);
 
 /// Store file contents and metadata from a multi-part form.
 pub struct AddFiles;
@@ -20,18 +34,24 @@ impl AddFiles {
         mut multipart: Multipart,
     ) -> Result<Vec<StoryFile>> {
         ctx.repo.fetch_story(story_id).await?;
+        let mut quota = remaining_quota(&ctx, story_id).await?;
         let mut files = Vec::new();
         while let Some(field) = multipart.next_field().await? {
             if field.name().unwrap_or_default() == "file" {
                 let file_name = field.file_name().unwrap_or(FILE).to_string();
-                let content_type = field.content_type().unwrap_or(OCTET).to_string();
-                let bytes = field.bytes().await?;
-                let size = bytes.len() as i64;
-                let storage_id = ctx.storage.write(bytes).await?;
-                let file = ctx
-                    .repo
-                    .create_file(story_id, &storage_id, file_name, size, content_type)
-                    .await?;
+                let claimed_type = field.content_type().unwrap_or(OCTET).to_string();
+
+                // Reject uploads once the quota is used up, before writing to storage
+                check_quota(&quota, 1)?;
+
+                let chunks = field.map_err(Error::from).boxed();
+                let stored = store_contents(&ctx, &quota, chunks, &claimed_type).await?;
+                let size = stored.size;
+                let file = create_file(&ctx, story_id, file_name, stored).await?;
+                quota = quota.remaining(Usage {
+                    bytes: size,
+                    files: 1,
+                });
                 files.push(file);
             }
         }
@@ -42,26 +62,406 @@ impl AddFiles {
     }
 }
 
-/// Fetch file metadata and contents for download.
+/// Contents written to storage, with their detected content type, size and checksum.
+pub(super) struct StoredContents {
+    pub storage_id: StorageId,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+}
+
+/// Write contents to storage, detecting their content type from the leading bytes and checking
+/// them against the upload allowlist and a quota as they stream in.
+pub(super) async fn store_contents(
+    ctx: &Ctx,
+    quota: &Quota,
+    mut chunks: ByteStream<'_>,
+    claimed_type: &str,
+) -> Result<StoredContents> {
+    // Detect the content type before writing to storage
+    let head = peek(&mut chunks, SNIFF_LEN).await?;
+    let content_type = sniff_content_type(&head, claimed_type);
+    check_content_type(ctx, &content_type)?;
+
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let stream = stream::once(future::ok(head.freeze()))
+        .chain(chunks)
+        .map(|chunk| {
+            let chunk = chunk?;
+            size += chunk.len() as i64;
+            check_quota(quota, size)?;
+            hasher.update(&chunk);
+            Ok(chunk)
+        })
+        .boxed();
+    let storage_id = match ctx.storage.write(stream).await {
+        Ok(storage_id) => storage_id,
+        Err(err) => {
+            // Storage may hide why the stream was aborted
+            check_quota(quota, size)?;
+            return Err(err);
+        }
+    };
+    Ok(StoredContents {
+        storage_id,
+        content_type,
+        size,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// The quota left for a story, within both the story and global limits.
+pub(super) async fn remaining_quota(ctx: &Ctx, story_id: &StoryId) -> Result<Quota> {
+    reserved_quota(ctx, story_id, Usage::default()).await
+}
+
+/// The quota left for a story, counting usage reserved by the caller as its own, e.g. the
+/// length of a resumable upload.
+pub(super) async fn reserved_quota(
+    ctx: &Ctx,
+    story_id: &StoryId,
+    reserved: Usage,
+) -> Result<Quota> {
+    let Quotas { story, global } = ctx.quotas;
+    let unreserved = |usage: Usage| Usage {
+        bytes: usage.bytes - reserved.bytes,
+        files: usage.files - reserved.files,
+    };
+    let mut remaining = Quota::default();
+    if !story.is_unbounded() {
+        let usage = ctx.repo.story_usage(story_id).await?;
+        remaining = remaining.min(story.remaining(unreserved(usage)));
+    }
+    if !global.is_unbounded() {
+        let usage = ctx.repo.total_usage().await?;
+        remaining = remaining.min(global.remaining(unreserved(usage)));
+    }
+    Ok(remaining)
+}
+
+/// Check that another file of a given size fits within the remaining quota.
+pub(super) fn check_quota(quota: &Quota, size: i64) -> Result<()> {
+    if quota.max_files == Some(0) {
+        let message = format!("file quota exceeded: {}", quota.describe());
+        return Err(Error::invalid_args(message));
+    }
+    if !quota.allows(size) {
+        let message = format!("storage quota exceeded: {}", quota.describe());
+        return Err(Error::quota_exceeded(message));
+    }
+    Ok(())
+}
+
+/// Check that a detected content type is allowed for uploads.
+fn check_content_type(ctx: &Ctx, content_type: &str) -> Result<()> {
+    if !ctx.allowed_types.allows(content_type) {
+        let message = format!("{content_type} uploads are not allowed");
+        return Err(Error::unsupported_type(message));
+    }
+    Ok(())
+}
+
+/// Check a new content type for a file against the leading bytes of its contents, and the
+/// upload allowlist. Returns the content type as detected. Empty files can take any type.
+async fn check_relabel(ctx: &Ctx, file: &StoryFile, content_type: &str) -> Result<String> {
+    let claimed = content_type.split(';').next().unwrap_or_default().trim();
+    let length = u64::try_from(file.size).unwrap_or_default();
+    if length == 0 {
+        let claimed = claimed.to_ascii_lowercase();
+        check_content_type(ctx, &claimed)?;
+        return Ok(claimed);
+    }
+    let range = ByteRange {
+        offset: 0,
+        length: length.min(SNIFF_LEN as u64),
+    };
+    let mut stream = ctx
+        .storage
+        .read_stream(&file.storage_id, Some(range))
+        .await?;
+    let head = peek(&mut stream, SNIFF_LEN).await?;
+    let detected = sniff_content_type(&head, content_type);
+    if !detected.eq_ignore_ascii_case(claimed) {
+        let message = format!("{claimed} does not match the file contents, detected {detected}");
+        return Err(Error::unsupported_type(message));
+    }
+    check_content_type(ctx, &detected)?;
+    Ok(detected)
+}
+
+/// Record metadata for stored contents, purging the stored copy if it duplicates another.
+/// The file is then scanned for malware before it can be downloaded.
+pub(super) async fn create_file(
+    ctx: &Arc<Ctx>,
+    story_id: &StoryId,
+    name: String,
+    stored: StoredContents,
+) -> Result<StoryFile> {
+    let StoredContents {
+        storage_id,
+        content_type,
+        size,
+        sha256,
+    } = stored;
+    let file = ctx
+        .repo
+        .create_file(story_id, &storage_id, name, size, content_type, sha256)
+        .await?;
+    start_file(ctx, &storage_id, file).await
+}
+
+/// Purge the stored copy of a new file's contents if it duplicates others, then start its
+/// malware scan.
+pub(super) async fn start_file(
+    ctx: &Arc<Ctx>,
+    storage_id: &StorageId,
+    file: StoryFile,
+) -> Result<StoryFile> {
+    // Duplicate content is already stored, so purge the copy just written
+    if file.storage_id != *storage_id {
+        if let Err(err) = ctx.storage.delete(storage_id).await {
+            tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+        }
+    }
+    StartScan::execute(Arc::clone(ctx), file).await
+}
+
+/// Issue a presigned url for uploading file contents directly to storage.
+pub struct PresignUpload;
+impl PresignUpload {
+    pub async fn execute(ctx: Arc<Ctx>, story_id: &StoryId) -> Result<PresignedUrl> {
+        ctx.repo.fetch_story(story_id).await?;
+        check_quota(&remaining_quota(&ctx, story_id).await?, 1)?;
+        let expires_at = Utc::now() + PRESIGN_EXPIRY;
+        let (storage_id, url) = ctx.storage.presign_write(PRESIGN_EXPIRY).await?;
+        ctx.repo
+            .create_presigned_upload(story_id, &storage_id, expires_at)
+            .await?;
+        Ok(PresignedUrl {
+            storage_id,
+            url,
+            expires_at,
+        })
+    }
+}
+
+/// Record metadata for file contents uploaded directly to storage with a presigned url.
+pub struct CompleteUpload;
+impl CompleteUpload {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        storage_id: &StorageId,
+        name: String,
+        content_type: Option<String>,
+    ) -> Result<StoryFile> {
+        ctx.repo.fetch_story(story_id).await?;
+
+        // The upload may not have finished yet, so it stays claimable until it expires
+        let object = ctx.storage.stat(storage_id).await.map_err(|err| {
+            tracing::warn!("unable to stat upload {}: {}", storage_id, err);
+            Error::not_found(format!("upload not found: {storage_id}"))
+        })?;
+
+        // Purge uploads that are empty or don't fit within the quota
+        let size = i64::try_from(object.size).unwrap_or(i64::MAX);
+        let checked = match size {
+            0 => Err(Error::invalid_args("uploaded file is empty")),
+            size => check_quota(&remaining_quota(&ctx, story_id).await?, size),
+        };
+        if let Err(err) = checked {
+            return reject_upload(&ctx, story_id, storage_id, err).await;
+        }
+
+        // Detect the content type from the leading bytes, rather than reading back the upload
+        let range = ByteRange {
+            offset: 0,
+            length: object.size.min(SNIFF_LEN as u64),
+        };
+        let mut stream = ctx.storage.read_stream(storage_id, Some(range)).await?;
+        let head = peek(&mut stream, SNIFF_LEN).await?;
+        let claimed_type = content_type.unwrap_or(OCTET.to_string());
+        let content_type = sniff_content_type(&head, &claimed_type);
+        if let Err(err) = check_content_type(&ctx, &content_type) {
+            return reject_upload(&ctx, story_id, storage_id, err).await;
+        }
+
+        // The checksum is computed in the background, once the presigned url expires
+        let file = ctx
+            .repo
+            .complete_presigned_upload(story_id, storage_id, name, size, content_type)
+            .await?;
+        StartScan::execute(ctx, file).await
+    }
+}
+
+/// Claim a rejected presigned upload, so it can't be completed, and purge it from storage.
+/// Returns the rejection, unless the upload was already completed or expired.
+async fn reject_upload(
+    ctx: &Arc<Ctx>,
+    story_id: &StoryId,
+    storage_id: &StorageId,
+    err: Error,
+) -> Result<StoryFile> {
+    let storage_id = ctx
+        .repo
+        .reject_presigned_upload(story_id, storage_id)
+        .await?;
+    if let Err(err) = PurgeStorage::execute(Arc::clone(ctx), &storage_id).await {
+        tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+    }
+    Err(err)
+}
+
+/// Issue a presigned url for downloading file contents directly from storage.
+pub struct PresignDownload;
+impl PresignDownload {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+    ) -> Result<PresignedUrl> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        file.check_scanned()?;
+        let expires_at = Utc::now() + PRESIGN_EXPIRY;
+        let url = ctx
+            .storage
+            .presign_read(&file.storage_id, PRESIGN_EXPIRY)
+            .await?;
+        Ok(PresignedUrl {
+            storage_id: file.storage_id,
+            url,
+            expires_at,
+        })
+    }
+}
+
+/// Fetch file metadata and stream contents for download, honoring a single byte range.
 pub struct DownloadFile;
 impl DownloadFile {
     pub async fn execute(
         ctx: Arc<Ctx>,
         story_id: &StoryId,
         file_id: &StoryFileId,
-    ) -> Result<([(String, String); 2], Vec<u8>)> {
+        range: Option<&str>,
+    ) -> Result<(StatusCode, AppendHeaders<Vec<(String, String)>>, Body)> {
         let file = ctx
             .repo
             .fetch_story(story_id)
             .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
             .await?;
-        let contents = ctx.storage.read(&file.storage_id).await?;
-        let disposition = format!("attachment; filename=\"{}\"", file.name);
-        let headers = [
-            ("content-type".into(), file.content_type),
-            ("content-disposition".into(), disposition),
-        ];
-        Ok((headers, contents.to_vec()))
+        serve_contents(&ctx, file, range).await
+    }
+}
+
+/// Stream the contents of a file for download, once they have passed a malware scan.
+pub(super) async fn serve_contents(
+    ctx: &Ctx,
+    file: StoryFile,
+    range: Option<&str>,
+) -> Result<(StatusCode, AppendHeaders<Vec<(String, String)>>, Body)> {
+    file.check_scanned()?;
+    let size = file.size as u64;
+    let range = match range {
+        Some(header) => ByteRange::parse(header, size)?,
+        None => None,
+    };
+    let mut stream = ctx.storage.read_stream(&file.storage_id, range).await?;
+    let disposition = format!("attachment; filename=\"{}\"", file.name);
+    let mut headers = vec![
+        ("content-disposition".into(), disposition),
+        ("accept-ranges".into(), "bytes".into()),
+    ];
+    if let Some(sha256) = &file.sha256 {
+        let digest = STANDARD.encode(hex::decode(sha256).unwrap_or_default());
+        headers.push(("etag".into(), format!("\"{sha256}\"")));
+        headers.push(("digest".into(), format!("sha-256={digest}")));
+        // Only complete contents can be checked against the stored checksum
+        if range.is_none() {
+            stream = verify(stream, file.id, sha256.clone());
+        }
+    }
+    headers.push(("content-type".into(), file.content_type));
+    let status = match range {
+        Some(r) => {
+            let content_range = format!("bytes {}-{}/{}", r.offset, r.last(), size);
+            headers.push(("content-range".into(), content_range));
+            headers.push(("content-length".into(), r.length.to_string()));
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => {
+            headers.push(("content-length".into(), size.to_string()));
+            StatusCode::OK
+        }
+    };
+    Ok((status, AppendHeaders(headers), Body::from_stream(stream)))
+}
+
+/// Wrap a download stream to check its contents against a SHA-256 checksum. The final chunk is
+/// held back until the check passes, so a mismatch aborts the response before it completes.
+pub(super) fn verify(
+    stream: ByteStream<'static>,
+    file_id: StoryFileId,
+    sha256: String,
+) -> ByteStream<'static> {
+    let state = Some((stream, Sha256::new(), None::<Bytes>, file_id, sha256));
+    stream::unfold(state, |state| async move {
+        let (mut stream, mut hasher, mut pending, file_id, sha256) = state?;
+        loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    hasher.update(&chunk);
+                    if let Some(prev) = pending.replace(chunk) {
+                        let state = Some((stream, hasher, pending, file_id, sha256));
+                        return Some((Ok(prev), state));
+                    }
+                }
+                Some(Err(err)) => return Some((Err(err), None)),
+                None => break,
+            }
+        }
+        let actual = hex::encode(hasher.finalize());
+        if actual != sha256 {
+            let message =
+                format!("checksum mismatch for file {file_id}: expected {sha256}, got {actual}");
+            tracing::error!("{}", message);
+            return Some((Err(Error::internal(message)), None));
+        }
+        pending.map(|chunk| (Ok(chunk), None))
+    })
+    .boxed()
+}
+
+/// Update the name, content type and description of a file. A new content type must match the
+/// file contents and be allowed for uploads, so relabeling can't get around content sniffing or
+/// the allowlist.
+pub struct UpdateFile;
+impl UpdateFile {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        update: FileUpdate,
+    ) -> Result<StoryFile> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        let content_type = match &update.content_type {
+            Some(content_type) => check_relabel(&ctx, &file, content_type).await?,
+            None => file.content_type,
+        };
+        let name = update.name.unwrap_or(file.name);
+        let description = update.description.unwrap_or(file.description);
+        ctx.repo
+            .update_file(file_id, name, content_type, description)
+            .await
     }
 }
 
@@ -70,20 +470,19 @@ pub struct DeleteFile;
 impl DeleteFile {
     pub async fn execute(ctx: Arc<Ctx>, story_id: &StoryId, file_id: &StoryFileId) -> Result<()> {
         // Delete file metadata
-        let file = ctx
+        // Contents may still be referenced by other files with the same content hash
+        let storage_ids = ctx
             .repo
             .fetch_story(story_id)
             .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
             .and_then(|file| ctx.repo.delete_file(file))
             .await?;
 
-        // Try to delete the file from storage, but only log error on failure
-        if let Err(err) = ctx.storage.delete(&file.storage_id).await {
-            tracing::error!(
-                "unable to delete file {} from storage: {}",
-                file.storage_id,
-                err
-            );
+        // Try to delete the contents from storage, leaving them queued for retry on failure
+        for storage_id in storage_ids {
+            if let Err(err) = PurgeStorage::execute(Arc::clone(&ctx), &storage_id).await {
+                tracing::error!("unable to delete file {} from storage: {}", storage_id, err);
+            }
         }
 
         Ok(())
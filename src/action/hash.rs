@@ -0,0 +1,51 @@
+use crate::{action::storage::PurgeStorage, api::Ctx, domain::StorageId, Result};
+use chrono::Duration;
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+// How long a pending hash is hidden from other workers, doubling with each attempt.
+const HASH_LEASE_SECS: i64 = 300;
+
+/// Compute the checksum of a completed presigned upload, and record it for the files
+/// referencing the contents. Duplicate contents are then purged in favor of the existing blob.
+pub struct HashFile;
+impl HashFile {
+    pub async fn execute(ctx: Arc<Ctx>, storage_id: &StorageId) -> Result<()> {
+        let mut stream = ctx.storage.read_stream(storage_id, None).await?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = stream.try_next().await? {
+            size += chunk.len() as i64;
+            hasher.update(&chunk);
+        }
+        let sha256 = hex::encode(hasher.finalize());
+        let released = ctx.repo.record_hash(storage_id, &sha256, size).await?;
+
+        // Try to delete duplicate contents, leaving them queued for retry on failure
+        for storage_id in released {
+            if let Err(err) = PurgeStorage::execute(Arc::clone(&ctx), &storage_id).await {
+                tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash a batch of completed presigned uploads whose urls have expired, retrying failures with
+/// a doubling delay. Returns the number of stored contents hashed.
+pub struct HashPendingFiles;
+impl HashPendingFiles {
+    pub async fn execute(ctx: Arc<Ctx>, limit: i64) -> Result<usize> {
+        let lease = Duration::seconds(HASH_LEASE_SECS);
+        let storage_ids = ctx.repo.lease_pending_hashes(limit, lease).await?;
+        let mut hashed = 0;
+        for storage_id in storage_ids {
+            match HashFile::execute(Arc::clone(&ctx), &storage_id).await {
+                Ok(()) => hashed += 1,
+                Err(err) => tracing::warn!("unable to hash {}: {}", storage_id, err),
+            }
+        }
+        Ok(hashed)
+    }
+}
@@ -1,3 +1,11 @@
 // Actions make API routes cleaner.
+pub mod archive;
 pub mod file;
+pub mod hash;
+pub mod scan;
+pub mod storage;
 pub mod story;
+pub mod thumbnail;
+pub mod transfer;
+pub mod upload;
+pub mod version;
@@ -0,0 +1,100 @@
+use crate::{
+    api::Ctx,
+    domain::{ScanStatus, ScanVerdict, StorageId, StoryFile},
+    Error, Result,
+};
+use chrono::Duration;
+use std::sync::Arc;
+
+// The delay before a failed scan is retried, doubling with each attempt.
+const SCAN_RETRY_SECS: i64 = 300;
+
+// The number of failed scans before contents are marked failed, e.g. when too large for clamd.
+const SCAN_MAX_ATTEMPTS: i32 = 5;
+
+/// Scan stored contents for malware, and record the result for every file referencing them.
+/// Without a scanner, contents are marked clean. Failed scans are recorded for a retry, until
+/// the contents are given up on and marked failed.
+pub struct ScanFile;
+impl ScanFile {
+    pub async fn execute(ctx: Arc<Ctx>, storage_id: &StorageId) -> Result<ScanStatus> {
+        let status = match &ctx.scanner {
+            Some(scanner) => {
+                let verdict = match ctx.storage.read_stream(storage_id, None).await {
+                    Ok(stream) => scanner.scan(stream).await,
+                    Err(err) => Err(err),
+                };
+                match verdict {
+                    Ok(ScanVerdict::Clean) => ScanStatus::Clean,
+                    Ok(ScanVerdict::Infected(signature)) => {
+                        tracing::warn!("malware found in {}: {}", storage_id, signature);
+                        ScanStatus::Infected
+                    }
+                    Err(err) => return Self::failed(&ctx, storage_id, err).await,
+                }
+            }
+            None => ScanStatus::Clean,
+        };
+        ctx.repo.update_scan_status(storage_id, status).await?;
+        Ok(status)
+    }
+
+    /// Record a failed scan, marking the contents failed after too many attempts. Returns the
+    /// scan error unless the contents were given up on.
+    async fn failed(ctx: &Ctx, storage_id: &StorageId, err: Error) -> Result<ScanStatus> {
+        let delay = Duration::seconds(SCAN_RETRY_SECS);
+        let attempts = ctx
+            .repo
+            .record_scan_failure(storage_id, &err.to_string(), delay)
+            .await?;
+        if attempts < SCAN_MAX_ATTEMPTS {
+            return Err(err);
+        }
+        tracing::error!(
+            "gave up scanning {} after {} attempts: {}",
+            storage_id,
+            attempts,
+            err
+        );
+        ctx.repo
+            .update_scan_status(storage_id, ScanStatus::Failed)
+            .await?;
+        Ok(ScanStatus::Failed)
+    }
+}
+
+/// Start the malware scan for a new file. Scans run in the background, so without a scanner
+/// the file is marked clean right away instead.
+pub struct StartScan;
+impl StartScan {
+    pub async fn execute(ctx: Arc<Ctx>, mut file: StoryFile) -> Result<StoryFile> {
+        if ctx.scanner.is_none() {
+            file.scan_status = ScanFile::execute(ctx, &file.storage_id).await?;
+            return Ok(file);
+        }
+        let storage_id = file.storage_id.clone();
+        tokio::spawn(async move {
+            if let Err(err) = ScanFile::execute(ctx, &storage_id).await {
+                tracing::warn!("unable to scan {}, leaving it pending: {}", storage_id, err);
+            }
+        });
+        Ok(file)
+    }
+}
+
+/// Scan files left waiting after their upload scan failed, e.g. during a scanner outage.
+/// Returns the number of stored contents scanned.
+pub struct ScanPendingFiles;
+impl ScanPendingFiles {
+    pub async fn execute(ctx: Arc<Ctx>, age: Duration, limit: i64) -> Result<usize> {
+        let storage_ids = ctx.repo.list_pending_scans(age, limit).await?;
+        let mut scanned = 0;
+        for storage_id in storage_ids {
+            match ScanFile::execute(Arc::clone(&ctx), &storage_id).await {
+                Ok(_) => scanned += 1,
+                Err(err) => tracing::warn!("unable to scan {}: {}", storage_id, err),
+            }
+        }
+        Ok(scanned)
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    api::Ctx,
+    domain::{PendingMigration, Storage, StorageId, StoredObject},
+    Error, Result,
+};
+use chrono::{Duration, Utc};
+use futures_util::{future, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, sync::Arc};
+
+// The number of stored objects processed at a time.
+const BATCH_SIZE: usize = 1000;
+
+// How long a leased deletion is hidden from other workers.
+const LEASE_SECS: i64 = 300;
+
+// Bounds on the delay between deletion attempts.
+const MIN_RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+/// Purge contents from storage and dequeue the deletion. On failure the deletion stays queued
+/// for the deletion worker to retry.
+pub struct PurgeStorage;
+impl PurgeStorage {
+    pub async fn execute(ctx: Arc<Ctx>, storage_id: &StorageId) -> Result<()> {
+        ctx.storage.delete(storage_id).await?;
+        ctx.repo.complete_deletion(storage_id).await
+    }
+}
+
+/// Retry a batch of queued storage deletions, backing off exponentially on failure.
+/// Returns the number of deletions that succeeded.
+pub struct RetryDeletions;
+impl RetryDeletions {
+    pub async fn execute(ctx: Arc<Ctx>, limit: i64) -> Result<usize> {
+        let lease = Duration::seconds(LEASE_SECS);
+        let deletions = ctx.repo.lease_deletions(limit, lease).await?;
+        let mut purged = 0;
+        for deletion in deletions {
+            let storage_id = &deletion.storage_id;
+            match ctx.storage.delete(storage_id).await {
+                Ok(()) => {
+                    ctx.repo.complete_deletion(storage_id).await?;
+                    purged += 1;
+                }
+                Err(err) => {
+                    let delay = retry_delay(deletion.attempts);
+                    tracing::warn!(
+                        "unable to delete {} from storage (attempt {}), retrying in {}s: {}",
+                        storage_id,
+                        deletion.attempts,
+                        delay.num_seconds(),
+                        err
+                    );
+                    ctx.repo
+                        .retry_deletion(storage_id, err.to_string(), delay)
+                        .await?;
+                }
+            }
+        }
+        Ok(purged)
+    }
+}
+
+/// Double the delay between deletion attempts, up to a limit.
+fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    let secs = MIN_RETRY_DELAY_SECS.saturating_mul(1 << exponent);
+    Duration::seconds(secs.min(MAX_RETRY_DELAY_SECS))
+}
+
+/// Find stored objects that no file references, and purge those older than a grace period.
+/// In dry-run mode orphans are only reported. Returns the orphaned objects found.
+pub struct ReconcileStorage;
+impl ReconcileStorage {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        grace_period: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<StoredObject>> {
+        // Skip recent objects, which may belong to uploads still in progress
+        let cutoff = Utc::now() - grace_period;
+        let mut batches = ctx
+            .storage
+            .list()
+            .await?
+            .try_filter(|object| future::ready(object.modified_at < cutoff))
+            .chunks(BATCH_SIZE);
+
+        let mut orphans = Vec::new();
+        while let Some(batch) = batches.next().await {
+            let batch: Vec<StoredObject> = batch.into_iter().collect::<Result<_>>()?;
+            let storage_ids: Vec<_> = batch.iter().map(|o| o.storage_id.clone()).collect();
+            let unreferenced: HashSet<_> = ctx
+                .repo
+                .filter_unreferenced(&storage_ids)
+                .await?
+                .into_iter()
+                .collect();
+            for object in batch {
+                if !unreferenced.contains(&object.storage_id) {
+                    continue;
+                }
+                if !dry_run {
+                    // Don't fail reconciliation, just log the error
+                    if let Err(err) = ctx.storage.delete(&object.storage_id).await {
+                        tracing::error!(
+                            "unable to delete {} from storage: {}",
+                            object.storage_id,
+                            err
+                        );
+                        continue;
+                    }
+                }
+                orphans.push(object);
+            }
+        }
+
+        Ok(orphans)
+    }
+}
+
+/// The outcome of a storage migration run.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub failed: usize,
+}
+
+/// Copy the stored contents of every file to a destination storage under the same ids, so
+/// either backend can serve them. Each copy is read back to verify its size and checksum.
+/// Progress is recorded per destination, so an interrupted migration resumes where it left off,
+/// and failed copies are retried on the next run.
+pub struct MigrateStorage;
+impl MigrateStorage {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        destination: &dyn Storage,
+        label: &str,
+    ) -> Result<MigrationReport> {
+        let mut report = MigrationReport::default();
+        let mut after = None;
+        loop {
+            let batch = ctx
+                .repo
+                .list_pending_migrations(label, after.as_ref(), BATCH_SIZE as i64)
+                .await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            after = Some(last.storage_id.clone());
+            for pending in batch {
+                let storage_id = &pending.storage_id;
+                match copy(&ctx, destination, &pending).await {
+                    Ok(()) => {
+                        ctx.repo
+                            .complete_migration(label, storage_id, pending.size)
+                            .await?;
+                        tracing::info!("migrated {} ({} bytes)", storage_id, pending.size);
+                        report.migrated += 1;
+                    }
+                    Err(err) => {
+                        tracing::error!("unable to migrate {}: {}", storage_id, err);
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Copy contents to a destination storage, then read them back to verify the copy.
+async fn copy(ctx: &Ctx, destination: &dyn Storage, pending: &PendingMigration) -> Result<()> {
+    let storage_id = &pending.storage_id;
+    let stream = ctx.storage.read_stream(storage_id, None).await?;
+    destination.put(storage_id, stream).await?;
+
+    let mut stream = destination.read_stream(storage_id, None).await?;
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.try_next().await? {
+        size += chunk.len() as i64;
+        hasher.update(&chunk);
+    }
+    if size != pending.size {
+        return Err(Error::internal(format!(
+            "size mismatch: expected {}, got {}",
+            pending.size, size
+        )));
+    }
+    let actual = hex::encode(hasher.finalize());
+    if let Some(sha256) = pending.sha256.as_ref().filter(|sha256| **sha256 != actual) {
+        return Err(Error::internal(format!(
+            "checksum mismatch: expected {sha256}, got {actual}"
+        )));
+    }
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::{api::Ctx, domain::StoryId, Result};
+use crate::{action::storage::PurgeStorage, api::Ctx, domain::StoryId, Result};
 use std::sync::Arc;
 
 /// Delete a story
@@ -8,20 +8,13 @@ impl DeleteStory {
         // Ensure story exists
         ctx.repo.fetch_story(story_id).await?;
 
-        // Gather all storage references
-        let storage_ids = if let Ok(files) = ctx.repo.list_files(story_id).await {
-            files.into_iter().map(|f| f.storage_id).collect()
-        } else {
-            vec![]
-        };
-
-        // Delete story, tasks, and file metadata
-        ctx.repo.delete_story(story_id).await?;
+        // Delete story, tasks, and file metadata, gathering unreferenced storage ids
+        let storage_ids = ctx.repo.delete_story(story_id).await?;
 
         // Delete file contents from storage only after metadata deletion succeeds
         for storage_id in storage_ids {
-            // Don't fail action, just log the error
-            if let Err(err) = ctx.storage.delete(&storage_id).await {
+            // Don't fail action, just log the error and leave the deletion queued for retry
+            if let Err(err) = PurgeStorage::execute(Arc::clone(&ctx), &storage_id).await {
                 tracing::error!("unable to delete {} from storage: {}", storage_id, err);
             }
         }
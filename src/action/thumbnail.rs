@@ -0,0 +1,87 @@
+use crate::{
+    api::Ctx,
+    domain::{
+        has_thumbnail, render_thumbnail, StoryFile, StoryFileId, StoryId, Thumbnail, THUMBNAIL_SIZE,
+    },
+    Error, Result,
+};
+use axum::{body::Body, response::AppendHeaders};
+use futures_util::{future, stream, StreamExt, TryFutureExt};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+// Images larger than this are not read into memory to render thumbnails.
+const MAX_SOURCE_SIZE: i64 = 50 * 1024 * 1024;
+
+/// Stream the thumbnail for an image file, rendering and storing it on first request.
+pub struct DownloadThumbnail;
+impl DownloadThumbnail {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+    ) -> Result<(AppendHeaders<Vec<(String, String)>>, Body)> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        file.check_scanned()?;
+        if !has_thumbnail(&file.content_type) {
+            let message = format!("no thumbnail for {} files", file.content_type);
+            return Err(Error::not_found(message));
+        }
+        let thumbnail = match ctx.repo.fetch_thumbnail(&file.storage_id).await? {
+            Some(thumbnail) => thumbnail,
+            None => RenderThumbnail::execute(ctx.clone(), &file).await?,
+        };
+        let stream = ctx.storage.read_stream(&thumbnail.storage_id, None).await?;
+        let headers = vec![
+            ("content-type".into(), thumbnail.content_type),
+            ("content-length".into(), thumbnail.size.to_string()),
+            ("etag".into(), format!("\"{}\"", thumbnail.sha256)),
+        ];
+        Ok((AppendHeaders(headers), Body::from_stream(stream)))
+    }
+}
+
+/// Render a thumbnail from the contents of an image file, and store it.
+pub struct RenderThumbnail;
+impl RenderThumbnail {
+    pub async fn execute(ctx: Arc<Ctx>, file: &StoryFile) -> Result<Thumbnail> {
+        if file.size > MAX_SOURCE_SIZE {
+            return Err(Error::invalid_args("image is too large for a thumbnail"));
+        }
+        let contents = ctx.storage.read(&file.storage_id).await?;
+        let (bytes, content_type) =
+            tokio::task::spawn_blocking(move || render_thumbnail(&contents, THUMBNAIL_SIZE))
+                .await
+                .map_err(|err| Error::internal(err.to_string()))??;
+        let size = bytes.len() as i64;
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        let storage_id = ctx
+            .storage
+            .write(stream::once(future::ok(bytes)).boxed())
+            .await?;
+        let rendered = Thumbnail {
+            source_id: file.storage_id.clone(),
+            storage_id: storage_id.clone(),
+            content_type: content_type.to_string(),
+            size,
+            sha256,
+        };
+
+        // Another request may have rendered a thumbnail first, or the file may be gone
+        let thumbnail = ctx.repo.create_thumbnail(rendered).await;
+        if thumbnail
+            .as_ref()
+            .map(|t| t.storage_id != storage_id)
+            .unwrap_or(true)
+        {
+            if let Err(err) = ctx.storage.delete(&storage_id).await {
+                tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+            }
+        }
+        thumbnail
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{
+    action::{
+        file::{check_quota, remaining_quota},
+        scan::StartScan,
+    },
+    api::Ctx,
+    domain::{ScanStatus, StoryFile, StoryFileId, StoryId},
+    Result,
+};
+use futures_util::{StreamExt, TryFutureExt};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Move a file and its prior versions to another story. Only the metadata changes, so only the
+/// target story quota applies.
+pub struct MoveFile;
+impl MoveFile {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        target_id: &StoryId,
+    ) -> Result<StoryFile> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_story(target_id))
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        if story_id == target_id {
+            return Ok(file);
+        }
+
+        // Prior versions move along with the file
+        let versions = ctx.repo.list_versions(file_id).await?;
+        let bytes = file.size + versions.iter().map(|v| v.size).sum::<i64>();
+        let usage = ctx.repo.story_usage(target_id).await?;
+        check_quota(&ctx.quotas.story.remaining(usage), bytes)?;
+
+        ctx.repo.move_file(file_id, target_id).await
+    }
+}
+
+/// Copy the current contents of a file to another story. Contents with a hash are shared with
+/// the copy, while older contents are duplicated in storage, and scanned again.
+pub struct CopyFile;
+impl CopyFile {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        target_id: &StoryId,
+    ) -> Result<StoryFile> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_story(target_id))
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        let quota = remaining_quota(&ctx, target_id).await?;
+        check_quota(&quota, file.size)?;
+
+        if file.sha256.is_some() {
+            return ctx.repo.copy_file(&file, target_id).await;
+        }
+        let source = duplicate_contents(&ctx, file).await?;
+        let copy = ctx.repo.copy_file(&source, target_id).await;
+
+        // Purge the duplicate if the same contents were already stored, or it wasn't recorded
+        if copy
+            .as_ref()
+            .map_or(true, |c| c.storage_id != source.storage_id)
+        {
+            if let Err(err) = ctx.storage.delete(&source.storage_id).await {
+                tracing::error!(
+                    "unable to delete {} from storage: {}",
+                    source.storage_id,
+                    err
+                );
+            }
+        }
+        StartScan::execute(ctx, copy?).await
+    }
+}
+
+/// Write a copy of file contents that have no hash, hashing them on the way. The returned file
+/// points to the copy, which waits for a malware scan.
+async fn duplicate_contents(ctx: &Ctx, file: StoryFile) -> Result<StoryFile> {
+    let mut hasher = Sha256::new();
+    let stream = ctx
+        .storage
+        .read_stream(&file.storage_id, None)
+        .await?
+        .inspect(|chunk| {
+            if let Ok(chunk) = chunk {
+                hasher.update(chunk);
+            }
+        })
+        .boxed();
+    let storage_id = ctx.storage.write(stream).await?;
+    Ok(StoryFile {
+        storage_id,
+        sha256: Some(hex::encode(hasher.finalize())),
+        scan_status: ScanStatus::Pending,
+        ..file
+    })
+}
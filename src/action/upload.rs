@@ -0,0 +1,228 @@
+use super::{
+    file::{
+        check_quota, remaining_quota, reserved_quota, start_file, store_contents, StoredContents,
+        FILE, OCTET,
+    },
+    storage::PurgeStorage,
+};
+use crate::{
+    api::Ctx,
+    domain::{peek, ByteStream, Quota, StorageId, StoryFile, StoryId, Upload, UploadId, Usage},
+    Error, Result,
+};
+use chrono::{Duration, Utc};
+use futures_util::{future, stream, StreamExt, TryFutureExt};
+use std::sync::Arc;
+
+// How long an upload can be resumed after it starts.
+const UPLOAD_EXPIRY_HOURS: i64 = 24;
+
+// How long a claim to assemble an upload lasts, after which it is assumed abandoned.
+const ASSEMBLE_LEASE_MINS: i64 = 60;
+
+/// Start a resumable upload of a file to a story. The upload reserves its length against the
+/// quotas until it is assembled or expires.
+pub struct CreateUpload;
+impl CreateUpload {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        name: Option<String>,
+        content_type: Option<String>,
+        length: i64,
+    ) -> Result<Upload> {
+        ctx.repo.fetch_story(story_id).await?;
+
+        // Reject files that can't fit within the quota before any bytes are sent
+        check_quota(&remaining_quota(&ctx, story_id).await?, length)?;
+
+        let name = name.unwrap_or(FILE.to_string());
+        let content_type = content_type.unwrap_or(OCTET.to_string());
+        let expires_at = Utc::now() + Duration::hours(UPLOAD_EXPIRY_HOURS);
+        ctx.repo
+            .create_upload(story_id, name, content_type, length, expires_at)
+            .await
+    }
+}
+
+/// Fetch a resumable upload, e.g. to find the offset to resume from.
+pub struct FetchUpload;
+impl FetchUpload {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        upload_id: &UploadId,
+    ) -> Result<Upload> {
+        ctx.repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_upload(story_id, upload_id))
+            .await
+    }
+}
+
+/// Append a chunk of bytes to a resumable upload, at its current offset. Once every byte has
+/// arrived, the chunks are assembled into a story file, which is returned.
+pub struct AppendUpload;
+impl AppendUpload {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        upload_id: &UploadId,
+        offset: i64,
+        body: ByteStream<'static>,
+    ) -> Result<(Upload, Option<StoryFile>)> {
+        let upload = FetchUpload::execute(Arc::clone(&ctx), story_id, upload_id).await?;
+        if offset != upload.offset {
+            let message = format!("upload offset is {}, not {offset}", upload.offset);
+            return Err(Error::conflict(message));
+        }
+
+        // Uploads started at once may have reserved more than the quotas allow between them
+        check_quota(&upload_quota(&ctx, &upload).await?, upload.length)?;
+
+        // Finish in the background if the client disconnects, so the upload resumes from the
+        // bytes that were received
+        tokio::spawn(append(ctx, upload, body))
+            .await
+            .map_err(|err| Error::internal(err.to_string()))?
+    }
+}
+
+/// Store a chunk of an upload, then assemble the file if it is complete.
+async fn append(
+    ctx: Arc<Ctx>,
+    upload: Upload,
+    body: ByteStream<'static>,
+) -> Result<(Upload, Option<StoryFile>)> {
+    // Keep the bytes sent before a body error, such as a dropped connection
+    let mut chunks = body
+        .scan((), |_, chunk| future::ready(chunk.ok().map(Ok)))
+        .boxed();
+    let head = peek(&mut chunks, 1).await?;
+
+    // Empty chunks are allowed, e.g. to retry assembling a complete upload
+    let upload = if head.is_empty() {
+        upload
+    } else {
+        let remaining = upload.length - upload.offset;
+        let mut size = 0;
+        let stream = stream::once(future::ok(head.freeze()))
+            .chain(chunks)
+            .map(|chunk| {
+                let chunk = chunk?;
+                size += chunk.len() as i64;
+                if size > remaining {
+                    return Err(Error::invalid_args("chunk exceeds the upload length"));
+                }
+                Ok(chunk)
+            })
+            .boxed();
+        let storage_id = match ctx.storage.write(stream).await {
+            Ok(storage_id) => storage_id,
+            Err(_) if size > remaining => {
+                return Err(Error::invalid_args("chunk exceeds the upload length"));
+            }
+            Err(err) => return Err(err),
+        };
+        let appended = ctx
+            .repo
+            .append_upload_chunk(&upload.id, upload.offset, &storage_id, size)
+            .await;
+        match appended {
+            Ok(upload) => upload,
+            Err(err) => {
+                if let Err(err) = ctx.storage.delete(&storage_id).await {
+                    tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+                }
+                return Err(err);
+            }
+        }
+    };
+    if !upload.is_complete() {
+        return Ok((upload, None));
+    }
+    let file = assemble(Arc::clone(&ctx), &upload).await?;
+    Ok((upload, Some(file)))
+}
+
+/// Assemble the chunks of a complete upload into a story file, deleting the upload as the file
+/// is recorded. The upload is claimed first, so a retried or concurrent request can't assemble
+/// it again.
+async fn assemble(ctx: Arc<Ctx>, upload: &Upload) -> Result<StoryFile> {
+    let lease = Duration::minutes(ASSEMBLE_LEASE_MINS);
+    let upload = ctx.repo.claim_upload(&upload.id, lease).await?;
+    let stored = match store_chunks(&ctx, &upload).await {
+        Ok(stored) => stored,
+        Err(err) => {
+            unclaim(&ctx, &upload).await;
+            return Err(err);
+        }
+    };
+    let StoredContents {
+        storage_id,
+        content_type,
+        size,
+        sha256,
+    } = stored;
+    let assembled = ctx
+        .repo
+        .assemble_upload(&upload.id, &storage_id, size, content_type, sha256)
+        .await;
+    let (file, chunk_ids) = match assembled {
+        Ok(assembled) => assembled,
+        Err(err) => {
+            if let Err(err) = ctx.storage.delete(&storage_id).await {
+                tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+            }
+            unclaim(&ctx, &upload).await;
+            return Err(err);
+        }
+    };
+    purge_chunks(Arc::clone(&ctx), chunk_ids).await;
+    start_file(&ctx, &storage_id, file).await
+}
+
+/// Release the claim on an upload that failed to assemble. Nothing was recorded, so the
+/// upload can be retried.
+async fn unclaim(ctx: &Ctx, upload: &Upload) {
+    if let Err(err) = ctx.repo.unclaim_upload(&upload.id).await {
+        tracing::error!("unable to release claim on upload {}: {}", upload.id, err);
+    }
+}
+
+/// Store the chunks of an upload as one object, checking its content type and quota.
+async fn store_chunks(ctx: &Arc<Ctx>, upload: &Upload) -> Result<StoredContents> {
+    let chunks = ctx.repo.list_upload_chunks(&upload.id).await?;
+    let quota = upload_quota(ctx, upload).await?;
+    let parts = ctx.storage.read_parts(&chunks);
+    store_contents(ctx, &quota, parts, &upload.content_type).await
+}
+
+/// The quota left for an upload, counting the length and file it reserves as its own.
+async fn upload_quota(ctx: &Ctx, upload: &Upload) -> Result<Quota> {
+    let reserved = Usage {
+        bytes: upload.length,
+        files: 1,
+    };
+    reserved_quota(ctx, &upload.story_id, reserved).await
+}
+
+/// Abandon a resumable upload, purging the chunks received so far.
+pub struct DeleteUpload;
+impl DeleteUpload {
+    pub async fn execute(ctx: Arc<Ctx>, story_id: &StoryId, upload_id: &UploadId) -> Result<()> {
+        let upload = FetchUpload::execute(Arc::clone(&ctx), story_id, upload_id).await?;
+        let storage_ids = ctx.repo.delete_upload(&upload.id).await?;
+        purge_chunks(ctx, storage_ids).await;
+        Ok(())
+    }
+}
+
+/// Purge upload chunks from storage, leaving failed deletions queued for retry.
+async fn purge_chunks(ctx: Arc<Ctx>, storage_ids: Vec<StorageId>) {
+    for storage_id in storage_ids {
+        if let Err(err) = PurgeStorage::execute(Arc::clone(&ctx), &storage_id).await {
+            tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+        }
+    }
+}
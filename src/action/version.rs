@@ -0,0 +1,124 @@
+use crate::{
+    action::{
+        file::{
+            check_quota, remaining_quota, serve_contents, store_contents, StoredContents, OCTET,
+        },
+        scan::StartScan,
+    },
+    api::Ctx,
+    domain::{Quota, ScanStatus, StoryFile, StoryFileId, StoryId},
+    Error, Result,
+};
+use axum::{body::Body, extract::Multipart, http::StatusCode, response::AppendHeaders};
+use futures_util::{StreamExt, TryFutureExt, TryStreamExt};
+use std::sync::Arc;
+
+/// Upload new contents for a file from a multi-part form, keeping the current contents as a
+/// prior version.
+pub struct AddVersion;
+impl AddVersion {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        mut multipart: Multipart,
+    ) -> Result<StoryFile> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+
+        // A new version adds bytes, but not another file
+        let quota = Quota {
+            max_files: None,
+            ..remaining_quota(&ctx, story_id).await?
+        };
+        while let Some(field) = multipart.next_field().await? {
+            if field.name().unwrap_or_default() == "file" {
+                let claimed_type = field.content_type().unwrap_or(OCTET).to_string();
+                let chunks = field.map_err(Error::from).boxed();
+                let stored = store_contents(&ctx, &quota, chunks, &claimed_type).await?;
+                return create_version(&ctx, &file.id, stored).await;
+            }
+        }
+        Err(Error::invalid_args("no file uploaded"))
+    }
+}
+
+/// Record stored contents as the new version of a file, purging the stored copy if it
+/// duplicates another. The new version is then scanned for malware before it can be downloaded.
+async fn create_version(
+    ctx: &Arc<Ctx>,
+    file_id: &StoryFileId,
+    stored: StoredContents,
+) -> Result<StoryFile> {
+    let StoredContents {
+        storage_id,
+        content_type,
+        size,
+        sha256,
+    } = stored;
+    let file = ctx
+        .repo
+        .create_version(file_id, &storage_id, size, content_type, sha256)
+        .await;
+
+    // Purge the copy just written if it duplicates stored content, or wasn't recorded
+    if file.as_ref().map_or(true, |f| f.storage_id != storage_id) {
+        if let Err(err) = ctx.storage.delete(&storage_id).await {
+            tracing::error!("unable to delete {} from storage: {}", storage_id, err);
+        }
+    }
+    StartScan::execute(Arc::clone(ctx), file?).await
+}
+
+/// Stream the contents of a prior file version for download, honoring a single byte range.
+pub struct DownloadVersion;
+impl DownloadVersion {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        version: i32,
+        range: Option<&str>,
+    ) -> Result<(StatusCode, AppendHeaders<Vec<(String, String)>>, Body)> {
+        let file = ctx
+            .repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        let prior = ctx.repo.fetch_version(file_id, version).await?;
+        serve_contents(&ctx, prior.apply(file), range).await
+    }
+}
+
+/// Make a prior version of a file current again, as its newest version.
+pub struct RestoreVersion;
+impl RestoreVersion {
+    pub async fn execute(
+        ctx: Arc<Ctx>,
+        story_id: &StoryId,
+        file_id: &StoryFileId,
+        version: i32,
+    ) -> Result<StoryFile> {
+        ctx.repo
+            .fetch_story(story_id)
+            .and_then(|_| ctx.repo.fetch_file(story_id, file_id))
+            .await?;
+        let prior = ctx.repo.fetch_version(file_id, version).await?;
+
+        // The restored contents are counted again, though they share stored content
+        let quota = Quota {
+            max_files: None,
+            ..remaining_quota(&ctx, story_id).await?
+        };
+        check_quota(&quota, prior.size)?;
+
+        let file = ctx.repo.restore_version(file_id, version).await?;
+        match file.scan_status {
+            ScanStatus::Pending => StartScan::execute(ctx, file).await,
+            _ => Ok(file),
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{domain::Storage, repo::Repo};
+use crate::{
+    domain::{AllowedTypes, Quotas, Scanner, Storage},
+    repo::Repo,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
 use std::sync::Arc;
 
 /// Context contains repo and driver pointers for use in API routes.
@@ -9,11 +13,54 @@ pub struct Ctx {
 
     /// Database storage
     pub repo: Arc<Repo>,
+
+    /// Limits on stored file contents
+    pub quotas: Quotas,
+
+    /// Content types accepted for uploads
+    pub allowed_types: AllowedTypes,
+
+    /// Malware scanner for uploads, if any
+    pub scanner: Option<Arc<dyn Scanner>>,
+
+    /// Renders recorded metrics, if a recorder is installed
+    pub metrics: Option<PrometheusHandle>,
 }
 
 impl Ctx {
     /// Create a new API context
     pub fn new(storage: Arc<Box<dyn Storage>>, repo: Arc<Repo>) -> Self {
-        Self { storage, repo }
+        Self {
+            storage,
+            repo,
+            quotas: Quotas::default(),
+            allowed_types: AllowedTypes::default(),
+            scanner: None,
+            metrics: None,
+        }
+    }
+
+    /// Limit the contents stored for files
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Limit the content types accepted for uploads
+    pub fn with_allowed_types(mut self, allowed_types: AllowedTypes) -> Self {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /// Scan uploads for malware before they can be downloaded
+    pub fn with_scanner(mut self, scanner: Option<Box<dyn Scanner>>) -> Self {
+        self.scanner = scanner.map(Arc::from);
+        self
+    }
+
+    /// Expose recorded metrics through the metrics endpoint
+    pub fn with_metrics(mut self, metrics: PrometheusHandle) -> Self {
+        self.metrics = Some(metrics);
+        self
     }
 }
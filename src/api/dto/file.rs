@@ -0,0 +1,223 @@
+use super::MAX_NAME_LEN;
+use crate::{
+    domain::{FileUpdate, StorageId, StoryId},
+    Error, Result,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+use std::fmt::Debug;
+use utoipa::ToSchema;
+
+/// Limit content type size in http request body.
+const MAX_CONTENT_TYPE_LEN: usize = 100;
+
+/// Limit description size in http request body.
+const MAX_DESCRIPTION_LEN: usize = 1000;
+
+/// The request body for completing a presigned file upload
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CompleteUploadRequest {
+    pub storage_id: StorageId,
+    name: String,
+    content_type: Option<String>,
+}
+
+impl CompleteUploadRequest {
+    /// Validate a complete upload request.
+    pub fn validate(&self) -> Result<(String, Option<String>)> {
+        let name = self.name.trim().to_string();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(Error::invalid_args("name: invalid length"));
+        }
+        let content_type = self.content_type.as_ref().map(|s| s.trim().to_string());
+        if let Some(content_type) = &content_type {
+            if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LEN {
+                return Err(Error::invalid_args("content_type: invalid length"));
+            }
+        }
+        Ok((name, content_type))
+    }
+}
+
+/// The PATCH body for updating file metadata
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateFileRequest {
+    pub name: Option<String>,
+    pub content_type: Option<String>,
+    /// Free-text notes about the file, or an empty string to clear them
+    pub description: Option<String>,
+}
+
+impl UpdateFileRequest {
+    /// Validate a file update request.
+    pub fn validate(&self) -> Result<FileUpdate> {
+        // Make sure at least one field is provided
+        if self.name.is_none() && self.content_type.is_none() && self.description.is_none() {
+            return Err(Error::invalid_args(
+                "name, content_type and/or description must be provided",
+            ));
+        }
+
+        // Defaults for return values
+        let mut messages = Vec::new();
+        let mut name: Option<String> = None;
+        let mut content_type: Option<String> = None;
+        let mut description: Option<Option<String>> = None;
+
+        // Validate
+        if let Some(n) = &self.name {
+            let n = n.trim();
+            if n.is_empty() || n.len() > MAX_NAME_LEN {
+                messages.push("name: invalid length".into());
+            } else {
+                name = Some(n.to_string());
+            }
+        }
+        if let Some(t) = &self.content_type {
+            let t = t.trim().to_ascii_lowercase();
+            if t.is_empty() || t.len() > MAX_CONTENT_TYPE_LEN {
+                messages.push("content_type: invalid length".into());
+            } else if !is_media_type(&t) {
+                messages.push("content_type: invalid media type".into());
+            } else {
+                content_type = Some(t);
+            }
+        }
+        if let Some(d) = &self.description {
+            let d = d.trim();
+            if d.len() > MAX_DESCRIPTION_LEN {
+                messages.push("description: invalid length".into());
+            } else {
+                description = Some((!d.is_empty()).then(|| d.to_string()));
+            }
+        }
+
+        // Check for validation failures and return an error if found
+        if !messages.is_empty() {
+            return Err(Error::InvalidArgs { messages });
+        }
+
+        Ok(FileUpdate {
+            name,
+            content_type,
+            description,
+        })
+    }
+}
+
+/// The request body for moving or copying a file to another story
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TransferFileRequest {
+    pub story_id: StoryId,
+}
+
+/// Whether a string looks like a `type/subtype` media type, without parameters.
+fn is_media_type(s: &str) -> bool {
+    let token = |t: &str| {
+        !t.is_empty()
+            && t.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    matches!(s.split_once('/'), Some((t, sub)) if token(t) && token(sub))
+}
+
+/// File details for a resumable upload, from a tus `Upload-Metadata` header
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UploadMetadata {
+    name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl UploadMetadata {
+    /// Parse an `Upload-Metadata` header of comma separated keys and base64 encoded values,
+    /// e.g. `filename cmVwb3J0LnBkZg==,filetype YXBwbGljYXRpb24vcGRm`.
+    pub fn parse(header: &str) -> Result<Self> {
+        let mut metadata = Self::default();
+        for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or_else(|| Error::invalid_args(format!("{key}: invalid metadata value")))?;
+            match key {
+                "filename" | "name" => metadata.name = Some(value),
+                "filetype" | "type" => metadata.content_type = Some(value),
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Validate upload metadata, returning the file name and content type if given.
+    pub fn validate(&self) -> Result<(Option<String>, Option<String>)> {
+        let name = self.name.as_ref().map(|s| s.trim().to_string());
+        if let Some(name) = &name {
+            if name.is_empty() || name.len() > MAX_NAME_LEN {
+                return Err(Error::invalid_args("filename: invalid length"));
+            }
+        }
+        let content_type = self.content_type.as_ref().map(|s| s.trim().to_string());
+        if let Some(content_type) = &content_type {
+            if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LEN {
+                return Err(Error::invalid_args("filetype: invalid length"));
+            }
+        }
+        Ok((name, content_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_update_file_request() {
+        let req = UpdateFileRequest {
+            name: Some(" notes.md ".to_string()),
+            content_type: Some("Text/Markdown".to_string()),
+            description: Some(" ".to_string()),
+        };
+        let update = req.validate().unwrap();
+        assert_eq!(update.name.as_deref(), Some("notes.md"));
+        assert_eq!(update.content_type.as_deref(), Some("text/markdown"));
+        assert_eq!(update.description, Some(None));
+
+        let req = UpdateFileRequest {
+            name: Some("x".repeat(MAX_NAME_LEN + 1)),
+            content_type: Some("text".to_string()),
+            description: Some("x".repeat(MAX_DESCRIPTION_LEN + 1)),
+        };
+        let Err(Error::InvalidArgs { messages }) = req.validate() else {
+            panic!("expected invalid args");
+        };
+        assert_eq!(messages.len(), 3);
+
+        let req = UpdateFileRequest {
+            name: None,
+            content_type: None,
+            description: None,
+        };
+        assert!(req.validate().is_err());
+    }
+
+    #[test]
+    fn parse_upload_metadata() {
+        let metadata = UploadMetadata::parse(
+            "filename cmVwb3J0LnBkZg==, filetype dGV4dC9jc3Y=,is_confidential",
+        )
+        .unwrap();
+        let (name, content_type) = metadata.validate().unwrap();
+        assert_eq!(name.as_deref(), Some("report.pdf"));
+        assert_eq!(content_type.as_deref(), Some("text/csv"));
+        assert_eq!(
+            UploadMetadata::parse("").unwrap(),
+            UploadMetadata::default()
+        );
+        assert!(UploadMetadata::parse("filename !!!").is_err());
+        assert!(UploadMetadata::parse("filename IA==")
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+}
@@ -1,7 +1,12 @@
+mod file;
 mod page;
 mod story;
 mod task;
 
+pub use file::{CompleteUploadRequest, TransferFileRequest, UpdateFileRequest, UploadMetadata};
 pub use page::{Page, PageParams, PageToken};
 pub use story::StoryRequest;
 pub use task::{CreateTaskRequest, TaskParams, UpdateTaskRequest};
+
+/// Limit story, task and file name size in http request body.
+const MAX_NAME_LEN: usize = 100;
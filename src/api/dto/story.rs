@@ -1,11 +1,9 @@
+use super::MAX_NAME_LEN;
 use crate::{Error, Result};
 use serde::Deserialize;
 use std::fmt::Debug;
 use utoipa::ToSchema;
 
-/// Limit name size in http request body.
-const MAX_NAME_LEN: usize = 100;
-
 /// The request body for creating or updating stories
 #[derive(Debug, Deserialize, ToSchema)]
 pub struct StoryRequest {
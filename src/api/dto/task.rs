@@ -1,3 +1,4 @@
+use super::MAX_NAME_LEN;
 use crate::{
     domain::{Status, StoryId},
     Error, Result,
@@ -7,9 +8,6 @@ use std::fmt::Debug;
 use std::str::FromStr;
 use utoipa::ToSchema;
 
-/// Limit name size in http request body.
-const MAX_NAME_LEN: usize = 100;
-
 /// The POST body for creating tasks
 #[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateTaskRequest {
@@ -7,7 +7,7 @@ mod ctx;
 pub use ctx::Ctx;
 mod dto;
 mod routes;
-use routes::{file, status, story, task};
+use routes::{file, metrics, status, story, task, upload, version};
 mod tracer;
 
 /// The top-level API
@@ -27,8 +27,11 @@ impl Api {
             Router::new()
                 .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", docs()))
                 .merge(status::routes())
+                .merge(metrics::routes())
                 .merge(story::routes())
                 .merge(file::routes())
+                .merge(version::routes())
+                .merge(upload::routes())
                 .merge(task::routes()),
         )
         .with_state(self.ctx)
@@ -39,6 +42,8 @@ impl Api {
 pub fn docs() -> OpenApiDocs {
     let mut api = story::ApiDoc::openapi();
     api.merge(file::ApiDoc::openapi());
+    api.merge(version::ApiDoc::openapi());
+    api.merge(upload::ApiDoc::openapi());
     api.merge(task::ApiDoc::openapi());
     api
 }
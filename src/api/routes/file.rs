@@ -1,16 +1,22 @@
 use crate::{
-    action::file::{AddFiles, DeleteFile, DownloadFile},
-    api::dto::Page,
+    action::archive::DownloadArchive,
+    action::file::{
+        AddFiles, CompleteUpload, DeleteFile, DownloadFile, PresignDownload, PresignUpload,
+        UpdateFile,
+    },
+    action::thumbnail::DownloadThumbnail,
+    action::transfer::{CopyFile, MoveFile},
+    api::dto::{CompleteUploadRequest, Page, TransferFileRequest, UpdateFileRequest},
     api::Ctx,
-    domain::{StoryFile, StoryFileId, StoryId},
+    domain::{PresignedUrl, ScanStatus, StoryFile, StoryFileId, StoryId},
     error::Errors,
     Result,
 };
 use axum::{
     extract::{Multipart, Path, State},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use futures_util::TryFutureExt;
@@ -28,8 +34,32 @@ struct FileUpload {
 /// OpenApi docs for file routes
 #[derive(utoipa::OpenApi)]
 #[openapi(
-    paths(get_files, add_files, get_file, download_file, delete_file),
-    components(schemas(Errors, FileUpload, Page<StoryFile>, StoryFile)),
+    paths(
+        get_files,
+        add_files,
+        download_archive,
+        presign_upload,
+        complete_upload,
+        get_file,
+        download_file,
+        presign_download,
+        download_thumbnail,
+        update_file,
+        move_file,
+        copy_file,
+        delete_file
+    ),
+    components(schemas(
+        CompleteUploadRequest,
+        Errors,
+        FileUpload,
+        Page<StoryFile>,
+        PresignedUrl,
+        ScanStatus,
+        StoryFile,
+        TransferFileRequest,
+        UpdateFileRequest
+    )),
     tags((name = "File"))
 )]
 pub struct ApiDoc;
@@ -39,8 +69,15 @@ pub struct ApiDoc;
 pub fn routes() -> Router<Arc<Ctx>> {
     Router::new()
         .route("/stories/{story_id}/files", get(get_files).post(add_files))
-        .route("/stories/{story_id}/files/{file_id}", get(get_file).delete(delete_file))
+        .route("/stories/{story_id}/files/{file_id}", get(get_file).patch(update_file).delete(delete_file))
+        .route("/stories/{story_id}/files/archive", get(download_archive))
+        .route("/stories/{story_id}/files/presign", post(presign_upload))
+        .route("/stories/{story_id}/files/complete", post(complete_upload))
         .route("/stories/{story_id}/files/{file_id}/contents", get(download_file))
+        .route("/stories/{story_id}/files/{file_id}/presign", get(presign_download))
+        .route("/stories/{story_id}/files/{file_id}/thumbnail", get(download_thumbnail))
+        .route("/stories/{story_id}/files/{file_id}/move", post(move_file))
+        .route("/stories/{story_id}/files/{file_id}/copy", post(copy_file))
 }
 
 /// List files for a story.
@@ -77,7 +114,10 @@ async fn get_files(
     ),
     responses(
         (status = 201, description = "A metadata array for the uploaded files", body = [StoryFile]),
-        (status = 404, description = "The parent story was not found", body = Errors)
+        (status = 400, description = "No files were uploaded, or the file quota is used up", body = Errors),
+        (status = 404, description = "The parent story was not found", body = Errors),
+        (status = 413, description = "The upload exceeds the storage quota", body = Errors),
+        (status = 415, description = "The detected content type is not allowed", body = Errors)
     ),
     tag = "File"
 )]
@@ -90,26 +130,148 @@ async fn add_files(
     Ok((StatusCode::CREATED, Json(files)))
 }
 
+/// Download every file of a story as a ZIP archive.
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/files/archive",
+    params(("story_id" = StoryId, Path, description = "The parent story id")),
+    responses(
+        (status = 200, description = "A ZIP archive of the files that passed a malware scan"),
+        (status = 404, description = "The parent story was not found", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn download_archive(
+    Path(story_id): Path<StoryId>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let archive = DownloadArchive::execute(ctx, &story_id).await?;
+    Ok(archive.into_response())
+}
+
+/// Issue a presigned url for uploading file contents directly to storage.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/presign",
+    params(("story_id" = StoryId, Path, description = "The parent story id")),
+    responses(
+        (status = 201, description = "A short-lived url for a PUT upload", body = PresignedUrl),
+        (status = 400, description = "Storage does not support presigned urls, or the file quota is used up", body = Errors),
+        (status = 404, description = "The parent story was not found", body = Errors),
+        (status = 413, description = "The storage quota is used up", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn presign_upload(
+    Path(story_id): Path<StoryId>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let presigned = PresignUpload::execute(ctx, &story_id).await?;
+    Ok((StatusCode::CREATED, Json(presigned)))
+}
+
+/// Record file metadata after uploading contents with a presigned url.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/complete",
+    params(("story_id" = StoryId, Path, description = "The parent story id")),
+    request_body = CompleteUploadRequest,
+    responses(
+        (status = 201, description = "The metadata for the uploaded file", body = StoryFile),
+        (status = 400, description = "The request body was invalid", body = Errors),
+        (status = 404, description = "The parent story or upload was not found, or the upload expired or was already completed", body = Errors),
+        (status = 413, description = "The upload exceeds the storage quota", body = Errors),
+        (status = 415, description = "The detected content type is not allowed", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn complete_upload(
+    Path(story_id): Path<StoryId>,
+    State(ctx): State<Arc<Ctx>>,
+    Json(req): Json<CompleteUploadRequest>,
+) -> Result<impl IntoResponse> {
+    let (name, content_type) = req.validate()?;
+    let file = CompleteUpload::execute(ctx, &story_id, &req.storage_id, name, content_type).await?;
+    Ok((StatusCode::CREATED, Json(file)))
+}
+
 /// Download file contents.
 #[utoipa::path(
     get,
     path = "/stories/{story_id}/files/{file_id}/contents",
     params(
         ("story_id" = StoryId, Path, description = "The parent story id"),
-        ("file_id" = StoryFileId, Path, description = "The id of the file to download")
+        ("file_id" = StoryFileId, Path, description = "The id of the file to download"),
+        ("Range" = Option<String>, Header, description = "A single byte range, e.g. bytes=0-1023")
     ),
     responses(
         (status = 200, description = "The contents of the file"),
-        (status = 404, description = "The file was not found", body = Errors)
+        (status = 206, description = "The requested byte range of the file contents"),
+        (status = 403, description = "The file failed a malware scan, or could not be scanned", body = Errors),
+        (status = 404, description = "The file was not found", body = Errors),
+        (status = 416, description = "The requested byte range was not satisfiable", body = Errors),
+        (status = 423, description = "The file is waiting for a malware scan", body = Errors)
     ),
     tag = "File"
 )]
 async fn download_file(
     Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
     State(ctx): State<Arc<Ctx>>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
-    let (headers, contents) = DownloadFile::execute(ctx, &story_id, &file_id).await?;
-    Ok((headers, contents).into_response())
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let download = DownloadFile::execute(ctx, &story_id, &file_id, range).await?;
+    Ok(download.into_response())
+}
+
+/// Issue a presigned url for downloading file contents directly from storage.
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/files/{file_id}/presign",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The id of the file to download")
+    ),
+    responses(
+        (status = 200, description = "A short-lived url for a GET download", body = PresignedUrl),
+        (status = 400, description = "Storage does not support presigned urls", body = Errors),
+        (status = 403, description = "The file failed a malware scan, or could not be scanned", body = Errors),
+        (status = 404, description = "The file was not found", body = Errors),
+        (status = 423, description = "The file is waiting for a malware scan", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn presign_download(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let presigned = PresignDownload::execute(ctx, &story_id, &file_id).await?;
+    Ok(Json(presigned))
+}
+
+/// Download a thumbnail of an image file.
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/files/{file_id}/thumbnail",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The id of the image file")
+    ),
+    responses(
+        (status = 200, description = "A scaled down JPEG or PNG of the image"),
+        (status = 400, description = "The image could not be rendered", body = Errors),
+        (status = 403, description = "The file failed a malware scan, or could not be scanned", body = Errors),
+        (status = 404, description = "The file was not found, or is not an image", body = Errors),
+        (status = 423, description = "The file is waiting for a malware scan", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn download_thumbnail(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let thumbnail = DownloadThumbnail::execute(ctx, &story_id, &file_id).await?;
+    Ok(thumbnail.into_response())
 }
 
 /// Get file metadata.
@@ -138,6 +300,85 @@ async fn get_file(
     Ok(Json(file))
 }
 
+/// Rename a file, correct its content type, or describe it.
+#[utoipa::path(
+    patch,
+    path = "/stories/{story_id}/files/{file_id}",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The id of the file metadata")
+    ),
+    request_body = UpdateFileRequest,
+    responses(
+        (status = 200, description = "The updated file metadata", body = StoryFile),
+        (status = 400, description = "The request body was invalid", body = Errors),
+        (status = 404, description = "The file was not found", body = Errors),
+        (status = 415, description = "The content type does not match the file contents, or is not allowed", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn update_file(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+    Json(req): Json<UpdateFileRequest>,
+) -> Result<impl IntoResponse> {
+    let update = req.validate()?;
+    let file = UpdateFile::execute(ctx, &story_id, &file_id, update).await?;
+    Ok(Json(file))
+}
+
+/// Move a file, along with its prior versions, to another story.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/{file_id}/move",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The id of the file to move")
+    ),
+    request_body = TransferFileRequest,
+    responses(
+        (status = 200, description = "The moved file metadata", body = StoryFile),
+        (status = 400, description = "The file quota of the target story is used up", body = Errors),
+        (status = 404, description = "The file or either story was not found", body = Errors),
+        (status = 413, description = "The file exceeds the storage quota of the target story", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn move_file(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+    Json(req): Json<TransferFileRequest>,
+) -> Result<impl IntoResponse> {
+    let file = MoveFile::execute(ctx, &story_id, &file_id, &req.story_id).await?;
+    Ok(Json(file))
+}
+
+/// Copy the current contents of a file to another story.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/{file_id}/copy",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The id of the file to copy")
+    ),
+    request_body = TransferFileRequest,
+    responses(
+        (status = 201, description = "The metadata of the copy", body = StoryFile),
+        (status = 400, description = "The file quota is used up", body = Errors),
+        (status = 404, description = "The file or either story was not found", body = Errors),
+        (status = 413, description = "The copy exceeds the storage quota", body = Errors)
+    ),
+    tag = "File"
+)]
+async fn copy_file(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+    Json(req): Json<TransferFileRequest>,
+) -> Result<impl IntoResponse> {
+    let file = CopyFile::execute(ctx, &story_id, &file_id, &req.story_id).await?;
+    Ok((StatusCode::CREATED, Json(file)))
+}
+
 /// Delete a file
 #[utoipa::path(
     delete,
@@ -0,0 +1,17 @@
+use crate::{api::Ctx, Error, Result};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use std::sync::Arc;
+
+/// API route for scraping metrics
+pub fn routes() -> Router<Arc<Ctx>> {
+    Router::new().route("/metrics", get(get_metrics))
+}
+
+/// Render recorded metrics in the Prometheus text format.
+async fn get_metrics(State(ctx): State<Arc<Ctx>>) -> Result<impl IntoResponse> {
+    let Some(metrics) = &ctx.metrics else {
+        return Err(Error::not_found("metrics are not enabled"));
+    };
+    let content_type = "text/plain; version=0.0.4";
+    Ok(([(header::CONTENT_TYPE, content_type)], metrics.render()))
+}
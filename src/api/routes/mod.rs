@@ -1,4 +1,7 @@
 pub mod file;
+pub mod metrics;
 pub mod status;
 pub mod story;
 pub mod task;
+pub mod upload;
+pub mod version;
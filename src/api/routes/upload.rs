@@ -0,0 +1,221 @@
+use crate::{
+    action::upload::{AppendUpload, CreateUpload, DeleteUpload, FetchUpload},
+    api::dto::UploadMetadata,
+    api::Ctx,
+    domain::{StoryId, Upload, UploadId},
+    error::Errors,
+    Error, Result,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    routing::{head, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+
+// The tus resumable upload protocol version, and the extensions supported.
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+// The content type of upload chunks.
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// OpenApi docs for resumable upload routes
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(upload_options, create_upload, get_upload_offset, append_upload, delete_upload),
+    components(schemas(Errors, Upload)),
+    tags((name = "Upload"))
+)]
+pub struct ApiDoc;
+
+/// API routes for resumable uploads
+#[rustfmt::skip]
+pub fn routes() -> Router<Arc<Ctx>> {
+    Router::new()
+        .route("/stories/{story_id}/uploads", post(create_upload).options(upload_options))
+        .route(
+            "/stories/{story_id}/uploads/{upload_id}",
+            head(get_upload_offset).patch(append_upload).delete(delete_upload),
+        )
+}
+
+/// Describe the tus protocol support for resumable uploads.
+#[utoipa::path(
+    options,
+    path = "/stories/{story_id}/uploads",
+    params(("story_id" = StoryId, Path, description = "The parent story id")),
+    responses((status = 204, description = "The supported tus version and extensions")),
+    tag = "Upload"
+)]
+async fn upload_options() -> impl IntoResponse {
+    let headers = [
+        ("tus-resumable", TUS_VERSION),
+        ("tus-version", TUS_VERSION),
+        ("tus-extension", TUS_EXTENSIONS),
+    ];
+    (StatusCode::NO_CONTENT, headers)
+}
+
+/// Start a resumable upload of a file to a story.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/uploads",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("Upload-Length" = i64, Header, description = "The size of the file in bytes"),
+        ("Upload-Metadata" = Option<String>, Header, description = "Base64 encoded filename and filetype, e.g. filename cmVwb3J0LnBkZg==")
+    ),
+    responses(
+        (status = 201, description = "The upload was started, at the url in the location header", body = Upload),
+        (status = 400, description = "The headers were invalid, or the file quota is used up", body = Errors),
+        (status = 404, description = "The parent story was not found", body = Errors),
+        (status = 413, description = "The file exceeds the storage quota", body = Errors)
+    ),
+    tag = "Upload"
+)]
+async fn create_upload(
+    Path(story_id): Path<StoryId>,
+    State(ctx): State<Arc<Ctx>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let length = int_header(&headers, "upload-length")?;
+    let metadata = match headers.get("upload-metadata") {
+        Some(value) => UploadMetadata::parse(value.to_str().unwrap_or_default())?,
+        None => UploadMetadata::default(),
+    };
+    let (name, content_type) = metadata.validate()?;
+    let upload = CreateUpload::execute(ctx, &story_id, name, content_type, length).await?;
+    let location = format!("/stories/{}/uploads/{}", story_id, upload.id);
+    let headers = vec![
+        (header::LOCATION.to_string(), location),
+        ("upload-expires".into(), http_date(upload.expires_at)),
+        ("tus-resumable".into(), TUS_VERSION.into()),
+    ];
+    Ok((StatusCode::CREATED, AppendHeaders(headers), Json(upload)))
+}
+
+/// Get the offset to resume an upload from.
+#[utoipa::path(
+    head,
+    path = "/stories/{story_id}/uploads/{upload_id}",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("upload_id" = UploadId, Path, description = "The upload id")
+    ),
+    responses(
+        (status = 200, description = "The upload offset and length headers"),
+        (status = 404, description = "The upload was not found, or has expired")
+    ),
+    tag = "Upload"
+)]
+async fn get_upload_offset(
+    Path((story_id, upload_id)): Path<(StoryId, UploadId)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let upload = FetchUpload::execute(ctx, &story_id, &upload_id).await?;
+    let headers = vec![
+        ("upload-offset".into(), upload.offset.to_string()),
+        ("upload-length".into(), upload.length.to_string()),
+        ("upload-expires".into(), http_date(upload.expires_at)),
+        (header::CACHE_CONTROL.to_string(), "no-store".into()),
+        ("tus-resumable".into(), TUS_VERSION.into()),
+    ];
+    Ok(AppendHeaders(headers))
+}
+
+/// Append a chunk of bytes to an upload, at its current offset. Once every byte has arrived, the
+/// file is created at the url in the content-location header.
+#[utoipa::path(
+    patch,
+    path = "/stories/{story_id}/uploads/{upload_id}",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("upload_id" = UploadId, Path, description = "The upload id"),
+        ("Upload-Offset" = i64, Header, description = "The offset of the chunk, which must match the upload")
+    ),
+    request_body(content_type = "application/offset+octet-stream", content = Vec<u8>),
+    responses(
+        (status = 204, description = "The chunk was stored, and the new offset is in the upload-offset header"),
+        (status = 400, description = "The headers were invalid, or the chunk exceeds the upload length", body = Errors),
+        (status = 404, description = "The upload was not found, or has expired", body = Errors),
+        (status = 409, description = "The offset does not match the upload, or the complete upload is already being assembled", body = Errors),
+        (status = 413, description = "The upload no longer fits within the storage quota", body = Errors),
+        (status = 415, description = "The chunk content type, or detected file content type, is not allowed", body = Errors)
+    ),
+    tag = "Upload"
+)]
+async fn append_upload(
+    Path((story_id, upload_id)): Path<(StoryId, UploadId)>,
+    State(ctx): State<Arc<Ctx>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some(CHUNK_CONTENT_TYPE) {
+        let message = format!("upload chunks must have content type {CHUNK_CONTENT_TYPE}");
+        return Err(Error::unsupported_type(message));
+    }
+    let offset = int_header(&headers, "upload-offset")?;
+    let stream = body
+        .into_data_stream()
+        .map_err(|err| Error::invalid_args(err.to_string()))
+        .boxed();
+    let (upload, file) = AppendUpload::execute(ctx, &story_id, &upload_id, offset, stream).await?;
+    let mut headers = vec![
+        ("upload-offset".into(), upload.offset.to_string()),
+        ("upload-expires".into(), http_date(upload.expires_at)),
+        ("tus-resumable".into(), TUS_VERSION.into()),
+    ];
+    if let Some(file) = file {
+        let location = format!("/stories/{}/files/{}", story_id, file.id);
+        headers.push((header::CONTENT_LOCATION.to_string(), location));
+    }
+    Ok((StatusCode::NO_CONTENT, AppendHeaders(headers)))
+}
+
+/// Abandon an upload, discarding the bytes received so far.
+#[utoipa::path(
+    delete,
+    path = "/stories/{story_id}/uploads/{upload_id}",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("upload_id" = UploadId, Path, description = "The upload id")
+    ),
+    responses(
+        (status = 204, description = "The upload was deleted"),
+        (status = 404, description = "The upload was not found, or has expired")
+    ),
+    tag = "Upload"
+)]
+async fn delete_upload(
+    Path((story_id, upload_id)): Path<(StoryId, UploadId)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> StatusCode {
+    if let Err(err) = DeleteUpload::execute(ctx, &story_id, &upload_id).await {
+        return StatusCode::from(err);
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// Parse a required, non-negative integer header.
+fn int_header(headers: &HeaderMap, name: &str) -> Result<i64> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|n: &i64| *n >= 0)
+        .ok_or_else(|| Error::invalid_args(format!("{name}: missing or invalid header")))
+}
+
+/// Format a timestamp as a http date.
+fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
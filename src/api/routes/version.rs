@@ -0,0 +1,156 @@
+use crate::{
+    action::version::{AddVersion, DownloadVersion, RestoreVersion},
+    api::dto::Page,
+    api::Ctx,
+    domain::{FileVersion, StoryFile, StoryFileId, StoryId},
+    error::Errors,
+    Result,
+};
+use axum::{
+    extract::{Multipart, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::TryFutureExt;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+// Just necessary for api docs
+#[derive(ToSchema)]
+#[allow(unused)]
+struct VersionUpload {
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+/// OpenApi docs for file version routes
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(get_versions, add_version, download_version, restore_version),
+    components(schemas(Errors, FileVersion, Page<FileVersion>, StoryFile, VersionUpload)),
+    tags((name = "Version"))
+)]
+pub struct ApiDoc;
+
+/// API routes for file versions
+#[rustfmt::skip]
+pub fn routes() -> Router<Arc<Ctx>> {
+    Router::new()
+        .route("/stories/{story_id}/files/{file_id}/versions", get(get_versions).post(add_version))
+        .route("/stories/{story_id}/files/{file_id}/versions/{version}/contents", get(download_version))
+        .route("/stories/{story_id}/files/{file_id}/versions/{version}/restore", post(restore_version))
+}
+
+/// List the prior versions of a file, newest first.
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/files/{file_id}/versions",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The file id")
+    ),
+    responses(
+        (status = 200, description = "The prior versions of the file", body = Page<FileVersion>),
+        (status = 404, description = "The file was not found", body = Errors)
+    ),
+    tag = "Version"
+)]
+async fn get_versions(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let versions = ctx
+        .repo
+        .fetch_story(&story_id)
+        .and_then(|_| ctx.repo.fetch_file(&story_id, &file_id))
+        .and_then(|_| ctx.repo.list_versions(&file_id))
+        .await?;
+    Ok(Json(Page::single(versions)))
+}
+
+/// Upload new contents for a file, keeping the current contents as a prior version.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/{file_id}/versions",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The file id")
+    ),
+    request_body(
+        content_type = "multipart/form-data",
+        content = VersionUpload,
+    ),
+    responses(
+        (status = 201, description = "The file metadata with the new version", body = StoryFile),
+        (status = 400, description = "No file was uploaded", body = Errors),
+        (status = 404, description = "The file was not found", body = Errors),
+        (status = 413, description = "The upload exceeds the storage quota", body = Errors),
+        (status = 415, description = "The detected content type is not allowed", body = Errors)
+    ),
+    tag = "Version"
+)]
+async fn add_version(
+    Path((story_id, file_id)): Path<(StoryId, StoryFileId)>,
+    State(ctx): State<Arc<Ctx>>,
+    multipart: Multipart,
+) -> Result<impl IntoResponse> {
+    let file = AddVersion::execute(ctx, &story_id, &file_id, multipart).await?;
+    Ok((StatusCode::CREATED, Json(file)))
+}
+
+/// Download the contents of a prior file version.
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/files/{file_id}/versions/{version}/contents",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The file id"),
+        ("version" = i32, Path, description = "The prior version number"),
+        ("Range" = Option<String>, Header, description = "A single byte range, e.g. bytes=0-1023")
+    ),
+    responses(
+        (status = 200, description = "The contents of the file version"),
+        (status = 206, description = "The requested byte range of the file version contents"),
+        (status = 403, description = "The version failed a malware scan, or could not be scanned", body = Errors),
+        (status = 404, description = "The file or version was not found", body = Errors),
+        (status = 416, description = "The requested byte range was not satisfiable", body = Errors),
+        (status = 423, description = "The version is waiting for a malware scan", body = Errors)
+    ),
+    tag = "Version"
+)]
+async fn download_version(
+    Path((story_id, file_id, version)): Path<(StoryId, StoryFileId, i32)>,
+    State(ctx): State<Arc<Ctx>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let download = DownloadVersion::execute(ctx, &story_id, &file_id, version, range).await?;
+    Ok(download.into_response())
+}
+
+/// Make a prior version of a file current again. The restored contents become the newest
+/// version, and the replaced contents are kept as a prior version.
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/files/{file_id}/versions/{version}/restore",
+    params(
+        ("story_id" = StoryId, Path, description = "The parent story id"),
+        ("file_id" = StoryFileId, Path, description = "The file id"),
+        ("version" = i32, Path, description = "The prior version number to restore")
+    ),
+    responses(
+        (status = 200, description = "The file metadata with the restored version", body = StoryFile),
+        (status = 404, description = "The file or version was not found", body = Errors),
+        (status = 413, description = "The restored version exceeds the storage quota", body = Errors)
+    ),
+    tag = "Version"
+)]
+async fn restore_version(
+    Path((story_id, file_id, version)): Path<(StoryId, StoryFileId, i32)>,
+    State(ctx): State<Arc<Ctx>>,
+) -> Result<impl IntoResponse> {
+    let file = RestoreVersion::execute(ctx, &story_id, &file_id, version).await?;
+    Ok(Json(file))
+}
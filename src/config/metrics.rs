@@ -0,0 +1,19 @@
+use crate::config::Config;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+// Latency histogram buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+impl Config {
+    /// Install a global metrics recorder, returning a handle that renders metrics in the
+    /// Prometheus text format. WARN: panics if a recorder is already installed.
+    pub fn install_metrics(&self) -> PrometheusHandle {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("seconds".into()), LATENCY_BUCKETS)
+            .expect("invalid latency buckets")
+            .install_recorder()
+            .expect("unable to install metrics recorder")
+    }
+}
@@ -1,6 +1,8 @@
 use std::env;
 
 mod database;
+mod metrics;
+mod scanner;
 mod storage;
 mod tcp;
 
@@ -16,6 +18,29 @@ pub struct Config {
     pub storage_minio_base_url: Option<String>,
     pub storage_minio_access_key: Option<String>,
     pub storage_minio_secret_key: Option<String>,
+    pub storage_s3_endpoint: Option<String>,
+    pub storage_s3_region: Option<String>,
+    pub storage_s3_access_key: Option<String>,
+    pub storage_s3_secret_key: Option<String>,
+    pub storage_s3_session_token: Option<String>,
+    pub storage_s3_path_style: bool,
+    pub storage_file_sync: Option<String>,
+    pub storage_memory_max_bytes: Option<u64>,
+    pub storage_memory_when_full: Option<String>,
+    pub storage_memory_snapshot: Option<String>,
+    pub storage_replica_prefix: Option<String>,
+    pub storage_encryption_key: Option<String>,
+    pub storage_compression: Option<String>,
+    pub storage_cache_size: Option<u64>,
+    pub storage_cache_dir: Option<String>,
+    pub storage_cache_dir_size: u64,
+    pub story_quota_bytes: Option<i64>,
+    pub story_quota_files: Option<i64>,
+    pub global_quota_bytes: Option<i64>,
+    pub global_quota_files: Option<i64>,
+    pub upload_allowed_types: Vec<String>,
+    pub scanner_type: Option<String>,
+    pub scanner_address: String,
 }
 
 /// Default for config just calls basic constructor
@@ -28,6 +53,14 @@ impl Default for Config {
 impl Config {
     /// Load config from env vars.
     pub fn load() -> Self {
+        Self::load_with_storage_prefix("")
+    }
+
+    /// Load config from env vars, reading storage settings from vars with a name prefix, e.g.
+    /// `DEST_STORAGE_TYPE` for a `DEST_` prefix.
+    pub fn load_with_storage_prefix(prefix: &str) -> Self {
+        let storage_var = |name: &str| env::var(format!("{prefix}{name}"));
+
         // http server settings
         let port = env::var("HTTP_SERVER_PORT").unwrap_or("8080".into());
         let listen_addr = format!("0.0.0.0:{port}");
@@ -39,13 +72,83 @@ impl Config {
         }
         let db_url = env::var("DATABASE_URL").expect("DB_HOST not set");
         let db_schema = env::var("DATABASE_SCHEMA").unwrap_or("public".to_string());
-        let storage_type = env::var("STORAGE_TYPE").expect("STORAGE_TYPE not set");
-        let storage_bucket = env::var("STORAGE_BUCKET").unwrap_or(".storage".to_string());
+        let storage_type = storage_var("STORAGE_TYPE").expect("STORAGE_TYPE not set");
+        let storage_bucket = storage_var("STORAGE_BUCKET").unwrap_or(".storage".to_string());
 
         // Check for extra minio configs
-        let storage_minio_base_url = env::var("STORAGE_MINIO_BASE_URL").ok();
-        let storage_minio_access_key = env::var("STORAGE_MINIO_ACCESS_KEY").ok();
-        let storage_minio_secret_key = env::var("STORAGE_MINIO_SECRET_KEY").ok();
+        let storage_minio_base_url = storage_var("STORAGE_MINIO_BASE_URL").ok();
+        let storage_minio_access_key = storage_var("STORAGE_MINIO_ACCESS_KEY").ok();
+        let storage_minio_secret_key = storage_var("STORAGE_MINIO_SECRET_KEY").ok();
+
+        // Check for extra generic s3 configs
+        let storage_s3_endpoint = storage_var("STORAGE_S3_ENDPOINT").ok();
+        let storage_s3_region = storage_var("STORAGE_S3_REGION").ok();
+        let storage_s3_access_key = storage_var("STORAGE_S3_ACCESS_KEY").ok();
+        let storage_s3_secret_key = storage_var("STORAGE_S3_SECRET_KEY").ok();
+        let storage_s3_session_token = storage_var("STORAGE_S3_SESSION_TOKEN").ok();
+        let mut storage_s3_path_style = false;
+        if let Ok(s) = storage_var("STORAGE_S3_PATH_STYLE") {
+            storage_s3_path_style = s
+                .parse()
+                .expect("STORAGE_S3_PATH_STYLE could not be parsed")
+        }
+
+        // How durably file storage writes files: none, file or full
+        let storage_file_sync = storage_var("STORAGE_FILE_SYNC").ok();
+
+        // Memory storage limits: max bytes held, and what to do when full: evict or reject
+        let storage_memory_max_bytes = storage_var("STORAGE_MEMORY_MAX_BYTES").ok().map(|s| {
+            s.parse()
+                .expect("STORAGE_MEMORY_MAX_BYTES could not be parsed")
+        });
+        let storage_memory_when_full = storage_var("STORAGE_MEMORY_WHEN_FULL").ok();
+
+        // File that memory storage is restored from on startup, and saved to on shutdown
+        let storage_memory_snapshot = storage_var("STORAGE_MEMORY_SNAPSHOT").ok();
+
+        // Secondary storage that every object is replicated to, configured with env vars named
+        // with this prefix, e.g. `REPLICA_STORAGE_TYPE` for a `REPLICA_` prefix
+        let storage_replica_prefix = storage_var("STORAGE_REPLICA_PREFIX").ok();
+
+        // Base64 encoded master key for encrypting contents at rest
+        let storage_encryption_key = storage_var("STORAGE_ENCRYPTION_KEY").ok();
+
+        // Compression encoding for stored contents: gzip or zstd
+        let storage_compression = storage_var("STORAGE_COMPRESSION").ok();
+
+        // Read-through cache for stored contents: bytes held in memory, and an optional dir
+        // for objects too large for memory, with its size in bytes
+        let storage_cache_size = storage_var("STORAGE_CACHE_SIZE")
+            .ok()
+            .map(|s| s.parse().expect("STORAGE_CACHE_SIZE could not be parsed"));
+        let storage_cache_dir = storage_var("STORAGE_CACHE_DIR").ok();
+        let mut storage_cache_dir_size = 1 << 30;
+        if let Ok(s) = storage_var("STORAGE_CACHE_DIR_SIZE") {
+            storage_cache_dir_size = s
+                .parse()
+                .expect("STORAGE_CACHE_DIR_SIZE could not be parsed")
+        }
+
+        // Limits on stored file contents, per story and for all stories
+        let quota_var = |name: &str| {
+            env::var(name).ok().map(|s| {
+                s.parse()
+                    .unwrap_or_else(|_| panic!("{name} could not be parsed"))
+            })
+        };
+        let story_quota_bytes = quota_var("STORY_QUOTA_BYTES");
+        let story_quota_files = quota_var("STORY_QUOTA_FILES");
+        let global_quota_bytes = quota_var("GLOBAL_QUOTA_BYTES");
+        let global_quota_files = quota_var("GLOBAL_QUOTA_FILES");
+
+        // Comma separated content types accepted for uploads, e.g. "image/*,application/pdf"
+        let upload_allowed_types = env::var("UPLOAD_ALLOWED_TYPES")
+            .map(|s| s.split(',').map(String::from).collect())
+            .unwrap_or_default();
+
+        // Malware scanner for uploads: clamd or fake
+        let scanner_type = env::var("SCANNER_TYPE").ok();
+        let scanner_address = env::var("SCANNER_ADDRESS").unwrap_or("localhost:3310".into());
 
         // Create config
         Self {
@@ -58,6 +161,29 @@ impl Config {
             storage_minio_base_url,
             storage_minio_access_key,
             storage_minio_secret_key,
+            storage_s3_endpoint,
+            storage_s3_region,
+            storage_s3_access_key,
+            storage_s3_secret_key,
+            storage_s3_session_token,
+            storage_s3_path_style,
+            storage_file_sync,
+            storage_memory_max_bytes,
+            storage_memory_when_full,
+            storage_memory_snapshot,
+            storage_replica_prefix,
+            storage_encryption_key,
+            storage_compression,
+            storage_cache_size,
+            storage_cache_dir,
+            storage_cache_dir_size,
+            story_quota_bytes,
+            story_quota_files,
+            global_quota_bytes,
+            global_quota_files,
+            upload_allowed_types,
+            scanner_type,
+            scanner_address,
         }
     }
 }
@@ -0,0 +1,17 @@
+use crate::{
+    config::Config,
+    domain::Scanner,
+    driver::scanner::{clamd::ClamdScanner, fake::FakeScanner},
+};
+
+impl Config {
+    /// Load a dynamic malware scanner instance, if configured. WARN: panics on unknown types.
+    pub fn load_scanner(&self) -> Option<Box<dyn Scanner>> {
+        let scanner: Box<dyn Scanner> = match self.scanner_type.as_deref()? {
+            "clamd" => Box::new(ClamdScanner::new(self.scanner_address.clone())),
+            "fake" => Box::new(FakeScanner::new()),
+            other => panic!("unknown scanner type: {other}"),
+        };
+        Some(scanner)
+    }
+}
@@ -1,20 +1,158 @@
 use crate::{
     config::Config,
-    domain::Storage,
-    driver::storage::{fs::FileStorage, mem::MemoryStorage, minio::MinioStorage},
+    domain::{AllowedTypes, Quota, Quotas, RepairQueue, Storage},
+    driver::storage::{
+        cached::CachedStorage,
+        compressed::CompressedStorage,
+        encrypted::EncryptedStorage,
+        fs::{FileStorage, FileSync},
+        mem::{MemoryStorage, WhenFull},
+        metered::MeteredStorage,
+        minio::MinioStorage,
+        replicated::ReplicatedStorage,
+    },
+    repo::Repo,
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use minio::s3::Client;
 use minio::s3::{creds::StaticProvider, http::BaseUrl};
+use std::{sync::Arc, time::Duration};
+
+// How often to repair divergence between storage replicas.
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60);
 
 impl Config {
-    /// Load a dynamic storage instance.
-    pub fn load_storage(&self) -> Box<dyn Storage> {
-        match self.storage_type.as_str() {
-            "file" => Box::new(FileStorage::new(self.storage_bucket.clone())),
-            "minio" => Box::new(MinioStorage::new(self)),
-            _ => Box::new(MemoryStorage::new()),
+    /// Load a dynamic storage instance, using the repo for state that must be shared or
+    /// outlive the process.
+    pub fn load_storage(&self, repo: &Arc<Repo>) -> Box<dyn Storage> {
+        let storage = self.replicate_storage(self.load_driver(), repo);
+        // Cache stored bytes as they are, so cache hits skip the backend but still decrypt
+        let storage = self.cache_storage(storage);
+        // Compress before encrypting, since ciphertext doesn't compress
+        let storage: Box<dyn Storage> = match self.storage_encryption_key() {
+            Some(key) => Box::new(EncryptedStorage::new(storage, &key)),
+            None => storage,
+        };
+        match &self.storage_compression {
+            Some(encoding) => {
+                let encoding = encoding.parse().expect("unknown storage compression");
+                Box::new(CompressedStorage::new(storage, encoding))
+            }
+            None => storage,
+        }
+    }
+
+    /// Load the driver for the storage type, metered by itself so metrics reflect the backend
+    /// rather than the decorators.
+    fn load_driver(&self) -> Box<dyn Storage> {
+        let (storage, driver): (Box<dyn Storage>, _) = match self.storage_type.as_str() {
+            "file" => {
+                let sync = match &self.storage_file_sync {
+                    Some(sync) => sync.parse().expect("unknown storage file sync"),
+                    None => FileSync::default(),
+                };
+                (Box::new(file_storage(&self.storage_bucket, sync)), "file")
+            }
+            "minio" => (Box::new(MinioStorage::new(self)), "minio"),
+            "s3" => (
+                Box::new(MinioStorage::with_client(
+                    self.storage_bucket.clone(),
+                    self.create_s3_client(),
+                )),
+                "s3",
+            ),
+            _ => (Box::new(self.memory_storage()), "memory"),
+        };
+        Box::new(MeteredStorage::new(storage, driver))
+    }
+
+    /// Create memory storage, limited in size and restored from a snapshot if configured.
+    /// WARN: panics on misconfiguration.
+    fn memory_storage(&self) -> MemoryStorage {
+        let mut storage = MemoryStorage::new();
+        if let Some(max_bytes) = self.storage_memory_max_bytes {
+            let when_full = match &self.storage_memory_when_full {
+                Some(when_full) => when_full
+                    .parse()
+                    .expect("unknown memory storage full policy"),
+                None => WhenFull::default(),
+            };
+            storage = storage.with_limit(max_bytes, when_full);
+        }
+        match &self.storage_memory_snapshot {
+            Some(path) => storage
+                .with_snapshot(path)
+                .expect("unable to restore memory storage snapshot"),
+            None => storage,
+        }
+    }
+
+    /// Replicate storage to a secondary driver, if a replica prefix is set. Divergence between
+    /// the replicas is queued in the repo, and repaired in the background when running in a
+    /// tokio runtime.
+    fn replicate_storage(&self, storage: Box<dyn Storage>, repo: &Arc<Repo>) -> Box<dyn Storage> {
+        let Some(prefix) = &self.storage_replica_prefix else {
+            return storage;
+        };
+        let config = Config::load_with_storage_prefix(prefix);
+        let replica = format!("{}:{}", config.storage_type, config.storage_bucket);
+        let secondary = config.load_driver();
+        let repairs = Arc::clone(repo) as Arc<dyn RepairQueue>;
+        let replicated = ReplicatedStorage::new(storage, secondary, replica, repairs);
+        if tokio::runtime::Handle::try_current().is_ok() {
+            replicated.spawn_repairs(REPAIR_INTERVAL);
         }
+        Box::new(replicated)
+    }
+
+    /// Wrap storage in a read-through cache, if a cache size or dir is set.
+    fn cache_storage(&self, storage: Box<dyn Storage>) -> Box<dyn Storage> {
+        if self.storage_cache_size.is_none() && self.storage_cache_dir.is_none() {
+            return storage;
+        }
+        let cached = CachedStorage::new(storage, self.storage_cache_size.unwrap_or_default());
+        match &self.storage_cache_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir).expect("unable to create storage cache dir");
+                // Cached files can always be read again, so aren't synced
+                let disk = file_storage(dir, FileSync::None);
+                Box::new(cached.with_disk(disk, self.storage_cache_dir_size))
+            }
+            None => Box::new(cached),
+        }
+    }
+
+    /// Limits on stored file contents.
+    pub fn quotas(&self) -> Quotas {
+        Quotas {
+            story: Quota {
+                max_bytes: self.story_quota_bytes,
+                max_files: self.story_quota_files,
+            },
+            global: Quota {
+                max_bytes: self.global_quota_bytes,
+                max_files: self.global_quota_files,
+            },
+        }
+    }
+
+    /// Content types accepted for uploads.
+    pub fn allowed_types(&self) -> AllowedTypes {
+        AllowedTypes::new(self.upload_allowed_types.clone())
+    }
+
+    /// Decode the storage master key, if set. WARN: panics on misconfiguration.
+    fn storage_encryption_key(&self) -> Option<[u8; 32]> {
+        let key = self.storage_encryption_key.as_ref()?;
+        let bytes = STANDARD
+            .decode(key)
+            .expect("unable to decode storage encryption key");
+        Some(
+            bytes
+                .try_into()
+                .expect("storage encryption key must be 32 bytes"),
+        )
     }
 
     /// Create a MinIO client from this config. WARN: panics on misconfiguration.
@@ -41,4 +179,42 @@ impl Config {
         Client::new(base_url, Some(Box::new(provider)), None, None)
             .expect("unable to create minio client")
     }
+
+    /// Create a generic S3 client from this config. WARN: panics on misconfiguration.
+    pub fn create_s3_client(&self) -> Client {
+        let access_key = self
+            .storage_s3_access_key
+            .clone()
+            .expect("s3 access key not set");
+
+        let secret_key = self
+            .storage_s3_secret_key
+            .clone()
+            .expect("s3 secret key not set");
+
+        let provider = StaticProvider::new(
+            &access_key,
+            &secret_key,
+            self.storage_s3_session_token.as_deref(),
+        );
+
+        let mut base_url: BaseUrl = self
+            .storage_s3_endpoint
+            .clone()
+            .expect("s3 endpoint not set")
+            .parse()
+            .expect("unable to parse s3 endpoint");
+        if let Some(region) = &self.storage_s3_region {
+            base_url.region = region.clone();
+        }
+        base_url.virtual_style = !self.storage_s3_path_style;
+
+        Client::new(base_url, Some(Box::new(provider)), None, None)
+            .expect("unable to create s3 client")
+    }
+}
+
+/// Create file storage in a dir.
+fn file_storage(dir: &str, sync: FileSync) -> FileStorage {
+    FileStorage::new(dir.to_string()).with_sync(sync)
 }
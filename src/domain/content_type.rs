@@ -0,0 +1,102 @@
+// Plain text types that a client may use to refine sniffed plain text.
+const TEXT_TYPES: &[&str] = &[
+    "application/json",
+    "text/csv",
+    "text/markdown",
+    "text/plain",
+    "text/tab-separated-values",
+];
+
+// Fallbacks for contents with no recognized signature.
+const PLAIN: &str = "text/plain";
+const OCTET: &str = "application/octet-stream";
+
+/// Detect the content type of file contents from their leading bytes. Contents without a known
+/// signature are plain text or binary, and a client may only narrow plain text to a text type.
+pub fn sniff_content_type(head: &[u8], claimed: &str) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+    if !is_text(head) {
+        return OCTET.to_string();
+    }
+    let claimed = claimed.split(';').next().unwrap_or_default().trim();
+    let claimed = claimed.to_ascii_lowercase();
+    if TEXT_TYPES.contains(&claimed.as_str()) {
+        return claimed;
+    }
+    PLAIN.to_string()
+}
+
+/// Whether bytes look like UTF-8 text, allowing for a character cut off at the end.
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    }
+}
+
+/// Content types accepted for uploads, as exact types or `type/*` wildcards. An empty
+/// allowlist accepts any type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowedTypes(Vec<String>);
+
+impl AllowedTypes {
+    /// Create an allowlist from content type patterns.
+    pub fn new(patterns: Vec<String>) -> Self {
+        let patterns = patterns
+            .into_iter()
+            .map(|p| p.trim().to_ascii_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        Self(patterns)
+    }
+
+    /// Whether a content type is allowed.
+    pub fn allows(&self, content_type: &str) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        let content_type = content_type.to_ascii_lowercase();
+        self.0
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(prefix) => content_type.split('/').next() == Some(prefix),
+                None => *pattern == content_type,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_content_types() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(sniff_content_type(png, "text/plain"), "image/png");
+        let html = b"<!DOCTYPE html><html><body>hi</body></html>";
+        assert_eq!(sniff_content_type(html, "image/png"), "text/html");
+        assert_eq!(sniff_content_type(b"a,b\n1,2\n", "text/csv"), "text/csv");
+        assert_eq!(sniff_content_type(b"{\"a\": 1}", "image/png"), "text/plain");
+        assert_eq!(sniff_content_type(b"\0\x01\x02", "text/plain"), OCTET);
+        assert_eq!(sniff_content_type("caf\u{e9}".as_bytes(), ""), "text/plain");
+        assert_eq!(
+            sniff_content_type(&"caf\u{e9}".as_bytes()[..4], ""),
+            "text/plain"
+        );
+    }
+
+    #[test]
+    fn allowed_types() {
+        let allowed = AllowedTypes::new(vec!["image/*".into(), " Text/CSV ".into()]);
+        assert!(allowed.allows("image/png"));
+        assert!(allowed.allows("text/csv"));
+        assert!(!allowed.allows("text/html"));
+        assert!(!allowed.allows("imagex/png"));
+        assert!(AllowedTypes::default().allows("text/html"));
+    }
+}
@@ -1,6 +1,8 @@
-use super::{StorageId, StoryId};
+use super::{ScanStatus, StorageId, StoryId};
+use crate::{Error, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
@@ -22,7 +24,141 @@ pub struct StoryFile {
     pub storage_id: StorageId,
     pub name: String,
     pub size: i64,
+    /// Content type detected from the file contents
     pub content_type: String,
+    /// Hex encoded SHA-256 checksum of the file contents
+    pub sha256: Option<String>,
+    /// Whether the file contents have passed a malware scan
+    pub scan_status: ScanStatus,
+    /// The version number of the current contents, starting at 1
+    pub version: i32,
+    /// Free-text notes about the file
+    pub description: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+impl StoryFile {
+    /// Check that the file contents passed a malware scan, before they are served.
+    pub fn check_scanned(&self) -> Result<()> {
+        match self.scan_status {
+            ScanStatus::Clean => Ok(()),
+            ScanStatus::Pending => Err(Error::locked(format!(
+                "file {} is waiting for a malware scan",
+                self.id
+            ))),
+            ScanStatus::Infected => Err(Error::forbidden(format!(
+                "file {} failed a malware scan",
+                self.id
+            ))),
+            ScanStatus::Failed => Err(Error::forbidden(format!(
+                "file {} could not be scanned for malware",
+                self.id
+            ))),
+        }
+    }
+}
+
+/// Changes to the metadata of a file. Unset fields are left as they are, and a description of
+/// `Some(None)` clears it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FileUpdate {
+    pub name: Option<String>,
+    pub content_type: Option<String>,
+    pub description: Option<Option<String>>,
+}
+
+/// Prior contents of a file, kept when a new version is uploaded or an old one restored.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, ToSchema)]
+pub struct FileVersion {
+    pub file_id: StoryFileId,
+    pub version: i32,
+    pub storage_id: StorageId,
+    pub size: i64,
+    pub content_type: String,
+    pub sha256: Option<String>,
+    pub scan_status: ScanStatus,
+    /// When these contents were replaced by a newer version
+    pub replaced_at: DateTime<Utc>,
+}
+
+impl FileVersion {
+    /// The file as it was at this version, for serving the prior contents.
+    pub fn apply(self, file: StoryFile) -> StoryFile {
+        StoryFile {
+            storage_id: self.storage_id,
+            size: self.size,
+            content_type: self.content_type,
+            sha256: self.sha256,
+            scan_status: self.scan_status,
+            version: self.version,
+            ..file
+        }
+    }
+}
+
+/// Choose unique names for files in an archive, numbering duplicates like `notes (1).txt`.
+/// Names are compared case-insensitively, and can't contain paths.
+pub fn unique_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut taken = HashSet::new();
+    names
+        .into_iter()
+        .map(|name| {
+            let mut name = name.trim().replace(['/', '\\'], "_");
+            if name.trim_matches('.').is_empty() {
+                name = "file".to_string();
+            }
+            let (stem, ext) = match name.rfind('.') {
+                Some(i) if i > 0 => name.split_at(i),
+                _ => (name.as_str(), ""),
+            };
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !taken.insert(unique.to_lowercase()) {
+                unique = format!("{stem} ({n}){ext}");
+                n += 1;
+            }
+            unique
+        })
+        .collect()
+}
+
+/// A short-lived url for transferring file contents directly with storage.
+#[derive(Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PresignedUrl {
+    pub storage_id: StorageId,
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_file_names() {
+        let names = [
+            "notes.txt",
+            "Notes.txt",
+            "notes (1).txt",
+            "notes.txt",
+            "../etc/passwd",
+            "..",
+            "README",
+            "README",
+        ];
+        assert_eq!(
+            unique_names(names),
+            vec![
+                "notes.txt",
+                "Notes (1).txt",
+                "notes (1) (1).txt",
+                "notes (2).txt",
+                ".._etc_passwd",
+                "file",
+                "README",
+                "README (1)",
+            ]
+        );
+    }
+}
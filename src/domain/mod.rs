@@ -1,11 +1,26 @@
+mod content_type;
 mod file;
+mod quota;
+mod replica;
+mod scan;
 mod status;
 mod storage;
 mod story;
 mod task;
+mod thumbnail;
+mod upload;
 
-pub use file::{StoryFile, StoryFileId};
+pub use content_type::{sniff_content_type, AllowedTypes};
+pub use file::{unique_names, FileUpdate, FileVersion, PresignedUrl, StoryFile, StoryFileId};
+pub use quota::{Quota, Quotas, Usage};
+pub use replica::{Repair, RepairQueue, StorageRepair};
+pub use scan::{ScanStatus, ScanVerdict, Scanner};
 pub use status::Status;
-pub use storage::{Storage, StorageId};
+pub use storage::{
+    peek, ByteRange, ByteStream, ObjectStream, PendingMigration, Storage, StorageDeletion,
+    StorageId, StoredObject,
+};
 pub use story::{Story, StoryId};
 pub use task::{Task, TaskId};
+pub use thumbnail::{has_thumbnail, render_thumbnail, Thumbnail, THUMBNAIL_SIZE};
+pub use upload::{Upload, UploadId};
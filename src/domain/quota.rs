@@ -0,0 +1,99 @@
+/// Limits on the contents stored for files. Unset limits are unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<i64>,
+    pub max_files: Option<i64>,
+}
+
+/// Quotas that apply to each story, and to all stories together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    pub story: Quota,
+    pub global: Quota,
+}
+
+/// The contents stored for files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: i64,
+    pub files: i64,
+}
+
+impl Quota {
+    /// Whether no limits are set.
+    pub fn is_unbounded(&self) -> bool {
+        self.max_bytes.is_none() && self.max_files.is_none()
+    }
+
+    /// The quota left over after some usage.
+    pub fn remaining(&self, usage: Usage) -> Self {
+        Self {
+            max_bytes: self.max_bytes.map(|max| (max - usage.bytes).max(0)),
+            max_files: self.max_files.map(|max| (max - usage.files).max(0)),
+        }
+    }
+
+    /// The tighter limits of two quotas.
+    pub fn min(&self, other: Self) -> Self {
+        let min = |a: Option<i64>, b: Option<i64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            _ => a.or(b),
+        };
+        Self {
+            max_bytes: min(self.max_bytes, other.max_bytes),
+            max_files: min(self.max_files, other.max_files),
+        }
+    }
+
+    /// Whether another file of a given size fits within the quota.
+    pub fn allows(&self, size: i64) -> bool {
+        self.max_files.is_none_or(|max| max >= 1) && self.max_bytes.is_none_or(|max| max >= size)
+    }
+
+    /// Describe the quota as what remains, e.g. "1024 bytes and 3 files remaining".
+    pub fn describe(&self) -> String {
+        let limit = |max: Option<i64>| max.map_or("unlimited".to_string(), |n| n.to_string());
+        format!(
+            "{} bytes and {} files remaining",
+            limit(self.max_bytes),
+            limit(self.max_files)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_quota() {
+        let story = Quota {
+            max_bytes: Some(100),
+            max_files: Some(2),
+        };
+        let global = Quota {
+            max_bytes: Some(50),
+            max_files: None,
+        };
+        let usage = Usage {
+            bytes: 30,
+            files: 1,
+        };
+        let remaining = story.remaining(usage).min(global);
+        assert_eq!(remaining.max_bytes, Some(50));
+        assert_eq!(remaining.max_files, Some(1));
+        assert!(remaining.allows(50));
+        assert!(!remaining.allows(51));
+        assert_eq!(remaining.describe(), "50 bytes and 1 files remaining");
+
+        // Usage past the limits leaves nothing
+        let usage = Usage {
+            bytes: 200,
+            files: 5,
+        };
+        let remaining = story.remaining(usage);
+        assert_eq!(remaining.max_bytes, Some(0));
+        assert!(!remaining.allows(0));
+        assert!(Quota::default().allows(i64::MAX));
+    }
+}
@@ -0,0 +1,65 @@
+use super::StorageId;
+use crate::Result;
+use chrono::{DateTime, Duration, Utc};
+use strum_macros::{Display, EnumString};
+
+/// How to bring the replicas of a stored object back in line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Repair {
+    /// Copy the object to the secondary, or delete it there if the primary has none.
+    CopyToSecondary,
+    /// Copy the object from the secondary back to the primary.
+    CopyToPrimary,
+    /// Delete the object from the secondary.
+    DeleteSecondary,
+}
+
+/// A queued repair for a stored object, as of when it was queued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageRepair {
+    pub storage_id: StorageId,
+    pub repair: Repair,
+    /// The number of times the repair was leased, including the current lease
+    pub attempts: i32,
+    pub queued_at: DateTime<Utc>,
+}
+
+/// Durable queue of repairs between storage replicas, named by a label for the secondary.
+/// Each object has at most one queued repair, the most recent.
+#[async_trait::async_trait]
+pub trait RepairQueue: Send + Sync {
+    /// Queue a repair for an object, due after a delay, replacing any already queued.
+    async fn queue_repair(
+        &self,
+        replica: &str,
+        storage_id: &StorageId,
+        repair: Repair,
+        delay: Duration,
+    ) -> Result<()>;
+
+    /// Drop any repair queued for an object, e.g. once both replicas are written.
+    async fn cancel_repair(&self, replica: &str, storage_id: &StorageId) -> Result<()>;
+
+    /// Lease a batch of due repairs, hiding them from other instances for a while. The lease
+    /// doubles with each attempt, so it also backs off retries of failed repairs.
+    async fn lease_repairs(
+        &self,
+        replica: &str,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<StorageRepair>>;
+
+    /// Remove a leased repair once it succeeds, unless another was queued meanwhile.
+    async fn complete_repair(&self, replica: &str, repair: &StorageRepair) -> Result<()>;
+
+    /// Record why a leased repair failed. It is retried once the lease expires, unless given
+    /// up on, when it is kept as failed until another repair is queued for the object.
+    async fn fail_repair(
+        &self,
+        replica: &str,
+        repair: &StorageRepair,
+        error: &str,
+        give_up: bool,
+    ) -> Result<()>;
+}
@@ -0,0 +1,47 @@
+use super::ByteStream;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use utoipa::ToSchema;
+
+/// Malware scan states for file contents. Only clean files can be downloaded. Contents that
+/// can't be scanned after several attempts are marked failed.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    EnumString,
+    Display,
+    Deserialize,
+    Serialize,
+    ToSchema,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    #[default]
+    Pending,
+    Clean,
+    Infected,
+    Failed,
+}
+
+/// The outcome of scanning file contents, naming the signature of any malware found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+/// Malware scanner for file contents.
+#[async_trait::async_trait]
+pub trait Scanner: Send + Sync {
+    /// Scan a stream of file contents.
+    async fn scan(&self, stream: ByteStream<'_>) -> Result<ScanVerdict>;
+}
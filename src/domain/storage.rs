@@ -1,6 +1,13 @@
-use crate::Result;
-use bytes::Bytes;
+use crate::{Error, Result};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    future,
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
@@ -15,15 +22,207 @@ impl std::fmt::Display for StorageId {
     }
 }
 
+/// A stream of binary object chunks.
+pub type ByteStream<'a> = BoxStream<'a, Result<Bytes>>;
+
+/// Buffer bytes from the front of a stream until there are at least `len`, or it ends.
+pub async fn peek(stream: &mut ByteStream<'_>, len: usize) -> Result<BytesMut> {
+    let mut buf = BytesMut::new();
+    while buf.len() < len {
+        match stream.try_next().await? {
+            Some(chunk) => buf.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(buf)
+}
+
+/// Metadata for a binary object held in storage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredObject {
+    pub storage_id: StorageId,
+    pub size: u64,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// A queued request to purge a binary object from storage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageDeletion {
+    pub storage_id: StorageId,
+    pub attempts: i32,
+}
+
+/// Stored contents that have yet to be copied to another storage backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingMigration {
+    pub storage_id: StorageId,
+    pub size: i64,
+    pub sha256: Option<String>,
+}
+
+/// A stream of stored object metadata.
+pub type ObjectStream = BoxStream<'static, Result<StoredObject>>;
+
+/// A contiguous range of bytes within a binary object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl ByteRange {
+    /// Resolve a http range header value against an object size. Unsupported or malformed
+    /// ranges are ignored, and ranges that start past the end of the object are rejected.
+    pub fn parse(header: &str, size: u64) -> Result<Option<Self>> {
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return Ok(None);
+        };
+        // Only a single range is supported
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Ok(None);
+        };
+        let (start, end) = (start.trim(), end.trim());
+        let (offset, last) = if start.is_empty() {
+            // Suffix range: the last n bytes
+            match end.parse::<u64>() {
+                Ok(0) => return Err(Error::RangeNotSatisfiable { size }),
+                Ok(n) => (size.saturating_sub(n), size.saturating_sub(1)),
+                Err(_) => return Ok(None),
+            }
+        } else {
+            let Ok(offset) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let last = match end {
+                "" => size.saturating_sub(1),
+                _ => match end.parse::<u64>() {
+                    Ok(n) if n >= offset => n.min(size.saturating_sub(1)),
+                    _ => return Ok(None),
+                },
+            };
+            (offset, last)
+        };
+        if offset >= size {
+            return Err(Error::RangeNotSatisfiable { size });
+        }
+        let length = last - offset + 1;
+        Ok(Some(Self { offset, length }))
+    }
+
+    /// The zero-based position of the last byte in the range.
+    pub fn last(&self) -> u64 {
+        self.offset + self.length - 1
+    }
+}
+
 /// Read, write, and delete binary objects.
 #[async_trait::async_trait]
 pub trait Storage: Send + Sync {
     /// Read bytes
     async fn read(&self, storage_id: &StorageId) -> Result<Bytes>;
 
-    /// Write bytes
-    async fn write(&self, bytes: Bytes) -> Result<StorageId>;
+    /// Read a stream of bytes, optionally limited to a byte range
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>>;
+
+    /// Read the bytes of several objects in order, as one stream, e.g. to assemble chunks
+    fn read_parts<'a>(&'a self, storage_ids: &'a [StorageId]) -> ByteStream<'a> {
+        stream::iter(storage_ids)
+            .then(move |storage_id| self.read_stream(storage_id, None))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Write a stream of bytes as a new object
+    async fn write(&self, stream: ByteStream<'_>) -> Result<StorageId> {
+        let storage_id = StorageId(Uuid::new_v4());
+        self.put(&storage_id, stream).await?;
+        Ok(storage_id)
+    }
+
+    /// Write a stream of bytes under a given id, replacing any existing object
+    async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()>;
 
     /// Delete bytes
     async fn delete(&self, storage_id: &StorageId) -> Result<()>;
+
+    /// List all stored objects
+    async fn list(&self) -> Result<ObjectStream>;
+
+    /// Read object metadata, by default counting the bytes of the whole object
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        let stream = self.read_stream(storage_id, None).await?;
+        let size = stream
+            .try_fold(0, |size, chunk| future::ok(size + chunk.len() as u64))
+            .await?;
+        Ok(StoredObject {
+            storage_id: storage_id.clone(),
+            size,
+            modified_at: Utc::now(),
+        })
+    }
+
+    /// Create a short-lived url for uploading a new object directly to storage
+    async fn presign_write(&self, _expires_in: Duration) -> Result<(StorageId, String)> {
+        Err(Error::invalid_args(
+            "storage does not support presigned urls",
+        ))
+    }
+
+    /// Create a short-lived url for downloading an object directly from storage
+    async fn presign_read(&self, _storage_id: &StorageId, _expires_in: Duration) -> Result<String> {
+        Err(Error::invalid_args(
+            "storage does not support presigned urls",
+        ))
+    }
+
+    /// Persist any state held only in memory, e.g. before shutdown
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_byte_ranges() {
+        let range = |offset, length| Some(ByteRange { offset, length });
+        assert_eq!(ByteRange::parse("bytes=0-99", 1000).unwrap(), range(0, 100));
+        assert_eq!(
+            ByteRange::parse("bytes=500-", 1000).unwrap(),
+            range(500, 500)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-100", 1000).unwrap(),
+            range(900, 100)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=900-5000", 1000).unwrap(),
+            range(900, 100)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-5000", 1000).unwrap(),
+            range(0, 1000)
+        );
+    }
+
+    #[test]
+    fn parse_ignored_byte_ranges() {
+        assert!(ByteRange::parse("items=0-99", 1000).unwrap().is_none());
+        assert!(ByteRange::parse("bytes=0-9,20-29", 1000).unwrap().is_none());
+        assert!(ByteRange::parse("bytes=99-0", 1000).unwrap().is_none());
+        assert!(ByteRange::parse("bytes=abc", 1000).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_unsatisfiable_byte_ranges() {
+        assert!(ByteRange::parse("bytes=1000-", 1000).is_err());
+        assert!(ByteRange::parse("bytes=-0", 1000).is_err());
+        assert!(ByteRange::parse("bytes=0-", 0).is_err());
+    }
 }
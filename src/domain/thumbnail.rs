@@ -0,0 +1,105 @@
+use super::StorageId;
+use crate::{Error, Result};
+use bytes::Bytes;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// The longest edge of rendered thumbnails, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+// Image types that thumbnails can be rendered from.
+const IMAGE_TYPES: &[&str] = &["image/gif", "image/jpeg", "image/png", "image/webp"];
+
+// Bounds on decoded images, to guard against decompression bombs.
+const MAX_DIMENSION: u32 = 16_384;
+const MAX_ALLOC: u64 = 512 * 1024 * 1024;
+
+// Quality of rendered JPEG thumbnails.
+const JPEG_QUALITY: u8 = 80;
+
+/// A stored thumbnail, rendered from the contents of an image file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub source_id: StorageId,
+    pub storage_id: StorageId,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+}
+
+/// Whether thumbnails can be rendered for files of a content type.
+pub fn has_thumbnail(content_type: &str) -> bool {
+    IMAGE_TYPES.contains(&content_type)
+}
+
+/// Render an image scaled down to fit within a square, keeping its aspect ratio. Opaque images
+/// are encoded as JPEG, and images with transparency as PNG. Returns the bytes and content type.
+pub fn render_thumbnail(contents: &[u8], size: u32) -> Result<(Bytes, &'static str)> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+
+    let mut reader = ImageReader::new(Cursor::new(contents)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode().map_err(invalid_image)?;
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    let mut buf = Vec::new();
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .map_err(invalid_image)?;
+        return Ok((buf.into(), "image/png"));
+    }
+    let rgb = DynamicImage::from(image.to_rgb8());
+    JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY)
+        .encode_image(&rgb)
+        .map_err(invalid_image)?;
+    Ok((buf.into(), "image/jpeg"))
+}
+
+/// Images that can't be decoded or encoded are rejected.
+fn invalid_image(err: image::ImageError) -> Error {
+    Error::invalid_args(format!("unable to render thumbnail: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn encode(image: DynamicImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn render_thumbnails() {
+        // Opaque images are scaled to fit, and encoded as JPEG
+        let photo = RgbImage::from_pixel(1024, 512, Rgb([200, 40, 40]));
+        let (bytes, content_type) = render_thumbnail(&encode(photo.into()), 256).unwrap();
+        assert_eq!(content_type, "image/jpeg");
+        let thumbnail = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(thumbnail.dimensions(), (256, 128));
+
+        // Transparent images are encoded as PNG, and never scaled up
+        let icon = RgbaImage::from_pixel(32, 64, Rgba([0, 0, 0, 0]));
+        let (bytes, content_type) = render_thumbnail(&encode(icon.into()), 256).unwrap();
+        assert_eq!(content_type, "image/png");
+        let thumbnail = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(thumbnail.dimensions(), (32, 64));
+
+        // Contents that aren't images are rejected
+        assert!(render_thumbnail(b"not an image", 256).is_err());
+        assert!(has_thumbnail("image/png"));
+        assert!(!has_thumbnail("image/svg+xml"));
+    }
+}
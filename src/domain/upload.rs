@@ -0,0 +1,40 @@
+use super::StoryId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// The newtype resumable upload id.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, ToSchema)]
+pub struct UploadId(pub Uuid);
+
+// Display the inner uuid.
+impl std::fmt::Display for UploadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A resumable upload, which becomes a story file once every byte has arrived.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Upload {
+    pub id: UploadId,
+    pub story_id: StoryId,
+    pub name: String,
+    /// Content type claimed by the client, refined from the contents on completion
+    pub content_type: String,
+    /// The total size of the file, in bytes
+    pub length: i64,
+    /// The number of bytes received so far
+    pub offset: i64,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Upload {
+    /// Whether every byte of the file has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.length
+    }
+}
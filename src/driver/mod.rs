@@ -1,2 +1,5 @@
+/// Drivers for malware scanning.
+pub mod scanner;
+
 /// Drivers for binary object storage.
 pub mod storage;
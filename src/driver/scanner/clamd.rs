@@ -0,0 +1,153 @@
+use crate::{
+    domain::{ByteStream, ScanVerdict, Scanner},
+    Error, Result,
+};
+use futures_util::TryStreamExt;
+use std::{future::Future, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// The max size of each chunk sent to clamd.
+const CHUNK_LEN: usize = 64 * 1024;
+
+// How long to wait for clamd to connect, accept a write, or reply.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Scanner backed by a ClamAV daemon, which is sent contents with the INSTREAM command.
+pub struct ClamdScanner {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    /// Create a scanner for a clamd tcp address, e.g. `localhost:3310`.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            timeout: TIMEOUT,
+        }
+    }
+
+    /// Set how long to wait for each call to clamd.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fail a call to clamd that takes longer than the timeout.
+    async fn timed<T>(&self, call: impl Future<Output = std::io::Result<T>>) -> Result<T> {
+        match tokio::time::timeout(self.timeout, call).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(Error::internal(format!("clamd timed out: {}", self.addr))),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Scanner for ClamdScanner {
+    /// Stream contents to clamd as length prefixed chunks, then read its verdict
+    async fn scan(&self, mut stream: ByteStream<'_>) -> Result<ScanVerdict> {
+        let mut conn = self.timed(TcpStream::connect(&self.addr)).await?;
+        self.timed(conn.write_all(b"zINSTREAM\0")).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            for part in chunk.chunks(CHUNK_LEN) {
+                self.timed(conn.write_all(&(part.len() as u32).to_be_bytes()))
+                    .await?;
+                self.timed(conn.write_all(part)).await?;
+            }
+        }
+        // A zero length chunk ends the stream
+        self.timed(conn.write_all(&[0; 4])).await?;
+        let mut reply = Vec::new();
+        self.timed(conn.read_to_end(&mut reply)).await?;
+        parse_reply(&reply)
+    }
+}
+
+/// Parse a clamd reply, e.g. `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_reply(reply: &[u8]) -> Result<ScanVerdict> {
+    let reply = String::from_utf8_lossy(reply);
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        return Ok(ScanVerdict::Clean);
+    }
+    match result.strip_suffix(" FOUND") {
+        Some(signature) => Ok(ScanVerdict::Infected(signature.trim().to_string())),
+        None => Err(Error::internal(format!("clamd scan failed: {reply}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::scanner::fake::{FakeScanner, EICAR};
+    use bytes::Bytes;
+    use futures_util::{stream, StreamExt};
+    use tokio::net::TcpListener;
+
+    /// Accept a single INSTREAM scan, detecting the test file like clamd would.
+    async fn serve_once(listener: TcpListener) {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut command = [0u8; 10];
+        conn.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+        let mut contents = Vec::new();
+        loop {
+            let len = conn.read_u32().await.unwrap() as usize;
+            if len == 0 {
+                break;
+            }
+            let mut chunk = vec![0u8; len];
+            conn.read_exact(&mut chunk).await.unwrap();
+            contents.extend(chunk);
+        }
+        let reply: &[u8] = if FakeScanner::detect(&contents) {
+            b"stream: Eicar-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        conn.write_all(reply).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clamd_scanner() {
+        for (contents, expected) in [
+            (Bytes::from("The quick brown fox"), ScanVerdict::Clean),
+            (
+                Bytes::from([b"prefix ", EICAR].concat()),
+                ScanVerdict::Infected("Eicar-Signature".into()),
+            ),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let scanner = ClamdScanner::new(listener.local_addr().unwrap().to_string());
+            let server = tokio::spawn(serve_once(listener));
+            let chunks = contents.chunks(5).map(|c| Ok(Bytes::copy_from_slice(c)));
+            let verdict = scanner.scan(stream::iter(chunks).boxed()).await.unwrap();
+            assert_eq!(verdict, expected);
+            server.await.unwrap();
+        }
+
+        // Errors reported by clamd fail the scan
+        assert!(parse_reply(b"INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clamd_scanner_timeout() {
+        // A daemon that accepts contents but never replies fails the scan
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let scanner = ClamdScanner::new(listener.local_addr().unwrap().to_string())
+            .with_timeout(Duration::from_millis(100));
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut contents = Vec::new();
+            conn.read_to_end(&mut contents).await
+        });
+        let chunks = stream::iter([Ok(Bytes::from("The quick brown fox"))]).boxed();
+        let result = scanner.scan(chunks).await;
+        assert!(matches!(result, Err(Error::Internal { .. })));
+        server.abort();
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{
+    domain::{ByteStream, ScanVerdict, Scanner},
+    Result,
+};
+use futures_util::TryStreamExt;
+
+/// The standard antivirus test file, which scanners detect as malware.
+pub const EICAR: &[u8] = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+// The signature reported for the test file.
+const SIGNATURE: &str = "Eicar-Test-Signature";
+
+/// Scanner that only detects the EICAR test file, for tests and local development.
+#[derive(Default)]
+pub struct FakeScanner;
+
+impl FakeScanner {
+    /// Create a new fake scanner.
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Whether bytes contain the test file.
+    pub fn detect(bytes: &[u8]) -> bool {
+        bytes.windows(EICAR.len()).any(|window| window == EICAR)
+    }
+}
+
+#[async_trait::async_trait]
+impl Scanner for FakeScanner {
+    /// Search contents for the test file, including across chunk boundaries
+    async fn scan(&self, mut stream: ByteStream<'_>) -> Result<ScanVerdict> {
+        let mut window = Vec::new();
+        while let Some(chunk) = stream.try_next().await? {
+            window.extend_from_slice(&chunk);
+            if Self::detect(&window) {
+                return Ok(ScanVerdict::Infected(SIGNATURE.to_string()));
+            }
+            let keep = window.len().min(EICAR.len() - 1);
+            window.drain(..window.len() - keep);
+        }
+        Ok(ScanVerdict::Clean)
+    }
+}
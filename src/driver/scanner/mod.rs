@@ -0,0 +1,2 @@
+pub mod clamd;
+pub mod fake;
@@ -0,0 +1,556 @@
+use super::fs::FileStorage;
+use crate::{
+    domain::{ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
+    Error, Result,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use metrics::counter;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{mpsc, OnceCell};
+use uuid::Uuid;
+
+// Objects up to this fraction of the memory size are kept in memory, larger ones on disk.
+const MEMORY_OBJECT_FRACTION: u64 = 8;
+
+// The number of chunks buffered for the disk tier while an object is read into it.
+const TEE_BUFFER: usize = 16;
+
+// Metric names
+const HITS: &str = "storage_cache_hits_total";
+const MISSES: &str = "storage_cache_misses_total";
+
+/// Storage decorator that caches objects read from another storage instance, evicting the
+/// least recently used objects once a size limit is reached. Small objects are kept in memory,
+/// and larger ones in an optional disk tier. Objects are cached when read in full, while byte
+/// ranges are read from the cache when possible, and otherwise from the inner storage.
+pub struct CachedStorage {
+    inner: Box<dyn Storage>,
+    memory: Mutex<Lru<Bytes>>,
+    max_memory_object: u64,
+    disk: Option<Arc<DiskCache>>,
+    filling: Arc<Filling>,
+}
+
+/// Objects cached in local files, indexed on first use from what is already on disk.
+struct DiskCache {
+    storage: FileStorage,
+    capacity: u64,
+    index: OnceCell<Mutex<Lru<()>>>,
+}
+
+/// Objects being read into the cache, flagged when invalidated mid-read.
+#[derive(Default)]
+struct Filling(Mutex<HashMap<Uuid, bool>>);
+
+/// Chunks copied to the disk tier, where `None` marks the end of the object. A copy that ends
+/// without it was abandoned by the reader, and isn't cached.
+type CopiedChunk = Result<Option<Bytes>>;
+
+impl CachedStorage {
+    /// Create a caching wrapper around another storage instance, with a memory size in bytes.
+    pub fn new(inner: Box<dyn Storage>, memory_size: u64) -> Self {
+        Self {
+            inner,
+            memory: Mutex::new(Lru::new(memory_size)),
+            max_memory_object: memory_size / MEMORY_OBJECT_FRACTION,
+            disk: None,
+            filling: Arc::default(),
+        }
+    }
+
+    /// Cache objects too large for memory in local files, up to a size in bytes.
+    pub fn with_disk(mut self, storage: FileStorage, size: u64) -> Self {
+        self.disk = Some(Arc::new(DiskCache {
+            storage,
+            capacity: size,
+            index: OnceCell::new(),
+        }));
+        self
+    }
+
+    /// Look up an object in memory.
+    fn memory_get(&self, key: &Uuid) -> Option<Bytes> {
+        self.memory.lock().ok()?.get(key)
+    }
+
+    /// Read a whole object from the inner storage into the cache, once `start` marked it as
+    /// filling. Small objects are buffered into memory, while larger ones are copied to disk as
+    /// they are streamed to the reader.
+    async fn fill(&self, storage_id: &StorageId) -> Result<ByteStream<'static>> {
+        let StorageId(key) = storage_id;
+        let (head, rest) = match self.read_head(storage_id).await {
+            Ok(read) => read,
+            Err(err) => {
+                self.filling.end(key);
+                return Err(err);
+            }
+        };
+        let Some(rest) = rest else {
+            if self.filling.end(key) {
+                if let Ok(mut memory) = self.memory.lock() {
+                    memory.insert(*key, head.clone(), head.len() as u64);
+                }
+            }
+            return Ok(stream::once(future::ok(head)).boxed());
+        };
+        let stream = stream::once(future::ok(head)).chain(rest).boxed();
+        match &self.disk {
+            Some(disk) => Ok(disk.fill(storage_id, stream, Arc::clone(&self.filling))),
+            None => {
+                self.filling.end(key);
+                Ok(stream)
+            }
+        }
+    }
+
+    /// Buffer the head of an object, up to the size kept in memory. The rest of the stream is
+    /// returned unless the object fits.
+    async fn read_head(
+        &self,
+        storage_id: &StorageId,
+    ) -> Result<(Bytes, Option<ByteStream<'static>>)> {
+        let mut stream = self.inner.read_stream(storage_id, None).await?;
+        let mut buf = BytesMut::new();
+        while buf.len() as u64 <= self.max_memory_object {
+            match stream.next().await {
+                Some(chunk) => buf.extend_from_slice(&chunk?),
+                None => return Ok((buf.freeze(), None)),
+            }
+        }
+        Ok((buf.freeze(), Some(stream)))
+    }
+
+    /// Drop an object from every tier, including reads into the cache still in progress.
+    async fn invalidate(&self, storage_id: &StorageId) -> Result<()> {
+        let StorageId(key) = storage_id;
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.remove(key);
+        }
+        self.filling.invalidate(key);
+        if let Some(disk) = &self.disk {
+            if disk.index().await?.lock().is_ok_and(|mut i| i.remove(key)) {
+                disk.storage.delete(storage_id).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filling {
+    /// Mark an object as being read into the cache. Returns false if it already is.
+    fn start(&self, key: Uuid) -> bool {
+        match self.0.lock() {
+            Ok(mut filling) if !filling.contains_key(&key) => {
+                filling.insert(key, false);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Finish reading an object into the cache. Returns false if it was invalidated meanwhile.
+    fn end(&self, key: &Uuid) -> bool {
+        match self.0.lock() {
+            Ok(mut filling) => filling.remove(key) == Some(false),
+            Err(_) => false,
+        }
+    }
+
+    /// Flag an object being read into the cache as invalidated.
+    fn invalidate(&self, key: &Uuid) {
+        if let Ok(mut filling) = self.0.lock() {
+            if let Some(invalidated) = filling.get_mut(key) {
+                *invalidated = true;
+            }
+        }
+    }
+}
+
+impl DiskCache {
+    /// Copy a stream to a cache file in the background as it is read, returning the stream.
+    /// The file is only cached once the reader has read the whole object.
+    fn fill(
+        self: &Arc<Self>,
+        storage_id: &StorageId,
+        stream: ByteStream<'static>,
+        filling: Arc<Filling>,
+    ) -> ByteStream<'static> {
+        let (tx, rx) = mpsc::channel::<CopiedChunk>(TEE_BUFFER);
+        let cache = Arc::clone(self);
+        let storage_id = storage_id.clone();
+        tokio::spawn(async move {
+            let StorageId(key) = storage_id;
+            let mut size = 0;
+            let copy = stream::unfold(rx, |mut rx| async move {
+                let chunk = match rx.recv().await {
+                    Some(Ok(Some(chunk))) => Ok(chunk),
+                    Some(Ok(None)) => return None,
+                    Some(Err(err)) => Err(err),
+                    None => Err(Error::internal("read abandoned before the end")),
+                };
+                Some((chunk, rx))
+            })
+            .inspect_ok(|chunk| size += chunk.len() as u64);
+            let result = cache.storage.put(&storage_id, copy.boxed()).await;
+            let cached = match result {
+                Ok(()) if filling.end(&key) => cache.insert(key, size).await,
+                Ok(()) => cache.remove(&key).await,
+                Err(err) => {
+                    filling.end(&key);
+                    tracing::debug!("not caching {}: {}", key, err);
+                    Ok(())
+                }
+            };
+            if let Err(err) = cached {
+                tracing::warn!("unable to cache {}: {}", key, err);
+            }
+        });
+
+        // Stop copying if writing the cache file fails, but keep serving the reader
+        let state = (stream, Some(tx));
+        stream::unfold(state, |(mut stream, tx)| async move {
+            let chunk = stream.next().await;
+            let Some(tx) = tx else {
+                return chunk.map(|chunk| (chunk, (stream, None)));
+            };
+            match chunk {
+                Some(Ok(bytes)) => {
+                    let copied = tx.send(Ok(Some(bytes.clone()))).await.is_ok();
+                    Some((Ok(bytes), (stream, copied.then_some(tx))))
+                }
+                Some(Err(err)) => {
+                    let _ = tx.send(Err(Error::internal(err.to_string()))).await;
+                    Some((Err(err), (stream, None)))
+                }
+                None => {
+                    let _ = tx.send(Ok(None)).await;
+                    None
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// The index of cached files, loaded from the cache dir on first use.
+    async fn index(&self) -> Result<&Mutex<Lru<()>>> {
+        self.index
+            .get_or_try_init(|| async {
+                let mut index = Lru::new(self.capacity);
+                let mut evicted = Vec::new();
+                let mut objects = self.storage.list().await?;
+                while let Some(object) = objects.try_next().await? {
+                    let StorageId(key) = object.storage_id;
+                    evicted.extend(index.insert(key, (), object.size));
+                }
+                self.delete(evicted).await;
+                Ok(Mutex::new(index))
+            })
+            .await
+    }
+
+    /// Whether an object is cached on disk, marking it as recently used.
+    async fn contains(&self, key: &Uuid) -> Result<bool> {
+        Ok(self
+            .index()
+            .await?
+            .lock()
+            .is_ok_and(|mut i| i.get(key).is_some()))
+    }
+
+    /// Record a cached file, deleting files evicted to make room.
+    async fn insert(&self, key: Uuid, size: u64) -> Result<()> {
+        let evicted = match self.index().await?.lock() {
+            Ok(mut index) => index.insert(key, (), size),
+            Err(_) => vec![key],
+        };
+        self.delete(evicted).await;
+        Ok(())
+    }
+
+    /// Forget a cached file, e.g. when it can no longer be read.
+    async fn remove(&self, key: &Uuid) -> Result<()> {
+        if let Ok(mut index) = self.index().await?.lock() {
+            index.remove(key);
+        }
+        self.delete(vec![*key]).await;
+        Ok(())
+    }
+
+    /// Delete cached files, logging failures.
+    async fn delete(&self, keys: Vec<Uuid>) {
+        for key in keys {
+            if let Err(err) = self.storage.delete(&StorageId(key)).await {
+                tracing::warn!("unable to delete {} from storage cache: {}", key, err);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for CachedStorage {
+    /// Read object, from the cache if possible
+    async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+        if let Some(bytes) = self.memory_get(&storage_id.0) {
+            counter!(HITS, "tier" => "memory").increment(1);
+            return Ok(bytes);
+        }
+        let chunks: Vec<Bytes> = self
+            .read_stream(storage_id, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(chunks.concat().into())
+    }
+
+    /// Stream object from the cache, reading it into the cache first on a miss
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let StorageId(key) = storage_id;
+        if let Some(bytes) = self.memory_get(key) {
+            counter!(HITS, "tier" => "memory").increment(1);
+            return Ok(stream::once(future::ok(slice(bytes, range))).boxed());
+        }
+        if let Some(disk) = &self.disk {
+            if disk.contains(key).await? {
+                match disk.storage.read_stream(storage_id, range).await {
+                    Ok(stream) => {
+                        counter!(HITS, "tier" => "disk").increment(1);
+                        return Ok(stream);
+                    }
+                    Err(err) => {
+                        tracing::warn!("unable to read {} from storage cache: {}", key, err);
+                        disk.remove(key).await?;
+                    }
+                }
+            }
+        }
+        counter!(MISSES).increment(1);
+
+        // Only whole objects are cached, and only by one reader at a time
+        if range.is_some() || !self.filling.start(*key) {
+            return self.inner.read_stream(storage_id, range).await;
+        }
+        self.fill(storage_id).await
+    }
+
+    /// Write object, dropping any cached copy it replaces
+    async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()> {
+        self.inner.put(storage_id, stream).await?;
+        self.invalidate(storage_id).await
+    }
+
+    /// Delete object, along with any cached copy
+    async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+        self.inner.delete(storage_id).await?;
+        self.invalidate(storage_id).await
+    }
+
+    /// List objects in the inner storage
+    async fn list(&self) -> Result<ObjectStream> {
+        self.inner.list().await
+    }
+
+    /// Read object metadata from the inner storage
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        self.inner.stat(storage_id).await
+    }
+
+    /// Presign uploads with the inner storage
+    async fn presign_write(&self, expires_in: Duration) -> Result<(StorageId, String)> {
+        self.inner.presign_write(expires_in).await
+    }
+
+    /// Presign downloads with the inner storage
+    async fn presign_read(&self, storage_id: &StorageId, expires_in: Duration) -> Result<String> {
+        self.inner.presign_read(storage_id, expires_in).await
+    }
+
+    /// Flush the inner storage
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// Slice cached bytes to a range if given.
+fn slice(bytes: Bytes, range: Option<ByteRange>) -> Bytes {
+    let Some(ByteRange { offset, length }) = range else {
+        return bytes;
+    };
+    let len = bytes.len() as u64;
+    let start = offset.min(len);
+    let end = offset.saturating_add(length).min(len);
+    bytes.slice(start as usize..end as usize)
+}
+
+/// Entries keyed by storage id, evicting the least recently used once their total size
+/// exceeds a capacity.
+struct Lru<V> {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<Uuid, LruEntry<V>>,
+    order: BTreeMap<u64, Uuid>,
+}
+
+/// A cached value, with its size and when it was last used.
+struct LruEntry<V> {
+    value: V,
+    size: u64,
+    tick: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Look up a value, marking it as recently used.
+    fn get(&mut self, key: &Uuid) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(entry.value.clone())
+    }
+
+    /// Insert a value, returning the keys evicted to make room. A value larger than the
+    /// capacity is evicted straight away.
+    fn insert(&mut self, key: Uuid, value: V, size: u64) -> Vec<Uuid> {
+        self.remove(&key);
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.insert(key, LruEntry { value, size, tick });
+        self.order.insert(tick, key);
+        self.size += size;
+        let mut evicted = Vec::new();
+        while self.size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    /// Remove a value. Returns whether it was present.
+    fn remove(&mut self, key: &Uuid) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.order.remove(&entry.tick);
+        self.size -= entry.size;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::storage::mem::MemoryStorage;
+
+    #[test]
+    fn test_lru() {
+        let keys: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut lru = Lru::new(10);
+        assert!(lru.insert(keys[0], 'a', 4).is_empty());
+        assert!(lru.insert(keys[1], 'b', 4).is_empty());
+
+        // Using the first entry makes the second the oldest
+        assert_eq!(lru.get(&keys[0]), Some('a'));
+        assert_eq!(lru.insert(keys[2], 'c', 4), vec![keys[1]]);
+        assert_eq!(lru.get(&keys[1]), None);
+
+        // Entries larger than the capacity are evicted straight away
+        assert_eq!(
+            lru.insert(keys[3], 'd', 11),
+            vec![keys[0], keys[2], keys[3]]
+        );
+        assert_eq!(lru.size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cached_storage() {
+        let dir = std::env::temp_dir().join(format!("cache-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let disk = FileStorage::new(dir.to_string_lossy().to_string());
+        let storage = CachedStorage::new(Box::new(MemoryStorage::new()), 800).with_disk(disk, 500);
+
+        // Small objects are cached in memory, and larger ones on disk
+        let small = Bytes::from("The quick brown fox jumped over the lazy dog");
+        let large = Bytes::from("lazy dog ".repeat(40));
+        let small_key = storage
+            .write(stream::iter([Ok(small.clone())]).boxed())
+            .await;
+        let small_key = small_key.unwrap();
+        let large_key = storage
+            .write(stream::iter([Ok(large.clone())]).boxed())
+            .await;
+        let large_key = large_key.unwrap();
+        assert_eq!(storage.read(&small_key).await.unwrap(), small);
+        assert_eq!(storage.read(&large_key).await.unwrap(), large);
+        assert!(storage.memory_get(&small_key.0).is_some());
+        assert!(storage.memory_get(&large_key.0).is_none());
+        let cache = storage.disk.as_ref().unwrap();
+        assert!(cached_on_disk(cache, &large_key).await);
+        let disk = &cache.storage;
+        assert!(disk.read(&large_key).await.is_ok());
+
+        // Large objects aren't cached when the reader stops before the end
+        let partial = Bytes::from("quick fox ".repeat(40));
+        let partial_key = storage.write(stream::iter([Ok(partial)]).boxed()).await;
+        let partial_key = partial_key.unwrap();
+        let mut stream = storage.read_stream(&partial_key, None).await.unwrap();
+        assert!(stream.next().await.is_some());
+        drop(stream);
+        assert!(!cached_on_disk(cache, &partial_key).await);
+        assert!(disk.read(&partial_key).await.is_err());
+
+        // Cached objects are read even once gone from the inner storage
+        storage.inner.delete(&small_key).await.unwrap();
+        storage.inner.delete(&large_key).await.unwrap();
+        assert_eq!(storage.read(&small_key).await.unwrap(), small);
+        let range = ByteRange::parse("bytes=5-7", large.len() as u64).unwrap();
+        let stream = storage.read_stream(&large_key, range).await.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"dog");
+
+        // Deletes invalidate the cache
+        storage.delete(&small_key).await.unwrap();
+        storage.delete(&large_key).await.unwrap();
+        assert!(storage.read(&small_key).await.is_err());
+        assert!(storage.read(&large_key).await.is_err());
+        assert!(disk.read(&large_key).await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    /// Whether an object is cached on disk, once the background copy has had time to finish.
+    async fn cached_on_disk(cache: &DiskCache, storage_id: &StorageId) -> bool {
+        for _ in 0..50 {
+            if cache.contains(&storage_id.0).await.unwrap() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+}
@@ -0,0 +1,356 @@
+use super::slice;
+use crate::{
+    domain::{peek, ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
+    Error, Result,
+};
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::{io, str::FromStr, time::Duration};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+// Marks compressed objects, and is followed by a byte recording the encoding.
+const MAGIC: &[u8; 4] = b"SQZ2";
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+// The uncompressed length follows the compressed contents, as it is only known once they are
+// written. Objects written before it was recorded are marked with the first version.
+const LEGACY_MAGIC: &[u8; 4] = b"SQZ1";
+const FOOTER_LEN: usize = 8;
+
+/// Compression encodings for stored objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The header byte recording this encoding.
+    fn tag(self) -> u8 {
+        match self {
+            Encoding::Gzip => 1,
+            Encoding::Zstd => 2,
+        }
+    }
+
+    /// Read the encoding from an object header, or None for uncompressed objects.
+    fn from_header(header: &[u8]) -> Option<Self> {
+        if header.len() < HEADER_LEN
+            || !(header.starts_with(MAGIC) || header.starts_with(LEGACY_MAGIC))
+        {
+            return None;
+        }
+        match header[MAGIC.len()] {
+            1 => Some(Encoding::Gzip),
+            2 => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(Error::invalid_args(format!("unknown encoding: {s}"))),
+        }
+    }
+}
+
+/// Storage decorator that compresses contents on write, and decompresses them on read. The
+/// encoding is recorded in an object header, so uncompressed objects are read back as is.
+/// Compressed objects can't be seeked, so byte ranges are read by skipping decoded bytes.
+pub struct CompressedStorage {
+    inner: Box<dyn Storage>,
+    encoding: Encoding,
+}
+
+impl CompressedStorage {
+    /// Create a compressing wrapper around another storage instance.
+    pub fn new(inner: Box<dyn Storage>, encoding: Encoding) -> Self {
+        Self { inner, encoding }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for CompressedStorage {
+    /// Read and decompress object
+    async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+        let chunks: Vec<Bytes> = self
+            .read_stream(storage_id, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(chunks.concat().into())
+    }
+
+    /// Stream and decompress object, skipping to a range if given
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let Some(range) = range else {
+            let mut stream = self.inner.read_stream(storage_id, None).await?;
+            let mut buf = peek(&mut stream, HEADER_LEN).await?;
+            let encoding = Encoding::from_header(&buf);
+            let sized = encoding.is_some() && buf.starts_with(MAGIC);
+            if encoding.is_some() {
+                buf.advance(HEADER_LEN);
+            }
+            let mut stream = stream::once(future::ok(buf.freeze())).chain(stream).boxed();
+            if sized {
+                stream = strip_footer(stream);
+            }
+            return Ok(match encoding {
+                Some(encoding) => decode(stream, encoding),
+                None => stream,
+            });
+        };
+
+        // Uncompressed objects can still be read by range from the inner storage
+        let header_range = ByteRange {
+            offset: 0,
+            length: HEADER_LEN as u64,
+        };
+        let header: Vec<Bytes> = self
+            .inner
+            .read_stream(storage_id, Some(header_range))
+            .await?
+            .try_collect()
+            .await?;
+        if Encoding::from_header(&header.concat()).is_none() {
+            return self.inner.read_stream(storage_id, Some(range)).await;
+        }
+        let stream = self.read_stream(storage_id, None).await?;
+        Ok(slice(stream, range.offset, range.length))
+    }
+
+    /// Compress and write object
+    async fn put(&self, storage_id: &StorageId, mut stream: ByteStream<'_>) -> Result<()> {
+        let head = peek(&mut stream, 1).await?;
+        if head.is_empty() {
+            return Err(Error::invalid_args("empty file"));
+        }
+        let stream = stream::once(future::ok(head.freeze()))
+            .chain(stream)
+            .boxed();
+        let header = Bytes::from_iter(MAGIC.iter().copied().chain([self.encoding.tag()]));
+
+        // Count the bytes read, to record once the stream has been compressed
+        let len = Arc::new(AtomicU64::new(0));
+        let counted = len.clone();
+        let stream = stream
+            .inspect_ok(move |chunk| {
+                counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            })
+            .boxed();
+        let footer = stream::once(async move {
+            Ok(Bytes::copy_from_slice(
+                &len.load(Ordering::Relaxed).to_be_bytes(),
+            ))
+        });
+        let compressed = stream::once(future::ok(header))
+            .chain(encode(stream, self.encoding))
+            .chain(footer);
+        self.inner.put(storage_id, compressed.boxed()).await
+    }
+
+    /// Delete object
+    async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+        self.inner.delete(storage_id).await
+    }
+
+    /// List objects, with sizes as stored
+    async fn list(&self) -> Result<ObjectStream> {
+        self.inner.list().await
+    }
+
+    /// Read object metadata, with the uncompressed size recorded after the contents
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        let mut object = self.inner.stat(storage_id).await?;
+        if object.size < (HEADER_LEN + FOOTER_LEN) as u64 {
+            return Ok(object);
+        }
+        let header_range = ByteRange {
+            offset: 0,
+            length: HEADER_LEN as u64,
+        };
+        let header: Vec<Bytes> = self
+            .inner
+            .read_stream(storage_id, Some(header_range))
+            .await?
+            .try_collect()
+            .await?;
+        let header = header.concat();
+        if Encoding::from_header(&header).is_none() {
+            return Ok(object);
+        }
+
+        // Objects without a recorded size are decompressed to count it
+        if !header.starts_with(MAGIC) {
+            let stream = self.read_stream(storage_id, None).await?;
+            object.size = stream
+                .try_fold(0, |size, chunk| future::ok(size + chunk.len() as u64))
+                .await?;
+            return Ok(object);
+        }
+        let footer_range = ByteRange {
+            offset: object.size - FOOTER_LEN as u64,
+            length: FOOTER_LEN as u64,
+        };
+        let footer: Vec<Bytes> = self
+            .inner
+            .read_stream(storage_id, Some(footer_range))
+            .await?
+            .try_collect()
+            .await?;
+        let footer: [u8; FOOTER_LEN] = footer
+            .concat()
+            .try_into()
+            .map_err(|_| Error::internal("truncated compressed object"))?;
+        object.size = u64::from_be_bytes(footer);
+        Ok(object)
+    }
+
+    /// Presigned urls would bypass compression, so they aren't supported
+    async fn presign_write(&self, _expires_in: Duration) -> Result<(StorageId, String)> {
+        Err(Error::invalid_args(
+            "presigned urls are not supported with storage compression",
+        ))
+    }
+
+    /// Presigned urls would serve compressed contents, so they aren't supported
+    async fn presign_read(&self, _storage_id: &StorageId, _expires_in: Duration) -> Result<String> {
+        Err(Error::invalid_args(
+            "presigned urls are not supported with storage compression",
+        ))
+    }
+
+    /// Flush the inner storage
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// Hold back the length recorded after compressed contents, so only the contents are decoded.
+fn strip_footer(stream: ByteStream<'static>) -> ByteStream<'static> {
+    let state = Some((stream, BytesMut::new()));
+    stream::unfold(state, |state| async move {
+        let (mut stream, mut buf) = state?;
+        loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                Some(Err(err)) => return Some((Err(err), None)),
+                None if buf.len() == FOOTER_LEN => return None,
+                None => {
+                    let err = Error::internal("truncated compressed object");
+                    return Some((Err(err), None));
+                }
+            }
+            if buf.len() > FOOTER_LEN {
+                let contents = buf.split_to(buf.len() - FOOTER_LEN).freeze();
+                return Some((Ok(contents), Some((stream, buf))));
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Compress a stream of bytes.
+fn encode(stream: ByteStream<'_>, encoding: Encoding) -> ByteStream<'_> {
+    let reader = StreamReader::new(stream.map_err(io::Error::other));
+    match encoding {
+        Encoding::Gzip => ReaderStream::new(GzipEncoder::new(reader))
+            .map_err(Error::from)
+            .boxed(),
+        Encoding::Zstd => ReaderStream::new(ZstdEncoder::new(reader))
+            .map_err(Error::from)
+            .boxed(),
+    }
+}
+
+/// Decompress a stream of bytes.
+fn decode(stream: ByteStream<'static>, encoding: Encoding) -> ByteStream<'static> {
+    let reader = StreamReader::new(stream.map_err(io::Error::other));
+    match encoding {
+        Encoding::Gzip => ReaderStream::new(GzipDecoder::new(reader))
+            .map_err(Error::from)
+            .boxed(),
+        Encoding::Zstd => ReaderStream::new(ZstdDecoder::new(reader))
+            .map_err(Error::from)
+            .boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::storage::mem::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_compressed_storage() {
+        let data = Bytes::from("id,name,status\n".repeat(10_000));
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let storage = CompressedStorage::new(Box::new(MemoryStorage::new()), encoding);
+
+            // Write some compressible data, in pieces
+            let chunks = data.chunks(1000).map(|c| Ok(Bytes::copy_from_slice(c)));
+            let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
+            assert_eq!(storage.read(&key).await.unwrap(), data);
+
+            // Contents at rest are smaller, and record the encoding
+            let stored = storage.inner.read(&key).await.unwrap();
+            assert!(stored.len() < data.len() / 10);
+            assert_eq!(Encoding::from_header(&stored), Some(encoding));
+
+            // Read a range from the decompressed contents
+            let range = ByteRange::parse("bytes=15-28", data.len() as u64).unwrap();
+            let stream = storage.read_stream(&key, range).await.unwrap();
+            let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), b"id,name,status");
+
+            // Metadata reports the uncompressed size
+            let object = storage.stat(&key).await.unwrap();
+            assert_eq!(object.size, data.len() as u64);
+
+            // Objects written without a recorded size are still readable
+            let legacy = [
+                &LEGACY_MAGIC[..],
+                &stored[LEGACY_MAGIC.len()..stored.len() - FOOTER_LEN],
+            ];
+            let stream = stream::iter([Ok(Bytes::from(legacy.concat()))]).boxed();
+            let legacy_key = storage.inner.write(stream).await.unwrap();
+            assert_eq!(storage.read(&legacy_key).await.unwrap(), data);
+            let object = storage.stat(&legacy_key).await.unwrap();
+            assert_eq!(object.size, data.len() as u64);
+
+            // Uncompressed objects are read as is
+            let plain = Bytes::from("The quick brown fox jumped over the lazy dog");
+            let stream = stream::iter([Ok(plain.clone())]).boxed();
+            let plain_key = storage.inner.write(stream).await.unwrap();
+            assert_eq!(storage.read(&plain_key).await.unwrap(), plain);
+            let range = ByteRange::parse("bytes=4-8", plain.len() as u64).unwrap();
+            let stream = storage.read_stream(&plain_key, range).await.unwrap();
+            let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), b"quick");
+            let object = storage.stat(&plain_key).await.unwrap();
+            assert_eq!(object.size, plain.len() as u64);
+
+            // Presigned urls are refused
+            let result = storage.presign_write(Duration::from_secs(60)).await;
+            assert!(matches!(result, Err(Error::InvalidArgs { .. })));
+
+            // Empty streams are rejected
+            let result = storage.write(stream::empty().boxed()).await;
+            assert!(result.is_err());
+        }
+    }
+}
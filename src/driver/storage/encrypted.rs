@@ -0,0 +1,355 @@
+use super::slice;
+use crate::{
+    domain::{peek, ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
+    Error, Result,
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use std::time::Duration;
+
+// Marks and versions the format of encrypted objects.
+const MAGIC: &[u8; 4] = b"SQE1";
+
+// AES-256-GCM sizes
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// Object header: magic, data key nonce, then the data key wrapped by the master key.
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN + KEY_LEN + TAG_LEN;
+
+// Contents are sealed in fixed size chunks, so byte ranges can be decrypted on their own.
+const CHUNK_LEN: usize = 64 * 1024;
+const SEALED_CHUNK_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+/// Storage decorator that encrypts contents at rest with AES-256-GCM. Each object is sealed
+/// with a random data key, which is wrapped by the master key and kept in the object header.
+/// Objects without an encryption header are read back as plaintext.
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+    master: Aes256Gcm,
+}
+
+impl EncryptedStorage {
+    /// Create an encrypting wrapper around another storage instance.
+    pub fn new(inner: Box<dyn Storage>, master_key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            inner,
+            master: Aes256Gcm::new(master_key.into()),
+        }
+    }
+
+    /// Generate a data key, and an object header holding the wrapped key.
+    fn seal_key(&self) -> Result<(Aes256Gcm, Bytes)> {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &key,
+            aad: MAGIC,
+        };
+        let wrapped = self
+            .master
+            .encrypt(&nonce, payload)
+            .map_err(|_| Error::internal("unable to wrap data key"))?;
+        let mut header = BytesMut::with_capacity(HEADER_LEN);
+        header.put_slice(MAGIC);
+        header.put_slice(&nonce);
+        header.put_slice(&wrapped);
+        Ok((Aes256Gcm::new(&key), header.freeze()))
+    }
+
+    /// Unwrap the data key from an object header, or None for plaintext objects.
+    fn open_key(&self, header: &[u8]) -> Result<Option<Aes256Gcm>> {
+        if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let (nonce, wrapped) = header[MAGIC.len()..HEADER_LEN].split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: wrapped,
+            aad: MAGIC,
+        };
+        let key = self
+            .master
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| Error::internal("unable to unwrap data key"))?;
+        Ok(Some(
+            Aes256Gcm::new_from_slice(&key)
+                .map_err(|_| Error::internal("invalid data key length"))?,
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for EncryptedStorage {
+    /// Read and decrypt object
+    async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+        let chunks: Vec<Bytes> = self
+            .read_stream(storage_id, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(chunks.concat().into())
+    }
+
+    /// Stream and decrypt object, reading only the chunks that cover a range if given
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let Some(range) = range else {
+            // Buffer enough of the stream to check for a header
+            let mut stream = self.inner.read_stream(storage_id, None).await?;
+            let mut buf = peek(&mut stream, HEADER_LEN).await?;
+            let Some(cipher) = self.open_key(&buf)? else {
+                let head = stream::once(future::ok(buf.freeze()));
+                return Ok(head.chain(stream).boxed());
+            };
+            buf.advance(HEADER_LEN);
+            return Ok(open(stream, cipher, 0, buf, true));
+        };
+
+        let header_range = ByteRange {
+            offset: 0,
+            length: HEADER_LEN as u64,
+        };
+        let header: Vec<Bytes> = self
+            .inner
+            .read_stream(storage_id, Some(header_range))
+            .await?
+            .try_collect()
+            .await?;
+        let Some(cipher) = self.open_key(&header.concat())? else {
+            return self.inner.read_stream(storage_id, Some(range)).await;
+        };
+
+        // Read the sealed chunks covering the range, then trim to the requested bytes
+        let first = range.offset / CHUNK_LEN as u64;
+        let last = range.last() / CHUNK_LEN as u64;
+        let sealed_range = ByteRange {
+            offset: HEADER_LEN as u64 + first * SEALED_CHUNK_LEN as u64,
+            length: (last - first + 1) * SEALED_CHUNK_LEN as u64,
+        };
+        let stream = self
+            .inner
+            .read_stream(storage_id, Some(sealed_range))
+            .await?;
+        let skip = range.offset - first * CHUNK_LEN as u64;
+        let stream = open(stream, cipher, first, BytesMut::new(), false);
+        Ok(slice(stream, skip, range.length))
+    }
+
+    /// Encrypt and write object
+    async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()> {
+        let (cipher, header) = self.seal_key()?;
+        let sealed = stream::once(future::ok(header)).chain(seal(stream, cipher));
+        self.inner.put(storage_id, sealed.boxed()).await
+    }
+
+    /// Delete object
+    async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+        self.inner.delete(storage_id).await
+    }
+
+    /// List objects, with sizes as stored
+    async fn list(&self) -> Result<ObjectStream> {
+        self.inner.list().await
+    }
+
+    /// Read object metadata, with the plaintext size worked out from the sealed size
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        let mut object = self.inner.stat(storage_id).await?;
+        if object.size < HEADER_LEN as u64 {
+            return Ok(object);
+        }
+        let header_range = ByteRange {
+            offset: 0,
+            length: HEADER_LEN as u64,
+        };
+        let header: Vec<Bytes> = self
+            .inner
+            .read_stream(storage_id, Some(header_range))
+            .await?
+            .try_collect()
+            .await?;
+        if self.open_key(&header.concat())?.is_some() {
+            object.size = plaintext_len(object.size - HEADER_LEN as u64);
+        }
+        Ok(object)
+    }
+
+    /// Presigned urls would bypass encryption, so they aren't supported
+    async fn presign_write(&self, _expires_in: Duration) -> Result<(StorageId, String)> {
+        Err(Error::invalid_args(
+            "presigned urls are not supported with storage encryption",
+        ))
+    }
+
+    /// Presigned urls would serve ciphertext, so they aren't supported
+    async fn presign_read(&self, _storage_id: &StorageId, _expires_in: Duration) -> Result<String> {
+        Err(Error::invalid_args(
+            "presigned urls are not supported with storage encryption",
+        ))
+    }
+
+    /// Flush the inner storage
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// The plaintext size of sealed chunks, each of which carries a tag.
+fn plaintext_len(sealed_len: u64) -> u64 {
+    let chunks = sealed_len.div_ceil(SEALED_CHUNK_LEN as u64);
+    sealed_len.saturating_sub(chunks * TAG_LEN as u64)
+}
+
+/// The nonce for a chunk is its index, and a flag marking the final chunk so truncation
+/// is detected. Data keys are never reused across objects, so nonces need not be random.
+fn chunk_nonce(index: u64, last: bool) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce.into()
+}
+
+/// Encrypt a stream of bytes in fixed size chunks.
+fn seal(stream: ByteStream<'_>, cipher: Aes256Gcm) -> ByteStream<'_> {
+    let state = Some((stream, cipher, BytesMut::new(), 0u64));
+    stream::unfold(state, |state| async move {
+        let (mut stream, cipher, mut buf, index) = state?;
+        loop {
+            // A full chunk is only final once the stream ends, so wait for more bytes
+            if buf.len() > CHUNK_LEN {
+                let chunk = buf.split_to(CHUNK_LEN);
+                let sealed = seal_chunk(&cipher, index, false, &chunk);
+                return Some((sealed, Some((stream, cipher, buf, index + 1))));
+            }
+            match stream.next().await {
+                Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                Some(Err(err)) => return Some((Err(err), None)),
+                None if index == 0 && buf.is_empty() => {
+                    return Some((Err(Error::invalid_args("empty file")), None));
+                }
+                None => return Some((seal_chunk(&cipher, index, true, &buf), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Encrypt a single chunk.
+fn seal_chunk(cipher: &Aes256Gcm, index: u64, last: bool, chunk: &[u8]) -> Result<Bytes> {
+    cipher
+        .encrypt(&chunk_nonce(index, last), chunk)
+        .map(Bytes::from)
+        .map_err(|_| Error::internal("unable to encrypt object contents"))
+}
+
+/// Decrypt a stream of sealed chunks, starting from a chunk index. When the stream holds
+/// every chunk to the end of the object, the final chunk must be marked as such.
+fn open(
+    stream: ByteStream<'static>,
+    cipher: Aes256Gcm,
+    index: u64,
+    buf: BytesMut,
+    to_end: bool,
+) -> ByteStream<'static> {
+    let state = Some((stream, cipher, buf, index));
+    stream::unfold(state, move |state| async move {
+        let (mut stream, cipher, mut buf, index) = state?;
+        loop {
+            if buf.len() > SEALED_CHUNK_LEN {
+                let chunk = buf.split_to(SEALED_CHUNK_LEN);
+                let opened = open_chunk(&cipher, index, Some(false), &chunk);
+                return Some((opened, Some((stream, cipher, buf, index + 1))));
+            }
+            match stream.next().await {
+                Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                Some(Err(err)) => return Some((Err(err), None)),
+                None if buf.is_empty() && !to_end => return None,
+                None => {
+                    let last = to_end.then_some(true);
+                    return Some((open_chunk(&cipher, index, last, &buf), None));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Decrypt a single chunk, trying both final and non-final nonces when unknown.
+fn open_chunk(cipher: &Aes256Gcm, index: u64, last: Option<bool>, chunk: &[u8]) -> Result<Bytes> {
+    let flags = match last {
+        Some(last) => vec![last],
+        None => vec![false, true],
+    };
+    flags
+        .into_iter()
+        .find_map(|last| cipher.decrypt(&chunk_nonce(index, last), chunk).ok())
+        .map(Bytes::from)
+        .ok_or_else(|| Error::internal("unable to decrypt object contents"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::storage::mem::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_encrypted_storage() {
+        let storage = EncryptedStorage::new(Box::new(MemoryStorage::new()), &[7u8; KEY_LEN]);
+
+        // Write data spanning several chunks, in uneven pieces
+        let data: Bytes = (0..CHUNK_LEN * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let chunks = data.chunks(1000).map(|c| Ok(Bytes::copy_from_slice(c)));
+        let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
+        assert_eq!(storage.read(&key).await.unwrap(), data);
+
+        // Contents at rest are not plaintext
+        let sealed = storage.inner.read(&key).await.unwrap();
+        assert_eq!(sealed.len(), HEADER_LEN + data.len() + 3 * TAG_LEN);
+        assert!(!sealed.windows(100).any(|w| w == &data[..100]));
+
+        // Metadata reports the plaintext size
+        let object = storage.stat(&key).await.unwrap();
+        assert_eq!(object.size, data.len() as u64);
+
+        // Ranges within, across and at the end of chunks
+        for (offset, length) in [(0, 10), (CHUNK_LEN - 5, 10), (CHUNK_LEN * 2 + 90, 10)] {
+            let range = ByteRange {
+                offset: offset as u64,
+                length: length as u64,
+            };
+            let stream = storage.read_stream(&key, Some(range)).await.unwrap();
+            let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), &data[offset..offset + length]);
+        }
+
+        // A different master key can't read the contents
+        let other = EncryptedStorage::new(Box::new(MemoryStorage::new()), &[8u8; KEY_LEN]);
+        let other_key = other.inner.write(stream::iter([Ok(sealed)]).boxed()).await;
+        assert!(other.read(&other_key.unwrap()).await.is_err());
+
+        // Plaintext objects written before encryption are still readable
+        let plain = Bytes::from("The quick brown fox jumped over the lazy dog");
+        let stream = stream::iter([Ok(plain.clone())]).boxed();
+        let plain_key = storage.inner.write(stream).await.unwrap();
+        assert_eq!(storage.read(&plain_key).await.unwrap(), plain);
+        let range = ByteRange::parse("bytes=4-8", plain.len() as u64).unwrap();
+        let stream = storage.read_stream(&plain_key, range).await.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"quick");
+        let object = storage.stat(&plain_key).await.unwrap();
+        assert_eq!(object.size, plain.len() as u64);
+
+        // Presigned urls are refused
+        let result = storage.presign_write(Duration::from_secs(60)).await;
+        assert!(matches!(result, Err(Error::InvalidArgs { .. })));
+    }
+}
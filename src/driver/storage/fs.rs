@@ -1,24 +1,78 @@
 use crate::{
-    domain::{Storage, StorageId},
+    domain::{ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
     Error, Result,
 };
 use bytes::Bytes;
-use std::path::{Path, MAIN_SEPARATOR_STR};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 use tokio::{
     fs::{self, File},
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
-/// Store binary objects in local files.
+// Files are written here first, then renamed into place once complete.
+const TEMP_DIR: &str = ".tmp";
+
+// Temp files older than this are left over from interrupted writes.
+const TEMP_MAX_AGE: Duration = Duration::from_secs(3600);
+
+// The number of nested shard dirs, each named by a byte of the key hash.
+const SHARD_DEPTH: usize = 2;
+
+/// How durably files are written before a write succeeds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileSync {
+    /// Leave flushing to the OS.
+    None,
+    /// Sync file contents to disk before renaming into place.
+    #[default]
+    File,
+    /// Also sync the parent dir after renaming, so the rename itself survives a crash.
+    Full,
+}
+
+impl FromStr for FileSync {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(FileSync::None),
+            "file" => Ok(FileSync::File),
+            "full" => Ok(FileSync::Full),
+            _ => Err(Error::invalid_args(format!("unknown file sync: {s}"))),
+        }
+    }
+}
+
+/// Store binary objects in local files, sharded into nested dirs named by a hash of the key so
+/// no one dir grows too large. Files are written to a temp dir and renamed into place, so
+/// readers never see partial files.
 pub struct FileStorage {
     pub root_dir: String,
+    sync: FileSync,
 }
 
 impl FileStorage {
     /// Create a file storage instance.
     pub fn new(root_dir: String) -> Self {
-        Self { root_dir }
+        Self {
+            root_dir,
+            sync: FileSync::default(),
+        }
+    }
+
+    /// Set how durably files are written.
+    pub fn with_sync(mut self, sync: FileSync) -> Self {
+        self.sync = sync;
+        self
     }
 
     /// Verify that the root dir exists.
@@ -29,9 +83,114 @@ impl FileStorage {
         Ok(self)
     }
 
-    /// Build file-system storage path for a key.
-    fn path(&self, file_name: &Uuid) -> String {
-        format!("{}{}{}", self.root_dir, MAIN_SEPARATOR_STR, file_name)
+    /// Move files stored flat in the root dir by the older layout into their shard dirs.
+    /// Returns the number of files moved. Files not yet moved can still be read and deleted,
+    /// and files written or deleted meanwhile are skipped, so this is safe while in use.
+    pub async fn shard_flat_files(&self) -> Result<usize> {
+        let mut entries = match fs::read_dir(&self.root_dir).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            entries => entries?,
+        };
+        let mut moved = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(key) = storage_key(&entry.path()) else {
+                continue;
+            };
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            let path = self.path(&key);
+            fs::create_dir_all(shard_dir(&path)).await?;
+            // Linking never replaces a newer file written into the shard dir meanwhile
+            match fs::hard_link(entry.path(), &path).await {
+                Ok(()) => moved += 1,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            match fs::remove_file(entry.path()).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Remove temp files left by interrupted writes, once unmodified for an hour. Returns the
+    /// number of files removed. A write stalled for that long by another instance would fail.
+    pub async fn remove_stale_temp_files(&self) -> Result<usize> {
+        let mut entries = match fs::read_dir(self.temp_dir()).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            entries => entries?,
+        };
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = match entry.metadata().await {
+                Ok(metadata) => metadata.modified()?,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age <= TEMP_MAX_AGE {
+                continue;
+            }
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Build file-system storage path for a key, within its shard dirs.
+    fn path(&self, file_name: &Uuid) -> PathBuf {
+        let hash = Sha256::digest(file_name.as_bytes());
+        let mut path = PathBuf::from(&self.root_dir);
+        for byte in &hash[..SHARD_DEPTH] {
+            path.push(format!("{byte:02x}"));
+        }
+        path.join(file_name.to_string())
+    }
+
+    /// Build file-system storage path for a key by the older flat layout.
+    fn flat_path(&self, file_name: &Uuid) -> PathBuf {
+        Path::new(&self.root_dir).join(file_name.to_string())
+    }
+
+    /// The dir that files are written to before they are complete.
+    fn temp_dir(&self) -> PathBuf {
+        Path::new(&self.root_dir).join(TEMP_DIR)
+    }
+
+    /// Open the file for a key, falling back to the flat layout for files not yet moved.
+    async fn open(&self, key: &Uuid) -> Result<File> {
+        let result = match File::open(self.path(key)).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => File::open(self.flat_path(key)).await,
+            result => result,
+        };
+        match result {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Err(Error::not_found(format!("file {key} not found")))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Write a stream to a temp file, syncing it if configured. Returns the number of bytes
+    /// written.
+    async fn write_temp(&self, path: &Path, stream: ByteStream<'_>) -> Result<usize> {
+        let mut file = File::create(path).await?;
+        let size = copy(stream, &mut file).await?;
+        if size == 0 {
+            return Err(Error::invalid_args("empty file"));
+        }
+        if self.sync != FileSync::None {
+            file.sync_all().await?;
+        }
+        Ok(size)
     }
 }
 
@@ -39,26 +198,166 @@ impl FileStorage {
 impl Storage for FileStorage {
     /// Read bytes from file
     async fn read(&self, StorageId(key): &StorageId) -> Result<Bytes> {
-        let bytes = fs::read(self.path(key)).await?;
+        let mut bytes = Vec::new();
+        self.open(key).await?.read_to_end(&mut bytes).await?;
         Ok(Bytes::from(bytes))
     }
 
-    /// Write bytes to file
-    async fn write(&self, bytes: Bytes) -> Result<StorageId> {
-        if bytes.is_empty() {
-            return Err(Error::invalid_args("empty file"));
+    /// Stream bytes from file, seeking to the start of a range if given
+    async fn read_stream(
+        &self,
+        StorageId(key): &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let mut file = self.open(key).await?;
+        let reader = match range {
+            Some(ByteRange { offset, length }) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.take(length)
+            }
+            None => file.take(u64::MAX),
+        };
+        Ok(ReaderStream::new(reader).map_err(Error::from).boxed())
+    }
+
+    /// Write streamed bytes to a temp file, then rename it into place
+    async fn put(&self, StorageId(key): &StorageId, stream: ByteStream<'_>) -> Result<()> {
+        let path = self.path(key);
+        let temp_dir = self.temp_dir();
+        fs::create_dir_all(&temp_dir).await?;
+        fs::create_dir_all(shard_dir(&path)).await?;
+
+        // Remove partially written or empty files
+        let temp_path = temp_dir.join(format!("{key}.{}", Uuid::new_v4()));
+        let result = match self.write_temp(&temp_path, stream).await {
+            Ok(_) => fs::rename(&temp_path, &path).await.map_err(Error::from),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            fs::remove_file(&temp_path).await.ok();
+            return Err(err);
+        }
+        if self.sync == FileSync::Full {
+            File::open(shard_dir(&path)).await?.sync_all().await?;
+        }
+
+        // Drop any copy stored by the older flat layout, which is now stale
+        match fs::remove_file(self.flat_path(key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
         }
-        let key = Uuid::new_v4();
-        let mut file = File::create(self.path(&key)).await?;
-        file.write_all(&bytes).await?;
-        Ok(StorageId(key))
     }
 
-    /// Delete bytes for a key
+    /// Delete bytes for a key, succeeding if the file is already gone
     async fn delete(&self, StorageId(key): &StorageId) -> Result<()> {
-        fs::remove_file(self.path(key)).await?;
+        for path in [self.path(key), self.flat_path(key)] {
+            match fs::remove_file(path).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
         Ok(())
     }
+
+    /// List files in the root and shard dirs, skipping entries that aren't storage keys
+    async fn list(&self) -> Result<ObjectStream> {
+        let root = PathBuf::from(&self.root_dir);
+        let entries = fs::read_dir(&root).await?;
+        let dirs = vec![(entries, 0)];
+        let stream = stream::unfold(dirs, |mut dirs| async move {
+            loop {
+                let (entries, depth) = dirs.last_mut()?;
+                let depth = *depth;
+                let entry = match entries.next_entry().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => {
+                        dirs.pop();
+                        continue;
+                    }
+                    Err(err) => {
+                        dirs.pop();
+                        return Some((Err(err.into()), dirs));
+                    }
+                };
+                let path = entry.path();
+                if depth < SHARD_DEPTH && is_shard_name(&path) {
+                    match entry.file_type().await {
+                        Ok(file_type) if !file_type.is_dir() => continue,
+                        Ok(_) => {}
+                        Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                        Err(err) => return Some((Err(err.into()), dirs)),
+                    }
+                    match fs::read_dir(&path).await {
+                        Ok(entries) => dirs.push((entries, depth + 1)),
+                        Err(err) => return Some((Err(err.into()), dirs)),
+                    }
+                    continue;
+                }
+                match stored_object(&path).await {
+                    Ok(Some(object)) => return Some((Ok(object), dirs)),
+                    Ok(None) => continue,
+                    Err(err) => return Some((Err(err), dirs)),
+                }
+            }
+        });
+        Ok(stream.boxed())
+    }
+
+    /// Read file metadata for a key
+    async fn stat(&self, StorageId(key): &StorageId) -> Result<StoredObject> {
+        let metadata = self.open(key).await?.metadata().await?;
+        Ok(StoredObject {
+            storage_id: StorageId(*key),
+            size: metadata.len(),
+            modified_at: metadata.modified()?.into(),
+        })
+    }
+}
+
+/// The storage key a path is named by, if any.
+fn storage_key(path: &Path) -> Option<Uuid> {
+    let name = path.file_name()?.to_str()?;
+    Uuid::parse_str(name).ok()
+}
+
+/// The dir a storage path is in.
+fn shard_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new("."))
+}
+
+/// Whether a path is named like a shard dir: a byte in lower-case hex.
+fn is_shard_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    name.len() == 2 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Read object metadata for a path, if it is a file named by a storage key.
+async fn stored_object(path: &Path) -> Result<Option<StoredObject>> {
+    let Some(key) = storage_key(path) else {
+        return Ok(None);
+    };
+    let metadata = fs::metadata(path).await?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(StoredObject {
+        storage_id: StorageId(key),
+        size: metadata.len(),
+        modified_at: metadata.modified()?.into(),
+    }))
+}
+
+/// Copy chunks from a stream into a file, returning the number of bytes written.
+async fn copy(mut stream: ByteStream<'_>, file: &mut File) -> Result<usize> {
+    let mut size = 0;
+    while let Some(bytes) = stream.next().await.transpose()? {
+        file.write_all(&bytes).await?;
+        size += bytes.len();
+    }
+    file.flush().await?;
+    Ok(size)
 }
 
 #[cfg(test)]
@@ -76,16 +375,85 @@ mod tests {
 
         // Write, read, then delete some binary data.
         let data = Bytes::from("The quick brown fox jumped over the lazy dog");
-        let key = storage.write(data.clone()).await.unwrap();
+        let chunks = data.chunks(8).map(|c| Ok(Bytes::copy_from_slice(c)));
+        let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
         let read_data = storage.read(&key).await.unwrap();
         assert_eq!(read_data, data);
+        assert!(storage.path(&key.0).exists());
+
+        // Stream a range of bytes
+        let range = ByteRange::parse("bytes=4-8", data.len() as u64).unwrap();
+        let stream = storage.read_stream(&key, range).await.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"quick");
+
+        // Read file metadata
+        let object = storage.stat(&key).await.unwrap();
+        assert_eq!(object.size, data.len() as u64);
+
+        // List stored files, ignoring files that aren't named by storage keys
+        fs::write(temp_dir.join("README.md"), "not an object")
+            .await
+            .unwrap();
+        let objects: Vec<StoredObject> = storage.list().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].storage_id, key);
+        assert_eq!(objects[0].size, data.len() as u64);
+        fs::remove_file(temp_dir.join("README.md")).await.unwrap();
         storage.delete(&key).await.unwrap();
 
-        // Verify file is deleted
+        // Verify file is deleted, and deleting again is a no-op
         let result = storage.read(&key).await;
+        assert!(matches!(result, Err(Error::NotFound { .. })));
+        storage.delete(&key).await.unwrap();
+
+        // Empty streams are rejected and leave no file behind
+        let result = storage.write(stream::empty().boxed()).await;
         assert!(result.is_err());
+        let mut entries = fs::read_dir(temp_dir.join(TEMP_DIR)).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
 
         // Cleanup
         fs::remove_dir_all(&temp_dir).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_fs_storage_flat_layout() {
+        let temp_dir = std::env::temp_dir().join(format!("fs_storage_test_{}", Uuid::new_v4()));
+        fs::create_dir_all(&temp_dir).await.unwrap();
+        let storage = FileStorage::new(temp_dir.to_str().unwrap().to_string());
+
+        // Files stored flat are readable and listed before they are moved
+        let key = Uuid::new_v4();
+        fs::write(temp_dir.join(key.to_string()), "flat")
+            .await
+            .unwrap();
+        assert_eq!(storage.read(&StorageId(key)).await.unwrap(), "flat");
+        let objects: Vec<StoredObject> = storage.list().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(objects.len(), 1);
+
+        // Sharding moves them into shard dirs
+        assert_eq!(storage.shard_flat_files().await.unwrap(), 1);
+        assert!(!temp_dir.join(key.to_string()).exists());
+        assert!(storage.path(&key).exists());
+        assert_eq!(storage.read(&StorageId(key)).await.unwrap(), "flat");
+        assert_eq!(storage.shard_flat_files().await.unwrap(), 0);
+
+        // Stale flat files never replace newer sharded ones
+        fs::write(temp_dir.join(key.to_string()), "stale")
+            .await
+            .unwrap();
+        assert_eq!(storage.shard_flat_files().await.unwrap(), 0);
+        assert!(!temp_dir.join(key.to_string()).exists());
+        assert_eq!(storage.read(&StorageId(key)).await.unwrap(), "flat");
+
+        // Only temp files left unmodified for a while are removed
+        let temp = storage.temp_dir();
+        fs::create_dir_all(&temp).await.unwrap();
+        fs::write(temp.join("partial"), "part").await.unwrap();
+        assert_eq!(storage.remove_stale_temp_files().await.unwrap(), 0);
+        assert!(temp.join("partial").exists());
+
+        fs::remove_dir_all(&temp_dir).await.unwrap();
+    }
 }
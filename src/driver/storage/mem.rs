@@ -1,29 +1,236 @@
 use crate::{
-    domain::{Storage, StorageId},
+    domain::{ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
     Error, Result,
 };
 
-use bytes::Bytes;
-use std::collections::HashMap;
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use metrics::gauge;
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 
+// Metric names
+const OBJECTS: &str = "storage_memory_objects";
+const BYTES: &str = "storage_memory_bytes";
+
 /// In-memory binary object storage.
 /// NOTE: This only allows a number of readers or at most one writer at any point in time.
 /// For this reason, it is only useful for testing or running in local a dev environment.
-type DataStore = Arc<RwLock<HashMap<Uuid, Bytes>>>;
+type DataStore = Arc<RwLock<MemoryObjects>>;
+
+/// A binary object and the time it was written.
+#[derive(Clone, Debug)]
+pub struct MemoryObject {
+    pub bytes: Bytes,
+    pub created_at: DateTime<Utc>,
+}
 
-/// Store binary objects in memory.
+/// Objects held in memory, indexed by age for eviction, with their total size.
+#[derive(Default)]
+pub struct MemoryObjects {
+    objects: HashMap<Uuid, MemoryObject>,
+    by_age: BTreeSet<(DateTime<Utc>, Uuid)>,
+    bytes: u64,
+}
+
+impl MemoryObjects {
+    /// Get an object for a key.
+    pub fn get(&self, key: &Uuid) -> Option<&MemoryObject> {
+        self.objects.get(key)
+    }
+
+    /// Iterate over all objects, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Uuid, &MemoryObject)> {
+        self.objects.iter()
+    }
+
+    /// Insert an object, returning the one it replaced, if any.
+    fn insert(&mut self, key: Uuid, object: MemoryObject) -> Option<MemoryObject> {
+        let replaced = self.remove(&key);
+        self.by_age.insert((object.created_at, key));
+        self.bytes += object.bytes.len() as u64;
+        self.objects.insert(key, object);
+        replaced
+    }
+
+    /// Remove an object for a key, returning it if it was held.
+    fn remove(&mut self, key: &Uuid) -> Option<MemoryObject> {
+        let object = self.objects.remove(key)?;
+        self.by_age.remove(&(object.created_at, *key));
+        self.bytes -= object.bytes.len() as u64;
+        Some(object)
+    }
+
+    /// Remove the oldest object other than the one for a key, returning it if there was one.
+    fn evict_oldest(&mut self, except: &Uuid) -> Option<MemoryObject> {
+        let (_, key) = self.by_age.iter().find(|(_, k)| k != except)?;
+        let key = *key;
+        self.remove(&key)
+    }
+
+    /// The size held by objects other than the one for a key.
+    fn bytes_except(&self, key: &Uuid) -> u64 {
+        self.bytes - self.objects.get(key).map_or(0, |o| o.bytes.len() as u64)
+    }
+}
+
+/// What to do with a write that would exceed the size limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Evict the oldest objects to make room.
+    #[default]
+    Evict,
+    /// Reject the write.
+    Reject,
+}
+
+impl FromStr for WhenFull {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "evict" => Ok(WhenFull::Evict),
+            "reject" => Ok(WhenFull::Reject),
+            _ => Err(Error::invalid_args(format!("unknown full policy: {s}"))),
+        }
+    }
+}
+
+/// The number and total size of objects held in memory, served as metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct MemoryStats {
+    objects: usize,
+    bytes: u64,
+}
+
+impl MemoryStats {
+    /// Count the objects in a datastore map.
+    fn of(map: &MemoryObjects) -> Self {
+        Self {
+            objects: map.objects.len(),
+            bytes: map.bytes,
+        }
+    }
+
+    /// Record the stats as metrics.
+    fn record(self) {
+        gauge!(OBJECTS).set(self.objects as f64);
+        gauge!(BYTES).set(self.bytes as f64);
+    }
+}
+
+/// A binary object as saved in a snapshot.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct SnapshotObject {
+    key: [u8; 16],
+    created_at_ms: i64,
+    bytes: Vec<u8>,
+}
+
+/// Store binary objects in memory, optionally limited in size, and saved to a snapshot file
+/// on flush so they survive a restart. Objects written since the last flush are lost if the
+/// process exits without flushing, e.g. on a crash.
 #[derive(Default)]
 pub struct MemoryStorage {
     pub datastore: DataStore,
+    max_bytes: Option<u64>,
+    when_full: WhenFull,
+    snapshot_path: Option<PathBuf>,
 }
 
 impl MemoryStorage {
     /// Create a new memory storage instance.
     pub fn new() -> Self {
+        MemoryStats::default().record();
         Default::default()
     }
+
+    /// Limit the total size of objects held, handling writes beyond the limit by a policy.
+    pub fn with_limit(mut self, max_bytes: u64, when_full: WhenFull) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.when_full = when_full;
+        self
+    }
+
+    /// Restore objects from a snapshot file if it exists, and save them back to it on flush.
+    /// Restored objects beyond the size limit are handled as for writes, oldest first.
+    pub fn with_snapshot(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let snapshot = match std::fs::read(&path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            snapshot => snapshot?,
+        };
+        if !snapshot.is_empty() {
+            let mut objects: Vec<SnapshotObject> = borsh::from_slice(&snapshot)?;
+            objects.sort_by_key(|o| o.created_at_ms);
+            let Ok(mut map) = self.datastore.write() else {
+                return Err(Error::internal("write lock fail"));
+            };
+            let mut dropped = 0;
+            for SnapshotObject {
+                key,
+                created_at_ms,
+                bytes,
+            } in objects
+            {
+                let object = MemoryObject {
+                    bytes: Bytes::from(bytes),
+                    created_at: DateTime::from_timestamp_millis(created_at_ms)
+                        .unwrap_or_else(Utc::now),
+                };
+                if self
+                    .insert(&mut map, Uuid::from_bytes(key), object)
+                    .is_err()
+                {
+                    dropped += 1;
+                }
+            }
+            if dropped > 0 {
+                tracing::warn!("dropped {} snapshot objects beyond the size limit", dropped);
+            }
+            MemoryStats::of(&map).record();
+        }
+        self.snapshot_path = Some(path);
+        Ok(self)
+    }
+
+    /// Check that an object of a size could be held under the size limit, if there is one.
+    /// An object being replaced makes room for its replacement.
+    fn check_room(&self, map: &MemoryObjects, key: &Uuid, size: u64) -> Result<()> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(());
+        };
+        if size > max_bytes {
+            return Err(Error::quota_exceeded(format!(
+                "object exceeds the memory storage limit of {max_bytes} bytes"
+            )));
+        }
+        if self.when_full == WhenFull::Reject && map.bytes_except(key) + size > max_bytes {
+            return Err(Error::quota_exceeded("memory storage is full"));
+        }
+        Ok(())
+    }
+
+    /// Insert an object, making room under the size limit if there is one.
+    fn insert(&self, map: &mut MemoryObjects, key: Uuid, object: MemoryObject) -> Result<()> {
+        let size = object.bytes.len() as u64;
+        self.check_room(map, &key, size)?;
+        if let Some(max_bytes) = self.max_bytes {
+            while map.bytes_except(&key) + size > max_bytes {
+                if map.evict_oldest(&key).is_none() {
+                    break;
+                }
+            }
+        }
+        map.insert(key, object);
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -31,41 +238,130 @@ impl Storage for MemoryStorage {
     /// Read object for a key
     async fn read(&self, StorageId(key): &StorageId) -> Result<Bytes> {
         if let Ok(map) = self.datastore.read() {
-            if let Some(value) = map.get(key) {
-                return Ok(value.to_owned());
+            if let Some(object) = map.get(key) {
+                return Ok(object.bytes.clone());
             }
         }
-        Err(Error::not_found("file not found"))
+        Err(Error::not_found(format!("object {key} not found")))
     }
 
-    /// Write object to datastore and return an lookup key.
-    async fn write(&self, bytes: Bytes) -> Result<StorageId> {
+    /// Stream object for a key as a single chunk, sliced to a range if given
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let mut bytes = self.read(storage_id).await?;
+        if let Some(ByteRange { offset, length }) = range {
+            let len = bytes.len() as u64;
+            let start = offset.min(len);
+            let end = offset.saturating_add(length).min(len);
+            bytes = bytes.slice(start as usize..end as usize);
+        }
+        Ok(stream::once(async { Ok(bytes) }).boxed())
+    }
+
+    /// Write object to datastore under a lookup key, within the size limit. Writes that can't
+    /// fit are aborted as soon as the chunks read so far exceed the room left.
+    async fn put(&self, StorageId(key): &StorageId, mut stream: ByteStream<'_>) -> Result<()> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = stream.next().await.transpose()? {
+            buf.extend_from_slice(&chunk);
+            if self.max_bytes.is_some() {
+                let Ok(map) = self.datastore.read() else {
+                    return Err(Error::internal("read lock fail"));
+                };
+                self.check_room(&map, key, buf.len() as u64)?;
+            }
+        }
+        let bytes = buf.freeze();
         if bytes.is_empty() {
             return Err(Error::invalid_args("empty file"));
         }
-        let key = Uuid::new_v4();
-        if let Ok(mut map) = self.datastore.write() {
-            map.insert(key, bytes);
-        } else {
+        let Ok(mut map) = self.datastore.write() else {
             return Err(Error::internal("write lock fail"));
-        }
-        Ok(StorageId(key))
+        };
+        let created_at = Utc::now();
+        self.insert(&mut map, *key, MemoryObject { bytes, created_at })?;
+        MemoryStats::of(&map).record();
+        Ok(())
     }
 
     /// Delete object for a key
     async fn delete(&self, StorageId(key): &StorageId) -> Result<()> {
         if let Ok(mut map) = self.datastore.write() {
             map.remove(key);
+            MemoryStats::of(&map).record();
         } else {
             return Err(Error::internal("write lock fail"));
         }
         Ok(())
     }
+
+    /// List a snapshot of all objects in the datastore
+    async fn list(&self) -> Result<ObjectStream> {
+        let Ok(map) = self.datastore.read() else {
+            return Err(Error::internal("read lock fail"));
+        };
+        let objects: Vec<_> = map
+            .iter()
+            .map(|(key, object)| {
+                Ok(StoredObject {
+                    storage_id: StorageId(*key),
+                    size: object.bytes.len() as u64,
+                    modified_at: object.created_at,
+                })
+            })
+            .collect();
+        Ok(stream::iter(objects).boxed())
+    }
+
+    /// Read object metadata for a key
+    async fn stat(&self, StorageId(key): &StorageId) -> Result<StoredObject> {
+        if let Ok(map) = self.datastore.read() {
+            if let Some(object) = map.get(key) {
+                return Ok(StoredObject {
+                    storage_id: StorageId(*key),
+                    size: object.bytes.len() as u64,
+                    modified_at: object.created_at,
+                });
+            }
+        }
+        Err(Error::not_found(format!("object {key} not found")))
+    }
+
+    /// Save all objects to the snapshot file, if set, replacing it atomically
+    async fn flush(&self) -> Result<()> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        let objects: Vec<_> = match self.datastore.read() {
+            Ok(map) => map
+                .iter()
+                .map(|(key, object)| SnapshotObject {
+                    key: key.into_bytes(),
+                    created_at_ms: object.created_at.timestamp_millis(),
+                    bytes: object.bytes.to_vec(),
+                })
+                .collect(),
+            Err(_) => return Err(Error::internal("read lock fail")),
+        };
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, borsh::to_vec(&objects)?).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_util::TryStreamExt;
+
+    /// The number and total size of objects held, as recorded in metrics.
+    fn stats(storage: &MemoryStorage) -> MemoryStats {
+        MemoryStats::of(&storage.datastore.read().unwrap())
+    }
 
     #[tokio::test]
     async fn test_mem_storage() {
@@ -74,13 +370,112 @@ mod tests {
 
         // Write, read, then delete some binary data.
         let input = Bytes::from("The quick brown fox jumped over the lazy dog");
-        let key = storage.write(input.clone()).await.unwrap();
+        let chunks = input.chunks(8).map(|c| Ok(Bytes::copy_from_slice(c)));
+        let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
         let output = storage.read(&key).await.unwrap();
         assert_eq!(output, input);
+
+        // Stream a range of bytes
+        let range = ByteRange::parse("bytes=-3", input.len() as u64).unwrap();
+        let stream = storage.read_stream(&key, range).await.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"dog");
+
+        // Read object metadata
+        let object = storage.stat(&key).await.unwrap();
+        assert_eq!(object.size, input.len() as u64);
+
+        // Read several objects as one stream
+        let part = storage
+            .write(stream::iter([Ok(Bytes::from("!"))]).boxed())
+            .await
+            .unwrap();
+        let parts = [key.clone(), part.clone()];
+        let chunks: Vec<Bytes> = storage.read_parts(&parts).try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), [&input[..], b"!"].concat());
+        storage.delete(&part).await.unwrap();
+
+        // List stored objects
+        let objects: Vec<StoredObject> = storage.list().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].storage_id, key);
         storage.delete(&key).await.unwrap();
 
         // Verify file is deleted
         let result = storage.read(&key).await;
+        assert!(matches!(result, Err(Error::NotFound { .. })));
+
+        // Empty streams are rejected
+        let result = storage.write(stream::empty().boxed()).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_mem_storage_limit() {
+        let bytes = |s: &'static str| stream::iter([Ok(Bytes::from(s))]).boxed();
+
+        // The oldest objects are evicted to make room
+        let storage = MemoryStorage::new().with_limit(10, WhenFull::Evict);
+        let first = storage.write(bytes("12345")).await.unwrap();
+        let second = storage.write(bytes("12345")).await.unwrap();
+        let third = storage.write(bytes("123")).await.unwrap();
+        assert!(storage.read(&first).await.is_err());
+        assert!(storage.read(&second).await.is_ok());
+        assert!(storage.read(&third).await.is_ok());
+        let stats = stats(&storage);
+        assert_eq!(
+            stats,
+            MemoryStats {
+                objects: 2,
+                bytes: 8
+            }
+        );
+
+        // Or writes are rejected once full
+        let storage = MemoryStorage::new().with_limit(10, WhenFull::Reject);
+        storage.write(bytes("12345")).await.unwrap();
+        let second = storage.write(bytes("12345")).await.unwrap();
+        let result = storage.write(bytes("123")).await;
+        assert!(matches!(result, Err(Error::QuotaExceeded { .. })));
+
+        // Replacing an object makes room for the replacement
+        storage.put(&second, bytes("54321")).await.unwrap();
+        assert_eq!(storage.read(&second).await.unwrap(), "54321");
+        assert!(storage.write(bytes("12345678901")).await.is_err());
+
+        // Writes that can't fit are aborted before the rest of the stream is read
+        let chunks = [
+            Ok(Bytes::from("1")),
+            Ok(Bytes::from("2")),
+            Err(Error::internal("read past the limit")),
+        ];
+        let result = storage.write(stream::iter(chunks).boxed()).await;
+        assert!(matches!(result, Err(Error::QuotaExceeded { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_mem_storage_snapshot() {
+        let path = std::env::temp_dir().join(format!("mem_snapshot_{}", Uuid::new_v4()));
+
+        // Objects are saved on flush, and restored into a new instance
+        let storage = MemoryStorage::new().with_snapshot(&path).unwrap();
+        let data = Bytes::from("The quick brown fox jumped over the lazy dog");
+        let key = storage
+            .write(stream::iter([Ok(data.clone())]).boxed())
+            .await
+            .unwrap();
+        storage.flush().await.unwrap();
+        let restored = MemoryStorage::new().with_snapshot(&path).unwrap();
+        assert_eq!(restored.read(&key).await.unwrap(), data);
+        assert_eq!(stats(&restored).objects, 1);
+
+        // Restored objects are held to the size limit
+        let limited = MemoryStorage::new()
+            .with_limit(10, WhenFull::Evict)
+            .with_snapshot(&path)
+            .unwrap();
+        assert_eq!(stats(&limited), MemoryStats::default());
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,199 @@
+use crate::{
+    domain::{ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
+    Result,
+};
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use metrics::{counter, histogram};
+use std::time::{Duration, Instant};
+
+// Metric names
+const DURATION: &str = "storage_operation_duration_seconds";
+const ERRORS: &str = "storage_operation_errors_total";
+const BYTES_READ: &str = "storage_bytes_read_total";
+const BYTES_WRITTEN: &str = "storage_bytes_written_total";
+
+/// Storage decorator that records metrics for another storage instance: latency histograms and
+/// error counts per operation, and the bytes read and written, all labelled by driver. Streamed
+/// reads are timed until the stream is done with, so latency covers the whole transfer.
+pub struct MeteredStorage {
+    inner: Box<dyn Storage>,
+    driver: &'static str,
+}
+
+impl MeteredStorage {
+    /// Create a metered wrapper around another storage instance.
+    pub fn new(inner: Box<dyn Storage>, driver: &'static str) -> Self {
+        Self { inner, driver }
+    }
+}
+
+/// Times an operation, recording its latency when dropped.
+struct Timer {
+    operation: &'static str,
+    driver: &'static str,
+    start: Instant,
+}
+
+impl Timer {
+    /// Start timing an operation.
+    fn start(operation: &'static str, driver: &'static str) -> Self {
+        Self {
+            operation,
+            driver,
+            start: Instant::now(),
+        }
+    }
+
+    /// Record the outcome of the operation, counting it if it failed.
+    fn finish<T>(self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.fail();
+        }
+        result
+    }
+
+    /// Count an error for the operation.
+    fn fail(&self) {
+        let labels = [("operation", self.operation), ("driver", self.driver)];
+        counter!(ERRORS, &labels).increment(1);
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let labels = [("operation", self.operation), ("driver", self.driver)];
+        histogram!(DURATION, &labels).record(self.start.elapsed());
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MeteredStorage {
+    /// Read object
+    async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+        let timer = Timer::start("read", self.driver);
+        let result = self.inner.read(storage_id).await;
+        if let Ok(bytes) = &result {
+            counter!(BYTES_READ, "driver" => self.driver).increment(bytes.len() as u64);
+        }
+        timer.finish(result)
+    }
+
+    /// Stream object, counting bytes as they are read
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let timer = Timer::start("read", self.driver);
+        let stream = match self.inner.read_stream(storage_id, range).await {
+            Ok(stream) => stream,
+            Err(err) => return timer.finish(Err(err)),
+        };
+        let driver = self.driver;
+        let stream = stream.map(move |chunk| {
+            match &chunk {
+                Ok(bytes) => counter!(BYTES_READ, "driver" => driver).increment(bytes.len() as u64),
+                Err(_) => timer.fail(),
+            }
+            chunk
+        });
+        Ok(stream.boxed())
+    }
+
+    /// Write object, counting bytes as they are written
+    async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()> {
+        let timer = Timer::start("write", self.driver);
+        let driver = self.driver;
+        let stream = stream.map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                counter!(BYTES_WRITTEN, "driver" => driver).increment(bytes.len() as u64);
+            }
+            chunk
+        });
+        let result = self.inner.put(storage_id, stream.boxed()).await;
+        timer.finish(result)
+    }
+
+    /// Delete object
+    async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+        let timer = Timer::start("delete", self.driver);
+        let result = self.inner.delete(storage_id).await;
+        timer.finish(result)
+    }
+
+    /// List objects
+    async fn list(&self) -> Result<ObjectStream> {
+        let timer = Timer::start("list", self.driver);
+        let result = self.inner.list().await;
+        timer.finish(result)
+    }
+
+    /// Read object metadata
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        let timer = Timer::start("stat", self.driver);
+        let result = self.inner.stat(storage_id).await;
+        timer.finish(result)
+    }
+
+    /// Presign uploads with the inner storage
+    async fn presign_write(&self, expires_in: Duration) -> Result<(StorageId, String)> {
+        self.inner.presign_write(expires_in).await
+    }
+
+    /// Presign downloads with the inner storage
+    async fn presign_read(&self, storage_id: &StorageId, expires_in: Duration) -> Result<String> {
+        self.inner.presign_read(storage_id, expires_in).await
+    }
+
+    /// Flush the inner storage
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::storage::mem::MemoryStorage;
+    use futures_util::{stream, TryStreamExt};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    #[test]
+    fn test_metered_storage() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let storage = MeteredStorage::new(Box::new(MemoryStorage::new()), "memory");
+
+                // Write, then read back in full and by stream
+                let data = Bytes::from("The quick brown fox jumped over the lazy dog");
+                let chunks = data.chunks(10).map(|c| Ok(Bytes::copy_from_slice(c)));
+                let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
+                assert_eq!(storage.read(&key).await.unwrap(), data);
+                let stream = storage.read_stream(&key, None).await.unwrap();
+                let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+                assert_eq!(chunks.concat(), data);
+
+                // Delete, then fail to read
+                storage.delete(&key).await.unwrap();
+                assert!(storage.read(&key).await.is_err());
+            })
+        });
+
+        let rendered = handle.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(&r#"storage_bytes_written_total{driver="memory"} 44"#));
+        assert!(lines.contains(&r#"storage_bytes_read_total{driver="memory"} 88"#));
+        assert!(lines
+            .contains(&r#"storage_operation_errors_total{operation="read",driver="memory"} 1"#));
+        assert!(lines.iter().any(|l| l.starts_with(
+            r#"storage_operation_duration_seconds_count{operation="delete",driver="memory"} 1"#
+        )));
+    }
+}
@@ -1,16 +1,27 @@
 use crate::{
     config::Config,
-    domain::{Storage, StorageId},
+    domain::{peek, ByteRange, ByteStream, ObjectStream, Storage, StorageId, StoredObject},
     Error, Result,
 };
 
+use axum::http::Method;
 use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt, TryStreamExt};
 use minio::s3::{
-    error::Error as MinioError, segmented_bytes::SegmentedBytes, types::S3Api, Client,
+    builders::{ObjectContent, Size, MIN_PART_SIZE},
+    error::{Error as MinioError, ErrorCode},
+    types::{ListEntry, S3Api, ToStream},
+    Client,
 };
+use std::{io, time::Duration};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
-/// MinIO object storage driver.
+// The number of chunks buffered between an upload stream and the MinIO client.
+const CHANNEL_SIZE: usize = 16;
+
+/// MinIO object storage driver, also used for generic S3-compatible backends.
 pub struct MinioStorage {
     bucket: String,
     client: Client,
@@ -24,6 +35,23 @@ impl MinioStorage {
             client: config.create_minio_client(),
         }
     }
+
+    /// Create a storage instance for a bucket using a preconfigured client.
+    pub fn with_client(bucket: String, client: Client) -> Self {
+        Self { bucket, client }
+    }
+
+    /// Create a presigned url for an object key.
+    async fn presign(&self, uuid: &Uuid, method: Method, expires_in: Duration) -> Result<String> {
+        let expiry = u32::try_from(expires_in.as_secs()).unwrap_or(u32::MAX);
+        let presigned = self
+            .client
+            .get_presigned_object_url(&self.bucket, uuid.to_string(), method)
+            .expiry_seconds(expiry)
+            .send()
+            .await?;
+        Ok(presigned.url)
+    }
 }
 
 #[async_trait::async_trait]
@@ -34,19 +62,63 @@ impl Storage for MinioStorage {
             .client
             .get_object(&self.bucket, uuid.to_string())
             .send()
-            .await?;
+            .await
+            .map_err(|err| object_error(uuid, err))?;
         let segmented_bytes = get_object.content.to_segmented_bytes().await?;
         Ok(segmented_bytes.to_bytes())
     }
 
-    /// Write object
-    async fn write(&self, bytes: Bytes) -> Result<StorageId> {
-        let uuid = Uuid::new_v4();
-        self.client
-            .put_object(&self.bucket, uuid.to_string(), SegmentedBytes::from(bytes))
+    /// Stream object, requesting only a range of bytes if given
+    async fn read_stream(
+        &self,
+        StorageId(uuid): &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let get_object = self
+            .client
+            .get_object(&self.bucket, uuid.to_string())
+            .offset(range.map(|r| r.offset))
+            .length(range.map(|r| r.length))
             .send()
-            .await?;
-        Ok(StorageId(uuid))
+            .await
+            .map_err(|err| object_error(uuid, err))?;
+        let (stream, _) = get_object.content.to_stream().await?;
+        Ok(stream.map_err(Error::from).boxed())
+    }
+
+    /// Write object, rejecting empty streams like the other drivers
+    async fn put(&self, StorageId(uuid): &StorageId, mut stream: ByteStream<'_>) -> Result<()> {
+        let head = peek(&mut stream, 1).await?;
+        if head.is_empty() {
+            return Err(Error::invalid_args("empty file"));
+        }
+        let mut stream = stream::once(future::ok(head.freeze())).chain(stream);
+
+        // The MinIO client requires a 'static stream, so forward chunks through a channel.
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let forward = async move {
+            while let Some(chunk) = stream.next().await {
+                let failed = chunk.is_err();
+                if tx.send(chunk.map_err(io::Error::other)).await.is_err() || failed {
+                    break;
+                }
+            }
+        };
+
+        // Upload in parts of unknown total size as chunks arrive.
+        let content = ObjectContent::new_from_stream(
+            stream::unfold(rx, |mut rx| async { rx.recv().await.map(|c| (c, rx)) }),
+            Size::Unknown,
+        );
+        let put = self
+            .client
+            .put_object_content(&self.bucket, uuid.to_string(), content)
+            .part_size(Size::Known(MIN_PART_SIZE))
+            .send();
+
+        let (_, result) = tokio::join!(forward, put);
+        result?;
+        Ok(())
     }
 
     /// Delete object
@@ -57,11 +129,159 @@ impl Storage for MinioStorage {
             .await?;
         Ok(())
     }
+
+    /// List objects in the bucket, skipping keys that aren't storage ids
+    async fn list(&self) -> Result<ObjectStream> {
+        // Objects are stored under flat keys, so no recursive listing is needed
+        let pages = self.client.list_objects(&self.bucket).to_stream().await;
+        let objects = pages
+            .map_err(Error::from)
+            .map_ok(|page| {
+                stream::iter(page.contents.into_iter().filter_map(stored_object).map(Ok))
+            })
+            .try_flatten();
+        Ok(objects.boxed())
+    }
+
+    /// Read object metadata with a HEAD request
+    async fn stat(&self, StorageId(uuid): &StorageId) -> Result<StoredObject> {
+        let stat = self
+            .client
+            .stat_object(&self.bucket, uuid.to_string())
+            .send()
+            .await
+            .map_err(|err| object_error(uuid, err))?;
+        Ok(StoredObject {
+            storage_id: StorageId(*uuid),
+            size: stat.size,
+            modified_at: stat.last_modified.unwrap_or_else(Utc::now),
+        })
+    }
+
+    /// Presign a PUT url for a new object
+    async fn presign_write(&self, expires_in: Duration) -> Result<(StorageId, String)> {
+        let uuid = Uuid::new_v4();
+        let url = self.presign(&uuid, Method::PUT, expires_in).await?;
+        Ok((StorageId(uuid), url))
+    }
+
+    /// Presign a GET url for an existing object
+    async fn presign_read(
+        &self,
+        StorageId(uuid): &StorageId,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.presign(uuid, Method::GET, expires_in).await
+    }
+}
+
+/// Map a bucket list entry to object metadata, if it is named by a storage key.
+fn stored_object(entry: ListEntry) -> Option<StoredObject> {
+    if entry.is_prefix || entry.is_delete_marker {
+        return None;
+    }
+    let key = Uuid::parse_str(&entry.name).ok()?;
+    Some(StoredObject {
+        storage_id: StorageId(key),
+        size: entry.size.unwrap_or_default(),
+        modified_at: entry.last_modified.unwrap_or_else(Utc::now),
+    })
 }
 
 // Map MinIO errors as internal errors for this project.
+/// Map an error for a request on an object, reporting missing objects as not found.
+fn object_error(uuid: &Uuid, err: MinioError) -> Error {
+    match err {
+        MinioError::S3Error(res) if res.code == ErrorCode::NoSuchKey => {
+            Error::not_found(format!("object not found: {uuid}"))
+        }
+        err => Error::from(err),
+    }
+}
+
 impl From<MinioError> for Error {
     fn from(err: MinioError) -> Self {
         Error::internal(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers_modules::minio::MinIO;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up a MinIO test container as a generic path-style S3 backend
+        let container = MinIO::default().start().await.unwrap();
+        let port = container.get_host_port_ipv4(9000).await.unwrap();
+        let config = Config {
+            listen_addr: "0.0.0.0:0".into(),
+            db_max_connections: 1,
+            db_url: String::new(),
+            db_schema: "public".into(),
+            storage_type: "s3".into(),
+            storage_bucket: "attachments".into(),
+            storage_minio_base_url: None,
+            storage_minio_access_key: None,
+            storage_minio_secret_key: None,
+            storage_s3_endpoint: Some(format!("http://127.0.0.1:{port}")),
+            storage_s3_region: Some("us-east-1".into()),
+            storage_s3_access_key: Some("minioadmin".into()),
+            storage_s3_secret_key: Some("minioadmin".into()),
+            storage_s3_session_token: None,
+            storage_s3_path_style: true,
+            storage_file_sync: None,
+            storage_memory_max_bytes: None,
+            storage_memory_when_full: None,
+            storage_memory_snapshot: None,
+            storage_replica_prefix: None,
+            storage_encryption_key: None,
+            storage_compression: None,
+            storage_cache_size: None,
+            storage_cache_dir: None,
+            storage_cache_dir_size: 0,
+            story_quota_bytes: None,
+            story_quota_files: None,
+            global_quota_bytes: None,
+            global_quota_files: None,
+            upload_allowed_types: Vec::new(),
+            scanner_type: None,
+            scanner_address: String::new(),
+        };
+        let client = config.create_s3_client();
+        client.create_bucket("attachments").send().await.unwrap();
+        let storage = MinioStorage::with_client(config.storage_bucket.clone(), client);
+
+        // Write, read, list, then delete some binary data.
+        let data = Bytes::from("The quick brown fox jumped over the lazy dog");
+        let chunks = data.chunks(8).map(|c| Ok(Bytes::copy_from_slice(c)));
+        let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
+        assert_eq!(storage.read(&key).await.unwrap(), data);
+
+        let range = ByteRange::parse("bytes=4-8", data.len() as u64).unwrap();
+        let stream = storage.read_stream(&key, range).await.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"quick");
+
+        let objects: Vec<StoredObject> = storage.list().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].storage_id, key);
+        assert_eq!(storage.stat(&key).await.unwrap().size, data.len() as u64);
+
+        storage.delete(&key).await.unwrap();
+        let missing = storage.read(&key).await;
+        assert!(matches!(missing, Err(Error::NotFound { .. })));
+        let missing = storage.read_stream(&key, None).await;
+        assert!(matches!(missing, Err(Error::NotFound { .. })));
+        let missing = storage.stat(&key).await;
+        assert!(matches!(missing, Err(Error::NotFound { .. })));
+
+        // Empty streams are rejected
+        let result = storage.write(stream::empty().boxed()).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,3 +1,35 @@
+use crate::domain::ByteStream;
+use bytes::Buf;
+use futures_util::{stream, StreamExt};
+
+pub mod cached;
+pub mod compressed;
+pub mod encrypted;
 pub mod fs;
 pub mod mem;
+pub mod metered;
 pub mod minio;
+pub mod replicated;
+
+/// Skip bytes from the front of a stream, then end it after `length` bytes.
+fn slice(stream: ByteStream<'static>, skip: u64, length: u64) -> ByteStream<'static> {
+    let state = Some((stream, skip, length));
+    stream::unfold(state, |state| async move {
+        let (mut stream, mut skip, length) = state.filter(|(_, _, length)| *length > 0)?;
+        loop {
+            let mut chunk = match stream.next().await? {
+                Ok(chunk) => chunk,
+                Err(err) => return Some((Err(err), None)),
+            };
+            let n = skip.min(chunk.len() as u64);
+            chunk.advance(n as usize);
+            skip -= n;
+            chunk.truncate(length.min(chunk.len() as u64) as usize);
+            if !chunk.is_empty() {
+                let length = length - chunk.len() as u64;
+                return Some((Ok(chunk), Some((stream, skip, length))));
+            }
+        }
+    })
+    .boxed()
+}
@@ -0,0 +1,558 @@
+use crate::{
+    domain::{
+        ByteRange, ByteStream, ObjectStream, Repair, RepairQueue, Storage, StorageId,
+        StorageRepair, StoredObject,
+    },
+    Error, Result,
+};
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use metrics::counter;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+// The number of chunks buffered for the secondary while writing to both.
+const TEE_BUFFER: usize = 16;
+
+// The max number of repairs applied per batch.
+const REPAIR_BATCH_SIZE: i64 = 100;
+
+// How long a leased repair is first hidden from other instances, and so the delay before a
+// failed repair is retried. The lease doubles with each attempt.
+const REPAIR_LEASE_SECS: i64 = 300;
+
+// The number of attempts before a failing repair is given up on.
+const REPAIR_MAX_ATTEMPTS: i32 = 8;
+
+// Metric names
+const DIVERGENCES: &str = "storage_replica_divergences_total";
+const FAILED_REPAIRS: &str = "storage_replica_failed_repairs_total";
+
+/// Storage that writes every object to a primary and a secondary instance, for disaster
+/// recovery. Reads come from the primary, falling back to the secondary. Failures on the
+/// secondary don't fail writes or deletes, but are logged and queued, along with objects only
+/// the secondary could read, for repair in the background. Objects uploaded with presigned urls
+/// go to the primary, and are copied to the secondary once the urls expire.
+#[derive(Clone)]
+pub struct ReplicatedStorage {
+    primary: Arc<dyn Storage>,
+    secondary: Arc<dyn Storage>,
+    replica: String,
+    repairs: Arc<dyn RepairQueue>,
+}
+
+impl ReplicatedStorage {
+    /// Create a replicated storage instance from a primary and a secondary instance, queueing
+    /// repairs under a label for the secondary.
+    pub fn new(
+        primary: Box<dyn Storage>,
+        secondary: Box<dyn Storage>,
+        replica: String,
+        repairs: Arc<dyn RepairQueue>,
+    ) -> Self {
+        Self {
+            primary: Arc::from(primary),
+            secondary: Arc::from(secondary),
+            replica,
+            repairs,
+        }
+    }
+
+    /// Repair queued divergence in the background, at an interval.
+    pub fn spawn_repairs(&self, interval: Duration) -> JoinHandle<()> {
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match storage.repair().await {
+                    Ok(0) => {}
+                    Ok(repaired) => {
+                        tracing::info!("repaired {} diverged storage replicas", repaired)
+                    }
+                    Err(err) => tracing::error!("unable to repair storage replicas: {}", err),
+                }
+            }
+        })
+    }
+
+    /// Repair due divergence between the replicas. Failed repairs stay queued, and are retried
+    /// once their lease expires, until they are given up on and kept as failed. Returns the
+    /// number of objects repaired.
+    pub async fn repair(&self) -> Result<usize> {
+        let lease = chrono::Duration::seconds(REPAIR_LEASE_SECS);
+        let mut repaired = 0;
+        loop {
+            let repairs = self
+                .repairs
+                .lease_repairs(&self.replica, REPAIR_BATCH_SIZE, lease)
+                .await?;
+            let leased = repairs.len() as i64;
+            for repair in repairs {
+                let StorageRepair { storage_id, .. } = &repair;
+                match self.apply(storage_id, repair.repair).await {
+                    Ok(()) => {
+                        self.repairs.complete_repair(&self.replica, &repair).await?;
+                        repaired += 1;
+                    }
+                    Err(err) => self.failed(&repair, &err).await?,
+                }
+            }
+            if leased < REPAIR_BATCH_SIZE {
+                return Ok(repaired);
+            }
+        }
+    }
+
+    /// Record a failed repair, giving up on it after too many attempts.
+    async fn failed(&self, repair: &StorageRepair, err: &Error) -> Result<()> {
+        let StorageRepair { storage_id, .. } = repair;
+        let give_up = repair.attempts >= REPAIR_MAX_ATTEMPTS;
+        if give_up {
+            tracing::error!(
+                "gave up trying to {} for {} after {} attempts: {}",
+                repair.repair,
+                storage_id,
+                repair.attempts,
+                err
+            );
+            counter!(FAILED_REPAIRS, "repair" => repair.repair.to_string()).increment(1);
+        } else {
+            tracing::warn!("unable to {} for {}: {}", repair.repair, storage_id, err);
+        }
+        self.repairs
+            .fail_repair(&self.replica, repair, &err.to_string(), give_up)
+            .await
+    }
+
+    /// Apply a repair to an object. Objects missing from the primary are only told apart by
+    /// metadata, as not all drivers report missing objects on read.
+    async fn apply(&self, storage_id: &StorageId, repair: Repair) -> Result<()> {
+        match repair {
+            Repair::CopyToSecondary => match self.primary.stat(storage_id).await {
+                Ok(_) => {
+                    let stream = self.primary.read_stream(storage_id, None).await?;
+                    self.secondary.put(storage_id, stream).await
+                }
+                Err(Error::NotFound { .. }) => self.secondary.delete(storage_id).await,
+                Err(err) => Err(err),
+            },
+            Repair::CopyToPrimary => {
+                let stream = self.secondary.read_stream(storage_id, None).await?;
+                self.primary.put(storage_id, stream).await
+            }
+            Repair::DeleteSecondary => self.secondary.delete(storage_id).await,
+        }
+    }
+
+    /// Log divergence between the replicas, and queue it for repair.
+    async fn diverged(&self, storage_id: &StorageId, repair: Repair, err: &Error) {
+        tracing::warn!(
+            "storage replicas diverged for {}, queued to {}: {}",
+            storage_id,
+            repair,
+            err
+        );
+        counter!(DIVERGENCES, "repair" => repair.to_string()).increment(1);
+        self.queue(storage_id, repair, chrono::Duration::zero())
+            .await;
+    }
+
+    /// Queue a repair, logging failures.
+    async fn queue(&self, storage_id: &StorageId, repair: Repair, delay: chrono::Duration) {
+        let queued = self
+            .repairs
+            .queue_repair(&self.replica, storage_id, repair, delay)
+            .await;
+        if let Err(err) = queued {
+            tracing::error!("unable to queue {} for {}: {}", repair, storage_id, err);
+        }
+    }
+
+    /// Drop any queued repair once both replicas are written or deleted.
+    async fn converged(&self, storage_id: &StorageId) {
+        if let Err(err) = self.repairs.cancel_repair(&self.replica, storage_id).await {
+            tracing::error!("unable to cancel repair for {}: {}", storage_id, err);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for ReplicatedStorage {
+    /// Read object from the primary, falling back to the secondary
+    async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+        let err = match self.primary.read(storage_id).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) => err,
+        };
+        match self.secondary.read(storage_id).await {
+            Ok(bytes) => {
+                self.diverged(storage_id, Repair::CopyToPrimary, &err).await;
+                Ok(bytes)
+            }
+            Err(_) => Err(err),
+        }
+    }
+
+    /// Stream object from the primary, falling back to the secondary. Streams that fail once
+    /// started aren't retried.
+    async fn read_stream(
+        &self,
+        storage_id: &StorageId,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream<'static>> {
+        let err = match self.primary.read_stream(storage_id, range).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => err,
+        };
+        match self.secondary.read_stream(storage_id, range).await {
+            Ok(stream) => {
+                self.diverged(storage_id, Repair::CopyToPrimary, &err).await;
+                Ok(stream)
+            }
+            Err(_) => Err(err),
+        }
+    }
+
+    /// Write object to both replicas at once. Only a failure on the primary fails the write.
+    async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(TEE_BUFFER);
+        // The copy ends once the primary is done with the stream, and drops the sender
+        let tee = stream.then(move |chunk| {
+            let tx = tx.clone();
+            async move {
+                let copy = match &chunk {
+                    Ok(bytes) => Ok(bytes.clone()),
+                    Err(err) => Err(Error::internal(err.to_string())),
+                };
+                // The secondary may have given up already, which doesn't matter here
+                let _ = tx.send(copy).await;
+                chunk
+            }
+        });
+        let copy = stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed();
+        let (result, copied) = tokio::join!(
+            self.primary.put(storage_id, tee.boxed()),
+            self.secondary.put(storage_id, copy)
+        );
+        match (&result, copied) {
+            // A copy of a failed write may be incomplete
+            (Err(_), Ok(())) => {
+                if let Err(err) = self.secondary.delete(storage_id).await {
+                    self.diverged(storage_id, Repair::DeleteSecondary, &err)
+                        .await;
+                }
+            }
+            (Ok(()), Err(err)) => {
+                self.diverged(storage_id, Repair::CopyToSecondary, &err)
+                    .await
+            }
+            (Ok(()), Ok(())) => self.converged(storage_id).await,
+            _ => {}
+        }
+        result
+    }
+
+    /// Delete object from both replicas. Only a failure on the primary fails the delete.
+    async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+        let (result, deleted) = tokio::join!(
+            self.primary.delete(storage_id),
+            self.secondary.delete(storage_id)
+        );
+        match deleted {
+            Ok(()) => self.converged(storage_id).await,
+            Err(err) => {
+                self.diverged(storage_id, Repair::DeleteSecondary, &err)
+                    .await
+            }
+        }
+        result
+    }
+
+    /// List objects in the primary
+    async fn list(&self) -> Result<ObjectStream> {
+        self.primary.list().await
+    }
+
+    /// Read object metadata from the primary
+    async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+        self.primary.stat(storage_id).await
+    }
+
+    /// Presign uploads with the primary, queueing a copy to the secondary for once the url
+    /// expires
+    async fn presign_write(&self, expires_in: Duration) -> Result<(StorageId, String)> {
+        let (storage_id, url) = self.primary.presign_write(expires_in).await?;
+        let delay = chrono::Duration::seconds(expires_in.as_secs() as i64);
+        self.repairs
+            .queue_repair(&self.replica, &storage_id, Repair::CopyToSecondary, delay)
+            .await?;
+        Ok((storage_id, url))
+    }
+
+    /// Presign downloads with the primary
+    async fn presign_read(&self, storage_id: &StorageId, expires_in: Duration) -> Result<String> {
+        self.primary.presign_read(storage_id, expires_in).await
+    }
+
+    /// Flush both replicas
+    async fn flush(&self) -> Result<()> {
+        let (result, flushed) = tokio::join!(self.primary.flush(), self.secondary.flush());
+        result.and(flushed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::storage::mem::MemoryStorage;
+    use chrono::{DateTime, Utc};
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+
+    /// A repair held in memory, when it is due, and whether it was given up on.
+    type QueuedRepair = (StorageRepair, DateTime<Utc>, bool);
+
+    /// Repair queue held in memory, where leased repairs stay due.
+    #[derive(Default)]
+    struct MemoryRepairQueue(Mutex<HashMap<StorageId, QueuedRepair>>);
+
+    #[async_trait::async_trait]
+    impl RepairQueue for MemoryRepairQueue {
+        async fn queue_repair(
+            &self,
+            _replica: &str,
+            storage_id: &StorageId,
+            repair: Repair,
+            delay: chrono::Duration,
+        ) -> Result<()> {
+            let queued = StorageRepair {
+                storage_id: storage_id.clone(),
+                repair,
+                attempts: 0,
+                queued_at: Utc::now(),
+            };
+            let due = queued.queued_at + delay;
+            self.0
+                .lock()
+                .unwrap()
+                .insert(storage_id.clone(), (queued, due, false));
+            Ok(())
+        }
+
+        async fn cancel_repair(&self, _replica: &str, storage_id: &StorageId) -> Result<()> {
+            self.0.lock().unwrap().remove(storage_id);
+            Ok(())
+        }
+
+        async fn lease_repairs(
+            &self,
+            _replica: &str,
+            _limit: i64,
+            _lease: chrono::Duration,
+        ) -> Result<Vec<StorageRepair>> {
+            let mut repairs = self.0.lock().unwrap();
+            let now = Utc::now();
+            let due = repairs
+                .values_mut()
+                .filter(|(_, due, failed)| *due <= now && !failed);
+            Ok(due
+                .map(|(repair, _, _)| {
+                    repair.attempts += 1;
+                    repair.clone()
+                })
+                .collect())
+        }
+
+        async fn complete_repair(&self, _replica: &str, repair: &StorageRepair) -> Result<()> {
+            let mut repairs = self.0.lock().unwrap();
+            if repairs
+                .get(&repair.storage_id)
+                .is_some_and(|(r, _, _)| r == repair)
+            {
+                repairs.remove(&repair.storage_id);
+            }
+            Ok(())
+        }
+
+        async fn fail_repair(
+            &self,
+            _replica: &str,
+            repair: &StorageRepair,
+            _error: &str,
+            give_up: bool,
+        ) -> Result<()> {
+            let mut repairs = self.0.lock().unwrap();
+            if let Some((r, _, failed)) = repairs.get_mut(&repair.storage_id) {
+                *failed = r == repair && give_up;
+            }
+            Ok(())
+        }
+    }
+
+    /// Memory storage that can be made to fail every operation, or to fail reads of missing
+    /// objects with an opaque error, as some drivers do.
+    #[derive(Clone, Default)]
+    struct FlakyStorage {
+        memory: Arc<MemoryStorage>,
+        failing: Arc<AtomicBool>,
+        opaque_reads: Arc<AtomicBool>,
+    }
+
+    impl FlakyStorage {
+        fn check(&self) -> Result<()> {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(Error::internal("storage is down")),
+                false => Ok(()),
+            }
+        }
+
+        fn check_read<T>(&self, result: Result<T>) -> Result<T> {
+            match result {
+                Err(Error::NotFound { .. }) if self.opaque_reads.load(Ordering::SeqCst) => {
+                    Err(Error::internal("read failed"))
+                }
+                result => result,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for FlakyStorage {
+        async fn read(&self, storage_id: &StorageId) -> Result<Bytes> {
+            self.check()?;
+            self.check_read(self.memory.read(storage_id).await)
+        }
+
+        async fn read_stream(
+            &self,
+            storage_id: &StorageId,
+            range: Option<ByteRange>,
+        ) -> Result<ByteStream<'static>> {
+            self.check()?;
+            self.check_read(self.memory.read_stream(storage_id, range).await)
+        }
+
+        async fn put(&self, storage_id: &StorageId, stream: ByteStream<'_>) -> Result<()> {
+            self.check()?;
+            self.memory.put(storage_id, stream).await
+        }
+
+        async fn delete(&self, storage_id: &StorageId) -> Result<()> {
+            self.check()?;
+            self.memory.delete(storage_id).await
+        }
+
+        async fn list(&self) -> Result<ObjectStream> {
+            self.check()?;
+            self.memory.list().await
+        }
+
+        async fn stat(&self, storage_id: &StorageId) -> Result<StoredObject> {
+            self.check()?;
+            self.memory.stat(storage_id).await
+        }
+
+        async fn presign_write(&self, _expires_in: Duration) -> Result<(StorageId, String)> {
+            self.check()?;
+            let storage_id = StorageId(uuid::Uuid::new_v4());
+            let url = format!("memory://{storage_id}");
+            Ok((storage_id, url))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_storage() {
+        let primary = FlakyStorage::default();
+        let secondary = FlakyStorage::default();
+        let storage = ReplicatedStorage::new(
+            Box::new(primary.clone()),
+            Box::new(secondary.clone()),
+            "secondary".to_string(),
+            Arc::new(MemoryRepairQueue::default()),
+        );
+
+        // Writes go to both replicas
+        let data = Bytes::from("The quick brown fox jumped over the lazy dog");
+        let chunks = data.chunks(10).map(|c| Ok(Bytes::copy_from_slice(c)));
+        let key = storage.write(stream::iter(chunks).boxed()).await.unwrap();
+        assert_eq!(primary.read(&key).await.unwrap(), data);
+        assert_eq!(secondary.read(&key).await.unwrap(), data);
+
+        // Reads fall back to the secondary, and the primary is repaired from it
+        primary.memory.delete(&key).await.unwrap();
+        assert_eq!(storage.read(&key).await.unwrap(), data);
+        assert_eq!(storage.repair().await.unwrap(), 1);
+        assert_eq!(primary.read(&key).await.unwrap(), data);
+
+        // Writes survive a failing secondary, which is repaired once back
+        secondary.failing.store(true, Ordering::SeqCst);
+        let other = storage
+            .write(stream::iter([Ok(data.clone())]).boxed())
+            .await;
+        let other = other.unwrap();
+        assert_eq!(storage.repair().await.unwrap(), 0);
+        secondary.failing.store(false, Ordering::SeqCst);
+        assert!(secondary.read(&other).await.is_err());
+        assert_eq!(storage.repair().await.unwrap(), 1);
+        assert_eq!(secondary.read(&other).await.unwrap(), data);
+
+        // Deletes survive a failing secondary, which is repaired once back
+        secondary.failing.store(true, Ordering::SeqCst);
+        storage.delete(&key).await.unwrap();
+        secondary.failing.store(false, Ordering::SeqCst);
+        assert!(secondary.read(&key).await.is_ok());
+        assert_eq!(storage.repair().await.unwrap(), 1);
+        assert!(secondary.read(&key).await.is_err());
+
+        // Presigned uploads are copied to the secondary once the url expires
+        let (presigned, _) = storage.presign_write(Duration::ZERO).await.unwrap();
+        let upload = stream::iter([Ok(data.clone())]).boxed();
+        primary.memory.put(&presigned, upload).await.unwrap();
+        assert_eq!(storage.repair().await.unwrap(), 1);
+        assert_eq!(secondary.read(&presigned).await.unwrap(), data);
+        let (pending, _) = storage
+            .presign_write(Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(storage.repair().await.unwrap(), 0);
+        storage.delete(&pending).await.unwrap();
+
+        // Presigned urls that expire unused are repaired, even where reads of missing objects
+        // fail with other errors
+        primary.opaque_reads.store(true, Ordering::SeqCst);
+        let (unused, _) = storage.presign_write(Duration::ZERO).await.unwrap();
+        assert_eq!(storage.repair().await.unwrap(), 1);
+        assert!(secondary.read(&unused).await.is_err());
+        primary.opaque_reads.store(false, Ordering::SeqCst);
+
+        // Repairs that keep failing are given up on
+        secondary.failing.store(true, Ordering::SeqCst);
+        let stuck = storage
+            .write(stream::iter([Ok(data.clone())]).boxed())
+            .await
+            .unwrap();
+        for _ in 0..REPAIR_MAX_ATTEMPTS {
+            assert_eq!(storage.repair().await.unwrap(), 0);
+        }
+        let lease = chrono::Duration::zero();
+        let queued = storage.repairs.lease_repairs("secondary", 10, lease).await;
+        assert!(queued.unwrap().is_empty());
+        secondary.failing.store(false, Ordering::SeqCst);
+        assert_eq!(storage.repair().await.unwrap(), 0);
+        assert!(secondary.read(&stuck).await.is_err());
+
+        // Writes fail with the primary, leaving no copy behind
+        primary.failing.store(true, Ordering::SeqCst);
+        let failed = StorageId(uuid::Uuid::new_v4());
+        let result = storage.put(&failed, stream::iter([Ok(data)]).boxed()).await;
+        assert!(result.is_err());
+        assert!(secondary.read(&failed).await.is_err());
+    }
+}
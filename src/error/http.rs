@@ -1,6 +1,6 @@
 use super::Error;
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -18,6 +18,15 @@ impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = http_status_code(&self);
         let errors = http_errors(&self);
+        if let Error::RangeNotSatisfiable { size } = self {
+            let content_range = format!("bytes */{size}");
+            return (
+                status,
+                [(header::CONTENT_RANGE, content_range)],
+                Json(errors),
+            )
+                .into_response();
+        }
         (status, Json(errors)).into_response()
     }
 }
@@ -37,8 +46,14 @@ impl From<Error> for StatusCode {
 fn http_status_code(err: &Error) -> StatusCode {
     match err {
         Error::NotFound { .. } => StatusCode::NOT_FOUND,
+        Error::Conflict { .. } => StatusCode::CONFLICT,
         Error::InvalidArgs { .. } => StatusCode::BAD_REQUEST,
         Error::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        Error::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::Locked { .. } => StatusCode::LOCKED,
+        Error::Forbidden { .. } => StatusCode::FORBIDDEN,
+        Error::UnsupportedType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Error::RangeNotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
     }
 }
 
@@ -47,10 +62,16 @@ fn http_errors(err: &Error) -> Errors {
     let errors = match err {
         Error::InvalidArgs { messages } => messages.to_owned(),
         Error::NotFound { message } => vec![message.to_owned()],
+        Error::Conflict { message } => vec![message.to_owned()],
+        Error::QuotaExceeded { message } => vec![message.to_owned()],
+        Error::Locked { message } => vec![message.to_owned()],
+        Error::Forbidden { message } => vec![message.to_owned()],
+        Error::UnsupportedType { message } => vec![message.to_owned()],
         Error::Internal { message } => {
             tracing::error!("internal error: {}", message);
             vec![message.to_owned()]
         }
+        Error::RangeNotSatisfiable { .. } => vec![err.to_string()],
     };
     Errors { errors }
 }
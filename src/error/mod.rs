@@ -12,8 +12,20 @@ pub enum Error {
     InvalidArgs { messages: Vec<String> },
     #[error("internal error: {message}")]
     Internal { message: String },
+    #[error("conflict: {message}")]
+    Conflict { message: String },
     #[error("not found error: {message}")]
     NotFound { message: String },
+    #[error("quota exceeded: {message}")]
+    QuotaExceeded { message: String },
+    #[error("locked: {message}")]
+    Locked { message: String },
+    #[error("forbidden: {message}")]
+    Forbidden { message: String },
+    #[error("unsupported content type: {message}")]
+    UnsupportedType { message: String },
+    #[error("range not satisfiable: size {size}")]
+    RangeNotSatisfiable { size: u64 },
 }
 
 // Error helpers
@@ -22,10 +34,30 @@ impl Error {
         Error::Internal { message: s.into() }
     }
 
+    pub fn conflict(s: impl Into<String>) -> Self {
+        Error::Conflict { message: s.into() }
+    }
+
     pub fn not_found(s: impl Into<String>) -> Self {
         Error::NotFound { message: s.into() }
     }
 
+    pub fn quota_exceeded(s: impl Into<String>) -> Self {
+        Error::QuotaExceeded { message: s.into() }
+    }
+
+    pub fn locked(s: impl Into<String>) -> Self {
+        Error::Locked { message: s.into() }
+    }
+
+    pub fn forbidden(s: impl Into<String>) -> Self {
+        Error::Forbidden { message: s.into() }
+    }
+
+    pub fn unsupported_type(s: impl Into<String>) -> Self {
+        Error::UnsupportedType { message: s.into() }
+    }
+
     pub fn invalid_args(s: impl Into<String>) -> Self {
         Error::InvalidArgs {
             messages: vec![s.into()],
@@ -39,6 +71,12 @@ impl From<base64::DecodeError> for Error {
     }
 }
 
+impl From<async_zip::error::ZipError> for Error {
+    fn from(err: async_zip::error::ZipError) -> Self {
+        Error::internal(err.to_string())
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::internal(err.to_string())
@@ -28,6 +28,9 @@ pub mod error;
 /// Postgres database logic
 pub mod repo;
 
+/// Background jobs
+pub mod worker;
+
 /// Project level error type
 pub use error::Error;
 
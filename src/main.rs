@@ -7,6 +7,7 @@ use sqlx_todos::{
     api::{Api, Ctx},
     config::Config,
     repo::Repo,
+    worker::{DeletionWorker, HashWorker, ScanWorker},
 };
 use std::{error::Error, sync::Arc};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -31,18 +32,71 @@ async fn main() -> Result<(), Box<dyn Error>> {
     tracing::debug!("Running migrations");
     MIGRATOR.run(&pool).await?;
 
-    // Set up storage and repo
-    let storage = config.load_storage();
-    let repo = Repo::new(Arc::new(pool));
+    // Record metrics, including storage latency, errors and bytes transferred
+    let metrics = config.install_metrics();
+
+    // Set up repo and storage
+    let repo = Arc::new(Repo::new(Arc::new(pool)));
+    let storage = config.load_storage(&repo);
+
+    // Set up API context
+    let ctx = Ctx::new(Arc::new(storage), repo)
+        .with_quotas(config.quotas())
+        .with_allowed_types(config.allowed_types())
+        .with_scanner(config.load_scanner())
+        .with_metrics(metrics);
+    let ctx = Arc::new(ctx);
+
+    // Start retrying failed storage deletions in the background
+    DeletionWorker::new(Arc::clone(&ctx)).spawn();
+
+    // Start scanning files left waiting for a malware scan in the background
+    ScanWorker::new(Arc::clone(&ctx)).spawn();
+
+    // Start hashing completed presigned uploads in the background
+    HashWorker::new(Arc::clone(&ctx)).spawn();
 
     // Set up API
-    let ctx = Ctx::new(Arc::new(storage), Arc::new(repo));
-    let service = Api::new(Arc::new(ctx)).mk_service();
+    let service = Api::new(Arc::clone(&ctx)).mk_service();
 
     // Start server
     tracing::info!("Server listening on {}", config.listen_addr);
     let listener = config.tcp_listener().await;
-    axum::serve(listener, service).await?;
+    axum::serve(listener, service)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    // Persist storage state held in memory, e.g. a memory storage snapshot
+    tracing::info!("Server shutting down");
+    ctx.storage.flush().await?;
 
     Ok(())
 }
+
+/// Wait for ctrl-c or, on unix, a terminate signal.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("unable to listen for ctrl-c: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("unable to listen for terminate signals: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,62 @@
+use dotenvy::dotenv;
+use sqlx_todos::{action::storage::MigrateStorage, api::Ctx, config::Config, repo::Repo};
+use std::{env, error::Error, sync::Arc};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+// Destination storage settings are read from env vars with this prefix by default.
+const DEFAULT_PREFIX: &str = "DEST_";
+
+/// Copy stored contents for all story files from the configured storage to a destination
+/// storage, configured with prefixed env vars (e.g. DEST_STORAGE_TYPE). Safe to re-run.
+///
+/// Usage: migrate-storage [--prefix <env var prefix>]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Load env vars and tracing subscriber
+    dotenv().ok();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    // Parse args
+    let mut prefix = DEFAULT_PREFIX.to_string();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => prefix = args.next().ok_or("prefix not set")?,
+            _ => return Err(format!("unknown argument: {arg}").into()),
+        }
+    }
+
+    // Check the source and destination storage differ
+    let config = Config::default();
+    let dest_config = Config::load_with_storage_prefix(&prefix);
+    let source_label = format!("{}:{}", config.storage_type, config.storage_bucket);
+    let label = format!(
+        "{}:{}",
+        dest_config.storage_type, dest_config.storage_bucket
+    );
+    if label == source_label {
+        return Err("source and destination storage must differ".into());
+    }
+
+    // Set up repo, then source and destination storage
+    let pool = config.db_pool_opts().connect(&config.db_url).await?;
+    let repo = Arc::new(Repo::new(Arc::new(pool)));
+    let storage = config.load_storage(&repo);
+    let destination = dest_config.load_storage(&repo);
+    let ctx = Ctx::new(Arc::new(storage), repo);
+
+    // Migrate contents
+    let report = MigrateStorage::execute(Arc::new(ctx), destination.as_ref(), &label).await?;
+    println!(
+        "migrated {} objects to {label}, {} failed",
+        report.migrated, report.failed
+    );
+    if report.failed > 0 {
+        return Err("some objects failed to migrate, re-run to retry them".into());
+    }
+
+    Ok(())
+}
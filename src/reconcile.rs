@@ -0,0 +1,56 @@
+use chrono::Duration;
+use dotenvy::dotenv;
+use sqlx_todos::{action::storage::ReconcileStorage, api::Ctx, config::Config, repo::Repo};
+use std::{env, error::Error, sync::Arc};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+// Only purge orphans older than a day by default.
+const DEFAULT_GRACE_PERIOD_SECS: i64 = 86400;
+
+/// Find and purge stored objects that aren't referenced by any story file.
+///
+/// Usage: reconcile [--dry-run] [--grace-period <seconds>]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Load env vars and tracing subscriber
+    dotenv().ok();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    // Parse args
+    let mut dry_run = false;
+    let mut grace_period_secs = DEFAULT_GRACE_PERIOD_SECS;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--grace-period" => {
+                grace_period_secs = args.next().ok_or("grace period not set")?.parse()?;
+            }
+            _ => return Err(format!("unknown argument: {arg}").into()),
+        }
+    }
+
+    // Set up repo and storage
+    let config = Config::default();
+    let pool = config.db_pool_opts().connect(&config.db_url).await?;
+    let repo = Arc::new(Repo::new(Arc::new(pool)));
+    let storage = config.load_storage(&repo);
+    let ctx = Ctx::new(Arc::new(storage), repo);
+
+    // Reconcile storage with file metadata
+    let grace_period = Duration::seconds(grace_period_secs);
+    let orphans = ReconcileStorage::execute(Arc::new(ctx), grace_period, dry_run).await?;
+    for object in &orphans {
+        println!(
+            "{}\t{}\t{}",
+            object.storage_id, object.size, object.modified_at
+        );
+    }
+    let verb = if dry_run { "found" } else { "deleted" };
+    println!("{verb} {} orphaned objects", orphans.len());
+
+    Ok(())
+}
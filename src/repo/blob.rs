@@ -0,0 +1,86 @@
+use super::{thumbnail, Repo};
+use crate::{domain::StorageId, Result};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Take a reference to a content-addressed blob, inserting it when the hash is new.
+/// Returns the storage id of the blob, which differs from the given one for duplicate content.
+pub(super) async fn acquire(
+    conn: &mut PgConnection,
+    sha256: &str,
+    storage_id: Uuid,
+    size: i64,
+) -> Result<Uuid> {
+    let record = sqlx::query!(
+        r#"INSERT INTO blobs (sha256, storage_id, size, ref_count) VALUES ($1, $2, $3, 1)
+        ON CONFLICT (sha256) DO UPDATE SET ref_count = blobs.ref_count + 1, updated_at = now()
+        RETURNING storage_id"#,
+        sha256,
+        storage_id,
+        size,
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(record.storage_id)
+}
+
+/// Release a reference to a content-addressed blob.
+/// Returns the storage id of the blob when its last reference is gone.
+pub(super) async fn release(conn: &mut PgConnection, sha256: &str) -> Result<Option<Uuid>> {
+    let record = sqlx::query!(
+        r#"UPDATE blobs SET ref_count = ref_count - 1, updated_at = now() WHERE sha256 = $1
+        RETURNING storage_id, ref_count"#,
+        sha256,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    match record {
+        Some(blob) if blob.ref_count <= 0 => {
+            sqlx::query!("DELETE FROM blobs WHERE sha256 = $1", sha256)
+                .execute(&mut *conn)
+                .await?;
+            Ok(Some(blob.storage_id))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Release the contents of a deleted file or file version, along with their thumbnail.
+/// Contents with a hash hold a blob reference, while older contents are released outright.
+/// Returns the storage ids that are no longer referenced.
+pub(super) async fn release_contents(
+    conn: &mut PgConnection,
+    storage_id: Uuid,
+    sha256: Option<&str>,
+) -> Result<Vec<Uuid>> {
+    let released = match sha256 {
+        Some(sha256) => release(conn, sha256).await?,
+        None => Some(storage_id),
+    };
+    let mut storage_ids = Vec::from_iter(released);
+    if let Some(source_id) = released {
+        storage_ids.extend(thumbnail::release(conn, source_id).await?);
+    }
+    Ok(storage_ids)
+}
+
+impl Repo {
+    /// Filter storage ids down to those not referenced by any file, version, blob, thumbnail,
+    /// upload or pending presigned upload.
+    pub async fn filter_unreferenced(&self, storage_ids: &[StorageId]) -> Result<Vec<StorageId>> {
+        let ids: Vec<Uuid> = storage_ids.iter().map(|StorageId(id)| *id).collect();
+        let unreferenced = sqlx::query_scalar!(
+            r#"SELECT c.storage_id AS "storage_id!" FROM unnest($1::uuid[]) AS c(storage_id)
+            WHERE NOT EXISTS (SELECT 1 FROM story_files f WHERE f.storage_id = c.storage_id)
+            AND NOT EXISTS (SELECT 1 FROM file_versions v WHERE v.storage_id = c.storage_id)
+            AND NOT EXISTS (SELECT 1 FROM blobs b WHERE b.storage_id = c.storage_id)
+            AND NOT EXISTS (SELECT 1 FROM thumbnails t WHERE t.storage_id = c.storage_id)
+            AND NOT EXISTS (SELECT 1 FROM upload_chunks u WHERE u.storage_id = c.storage_id)
+            AND NOT EXISTS (SELECT 1 FROM presigned_uploads p WHERE p.storage_id = c.storage_id)"#,
+            &ids,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        Ok(unreferenced.into_iter().map(StorageId).collect())
+    }
+}
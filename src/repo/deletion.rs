@@ -0,0 +1,163 @@
+use super::Repo;
+use crate::{
+    domain::{StorageDeletion, StorageId},
+    Result,
+};
+use chrono::Duration;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Queue storage ids for deletion, within the transaction that released them. Failed malware
+/// scans and pending hashes of the contents are forgotten along with them.
+pub(super) async fn enqueue(conn: &mut PgConnection, storage_ids: &[Uuid]) -> Result<()> {
+    if storage_ids.is_empty() {
+        return Ok(());
+    }
+    sqlx::query!(
+        r#"INSERT INTO storage_deletions (storage_id) SELECT * FROM unnest($1::uuid[])
+        ON CONFLICT DO NOTHING"#,
+        storage_ids,
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM scan_failures WHERE storage_id = ANY($1)",
+        storage_ids,
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM pending_hashes WHERE storage_id = ANY($1)",
+        storage_ids,
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Extend repo with queries related to queued storage deletions.
+impl Repo {
+    /// Lease a batch of due storage deletions, hiding them from other workers for a while.
+    pub async fn lease_deletions(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<StorageDeletion>> {
+        let records = sqlx::query!(
+            r#"UPDATE storage_deletions
+            SET attempts = attempts + 1, next_attempt_at = now() + make_interval(secs => $2),
+            updated_at = now()
+            WHERE storage_id IN (
+                SELECT storage_id FROM storage_deletions WHERE next_attempt_at <= now()
+                ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING storage_id, attempts"#,
+            limit,
+            lease.num_seconds() as f64,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        let deletions = records
+            .into_iter()
+            .map(|r| StorageDeletion {
+                storage_id: StorageId(r.storage_id),
+                attempts: r.attempts,
+            })
+            .collect();
+        Ok(deletions)
+    }
+
+    /// Remove a storage deletion from the queue once it succeeds.
+    pub async fn complete_deletion(&self, &StorageId(storage_id): &StorageId) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM storage_deletions WHERE storage_id = $1",
+            storage_id
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// Record a failed storage deletion, scheduling the next attempt after a delay.
+    pub async fn retry_deletion(
+        &self,
+        &StorageId(storage_id): &StorageId,
+        error: impl Into<String>,
+        delay: Duration,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE storage_deletions
+            SET last_error = $2, next_attempt_at = now() + make_interval(secs => $3),
+            updated_at = now()
+            WHERE storage_id = $1"#,
+            storage_id,
+            error.into(),
+            delay.num_seconds() as f64,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Create a story with a file
+        let story = repo.create_story("Attachments").await.unwrap();
+        let storage_id = StorageId(Uuid::new_v4());
+        let sha256 = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".to_string();
+        let content_type = "text/plain".to_string();
+        let file = repo
+            .create_file(
+                &story.id,
+                &storage_id,
+                "foo.txt".into(),
+                3,
+                content_type,
+                sha256,
+            )
+            .await
+            .unwrap();
+
+        // Deleting the file queues its contents for deletion
+        repo.delete_file(file).await.unwrap();
+        let lease = Duration::seconds(60);
+        let deletions = repo.lease_deletions(10, lease).await.unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].storage_id, storage_id);
+        assert_eq!(deletions[0].attempts, 1);
+
+        // Leased deletions are hidden until retried
+        assert!(repo.lease_deletions(10, lease).await.unwrap().is_empty());
+        repo.retry_deletion(&storage_id, "unavailable", Duration::zero())
+            .await
+            .unwrap();
+        let deletions = repo.lease_deletions(10, lease).await.unwrap();
+        assert_eq!(deletions[0].attempts, 2);
+
+        // Completed deletions are removed from the queue
+        repo.complete_deletion(&storage_id).await.unwrap();
+        repo.retry_deletion(&storage_id, "unavailable", Duration::zero())
+            .await
+            .unwrap();
+        assert!(repo.lease_deletions(10, lease).await.unwrap().is_empty());
+
+        // Cleanup
+        repo.delete_story(&story.id).await.unwrap();
+    }
+}
@@ -1,9 +1,11 @@
-use super::Repo;
+use super::{blob, deletion, version, Repo};
 use crate::{
-    domain::{StorageId, StoryFile, StoryFileId, StoryId},
+    domain::{ScanStatus, StorageId, StoryFile, StoryFileId, StoryId, Usage},
     Error, Result,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgConnection;
+use std::str::FromStr;
 use uuid::Uuid;
 
 // Defines a reasonable limit on the max files per story.
@@ -11,13 +13,17 @@ const MAX_FILES: i16 = 100;
 
 /// The task entity object - used for query validation against the database.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct StoryFileEntity {
+pub(super) struct StoryFileEntity {
     pub id: Uuid,
     pub story_id: Uuid,
     pub storage_id: Uuid,
     pub name: String,
     pub size: i64,
     pub content_type: String,
+    pub sha256: Option<String>,
+    pub scan_status: String,
+    pub version: i32,
+    pub description: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -32,14 +38,55 @@ impl From<StoryFileEntity> for StoryFile {
             name: entity.name,
             size: entity.size,
             content_type: entity.content_type,
+            sha256: entity.sha256,
+            scan_status: ScanStatus::from_str(&entity.scan_status).unwrap_or_default(),
+            version: entity.version,
+            description: entity.description,
             created_at: entity.created_at,
             updated_at: entity.updated_at,
         }
     }
 }
 
+/// Insert a new file metadata row within a transaction, referencing the blob for its content
+/// hash. When the content already exists, the file points to the existing blob instead.
+/// Contents without a hash yet are referenced outright.
+pub(super) async fn insert(
+    conn: &mut PgConnection,
+    story_id: Uuid,
+    storage_id: Uuid,
+    name: String,
+    size: i64,
+    content_type: String,
+    sha256: Option<String>,
+) -> Result<StoryFile> {
+    if size <= 0 {
+        return Err(Error::invalid_args("file size must be > 0"));
+    }
+    let storage_id = match &sha256 {
+        Some(sha256) => blob::acquire(&mut *conn, sha256, storage_id, size).await?,
+        None => storage_id,
+    };
+    let query = sqlx::query_as!(
+        StoryFileEntity,
+        r#"INSERT INTO story_files (story_id, storage_id, name, size, content_type, sha256)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+        version, description, created_at, updated_at"#,
+        story_id,
+        storage_id,
+        name,
+        size,
+        content_type,
+        sha256,
+    );
+    let entity = query.fetch_one(conn).await?;
+    Ok(StoryFile::from(entity))
+}
+
 impl Repo {
-    /// Insert a new file metadata row.
+    /// Insert a new file metadata row, referencing the blob for its content hash. When the
+    /// content already exists, the file points to the existing blob instead of the given one.
     pub async fn create_file(
         &self,
         &StoryId(story_id): &StoryId,
@@ -47,31 +94,164 @@ impl Repo {
         name: String,
         size: i64,
         content_type: String,
+        sha256: String,
     ) -> Result<StoryFile> {
-        if size <= 0 {
-            return Err(Error::invalid_args("file size must be > 0"));
-        }
-        let query = sqlx::query_as!(
-            StoryFileEntity,
-            r#"INSERT INTO story_files (story_id, storage_id, name, size, content_type)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING id, story_id, storage_id, name, size, content_type, created_at, updated_at"#,
+        let mut tx = self.db.begin().await?;
+        let file = insert(
+            &mut tx,
             story_id,
             storage_id,
             name,
             size,
             content_type,
-        );
-        let entity = query.fetch_one(self.db_ref()).await?;
-        Ok(StoryFile::from(entity))
+            Some(sha256),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    /// Record the malware scan status of stored contents, for every file and file version
+    /// referencing them. Earlier failed scans are forgotten once the contents are scanned,
+    /// though the last error is kept for contents given up on.
+    pub async fn update_scan_status(
+        &self,
+        &StorageId(storage_id): &StorageId,
+        status: ScanStatus,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query!(
+            r#"UPDATE story_files SET scan_status = $2, updated_at = now()
+            WHERE storage_id = $1"#,
+            storage_id,
+            status.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE file_versions SET scan_status = $2 WHERE storage_id = $1",
+            storage_id,
+            status.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        if matches!(status, ScanStatus::Clean | ScanStatus::Infected) {
+            sqlx::query!(
+                "DELETE FROM scan_failures WHERE storage_id = $1",
+                storage_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record a failed malware scan of stored contents, with a retry delay doubling from a
+    /// base delay on each attempt. Returns the number of failed attempts so far.
+    pub async fn record_scan_failure(
+        &self,
+        &StorageId(storage_id): &StorageId,
+        error: &str,
+        delay: Duration,
+    ) -> Result<i32> {
+        let attempts = sqlx::query_scalar!(
+            r#"INSERT INTO scan_failures (storage_id, last_error, next_attempt_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3))
+            ON CONFLICT (storage_id) DO UPDATE
+            SET attempts = scan_failures.attempts + 1, last_error = excluded.last_error,
+            next_attempt_at = now() + make_interval(
+                secs => $3 * power(2, least(scan_failures.attempts, 10))
+            ),
+            updated_at = now()
+            RETURNING attempts"#,
+            storage_id,
+            error,
+            delay.num_seconds() as f64,
+        )
+        .fetch_one(self.db_ref())
+        .await?;
+        Ok(attempts)
+    }
+
+    /// List the storage ids of files that have been waiting for a malware scan for a while,
+    /// and are due a retry if scanning them failed before. Contents that failed the fewest
+    /// times come first, so failing contents can't hold up new ones.
+    pub async fn list_pending_scans(&self, age: Duration, limit: i64) -> Result<Vec<StorageId>> {
+        let storage_ids = sqlx::query_scalar!(
+            r#"SELECT c.storage_id AS "storage_id!" FROM (
+                SELECT storage_id, min(updated_at) AS updated_at FROM (
+                    SELECT storage_id, scan_status, updated_at FROM story_files
+                    UNION ALL SELECT storage_id, scan_status, replaced_at FROM file_versions
+                ) p
+                WHERE scan_status = 'pending'
+                GROUP BY storage_id
+            ) c
+            LEFT JOIN scan_failures f ON f.storage_id = c.storage_id
+            WHERE c.updated_at <= now() - make_interval(secs => $1)
+            AND (f.next_attempt_at IS NULL OR f.next_attempt_at <= now())
+            ORDER BY coalesce(f.attempts, 0), c.updated_at
+            LIMIT $2"#,
+            age.num_seconds() as f64,
+            limit,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        Ok(storage_ids.into_iter().map(StorageId).collect())
+    }
+
+    /// Sum the size of files and their prior versions, and count the files for a story.
+    /// Unexpired resumable uploads count as files of their full length, which they reserve.
+    pub async fn story_usage(&self, &StoryId(story_id): &StoryId) -> Result<Usage> {
+        let record = sqlx::query!(
+            r#"SELECT (
+                coalesce(sum(f.size), 0) + coalesce(sum(
+                    (SELECT sum(v.size) FROM file_versions v WHERE v.file_id = f.id)
+                ), 0) + (
+                    SELECT coalesce(sum(u.upload_length), 0) FROM uploads u
+                    WHERE u.story_id = $1 AND u.expires_at > now()
+                )
+            )::bigint AS "bytes!", (count(*) + (
+                SELECT count(*) FROM uploads u WHERE u.story_id = $1 AND u.expires_at > now()
+            )) AS "files!"
+            FROM story_files f WHERE f.story_id = $1"#,
+            story_id,
+        )
+        .fetch_one(self.db_ref())
+        .await?;
+        Ok(Usage {
+            bytes: record.bytes,
+            files: record.files,
+        })
+    }
+
+    /// Sum the size of files and their prior versions, and count the files for all stories.
+    /// Unexpired resumable uploads count as files of their full length, which they reserve.
+    pub async fn total_usage(&self) -> Result<Usage> {
+        let record = sqlx::query!(
+            r#"SELECT (
+                coalesce(sum(size), 0) + (SELECT coalesce(sum(size), 0) FROM file_versions) + (
+                    SELECT coalesce(sum(upload_length), 0) FROM uploads WHERE expires_at > now()
+                )
+            )::bigint AS "bytes!", (count(*) + (
+                SELECT count(*) FROM uploads WHERE expires_at > now()
+            )) AS "files!"
+            FROM story_files"#,
+        )
+        .fetch_one(self.db_ref())
+        .await?;
+        Ok(Usage {
+            bytes: record.bytes,
+            files: record.files,
+        })
     }
 
     /// List all files for a story.
     pub async fn list_files(&self, &StoryId(story_id): &StoryId) -> Result<Vec<StoryFile>> {
         let query = sqlx::query_as!(
             StoryFileEntity,
-            r#"SELECT id, story_id, storage_id, name, size, content_type, created_at, updated_at
-            FROM story_files WHERE story_id = $1
+            r#"SELECT id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+            version, description, created_at, updated_at FROM story_files WHERE story_id = $1
             ORDER BY created_at LIMIT $2"#,
             story_id,
             MAX_FILES as i64,
@@ -88,8 +268,8 @@ impl Repo {
     ) -> Result<StoryFile> {
         let query = sqlx::query_as!(
             StoryFileEntity,
-            r#"SELECT id, story_id, storage_id, name, size, content_type, created_at, updated_at
-            FROM story_files WHERE id = $1 AND story_id = $2"#,
+            r#"SELECT id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+            version, description, created_at, updated_at FROM story_files WHERE id = $1 AND story_id = $2"#,
             file_id,
             story_id,
         );
@@ -99,13 +279,117 @@ impl Repo {
         }
     }
 
-    /// Delete a file
-    pub async fn delete_file(&self, file: StoryFile) -> Result<StoryFile> {
+    /// Move a file and its prior versions to another story.
+    pub async fn move_file(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+        &StoryId(story_id): &StoryId,
+    ) -> Result<StoryFile> {
+        let query = sqlx::query_as!(
+            StoryFileEntity,
+            r#"UPDATE story_files SET story_id = $2, updated_at = now() WHERE id = $1
+            RETURNING id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+            version, description, created_at, updated_at"#,
+            file_id,
+            story_id,
+        );
+        match query.fetch_optional(self.db_ref()).await? {
+            Some(entity) => Ok(StoryFile::from(entity)),
+            None => Err(Error::not_found(format!("file not found: {file_id}"))),
+        }
+    }
+
+    /// Copy the current contents and metadata of a file to another story, taking another
+    /// reference to its blob. The source file is locked, so it can't be deleted mid-copy.
+    pub async fn copy_file(
+        &self,
+        file: &StoryFile,
+        &StoryId(story_id): &StoryId,
+    ) -> Result<StoryFile> {
         let StoryFileId(file_id) = file.id;
-        sqlx::query!("DELETE FROM story_files WHERE id = $1", file_id)
-            .execute(self.db_ref())
-            .await?;
-        Ok(file)
+        let Some(sha256) = &file.sha256 else {
+            return Err(Error::invalid_args(format!(
+                "file {file_id} has no content hash"
+            )));
+        };
+        let mut tx = self.db.begin().await?;
+        let locked = sqlx::query!(
+            "SELECT id FROM story_files WHERE id = $1 FOR SHARE",
+            file_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if locked.is_none() {
+            return Err(Error::not_found(format!("file not found: {file_id}")));
+        }
+        let storage_id = blob::acquire(&mut tx, sha256, file.storage_id.0, file.size).await?;
+        let query = sqlx::query_as!(
+            StoryFileEntity,
+            r#"INSERT INTO story_files
+            (story_id, storage_id, name, size, content_type, sha256, scan_status, description)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+            version, description, created_at, updated_at"#,
+            story_id,
+            storage_id,
+            file.name,
+            file.size,
+            file.content_type,
+            sha256,
+            file.scan_status.to_string(),
+            file.description,
+        );
+        let entity = query.fetch_one(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(StoryFile::from(entity))
+    }
+
+    /// Update the name, content type and description of a file.
+    pub async fn update_file(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+        name: String,
+        content_type: String,
+        description: Option<String>,
+    ) -> Result<StoryFile> {
+        let query = sqlx::query_as!(
+            StoryFileEntity,
+            r#"UPDATE story_files SET name = $2, content_type = $3, description = $4,
+            updated_at = now() WHERE id = $1
+            RETURNING id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+            version, description, created_at, updated_at"#,
+            file_id,
+            name,
+            content_type,
+            description,
+        );
+        match query.fetch_optional(self.db_ref()).await? {
+            Some(entity) => Ok(StoryFile::from(entity)),
+            None => Err(Error::not_found(format!("file not found: {file_id}"))),
+        }
+    }
+
+    /// Delete a file and its prior versions, returning the storage ids of contents and thumbnails
+    /// that are no longer referenced. These are also queued for deletion, in case purging them
+    /// from storage fails.
+    pub async fn delete_file(&self, file: StoryFile) -> Result<Vec<StorageId>> {
+        let StoryFileId(file_id) = file.id;
+        let mut tx = self.db.begin().await?;
+        let mut released = version::release_file(&mut tx, file_id).await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM story_files WHERE id = $1 RETURNING storage_id, sha256",
+            file_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        // Thumbnails rendered from the contents are released along with them
+        if let Some(row) = deleted {
+            let sha256 = row.sha256.as_deref();
+            released.extend(blob::release_contents(&mut tx, row.storage_id, sha256).await?);
+        }
+        deletion::enqueue(&mut tx, &released).await?;
+        tx.commit().await?;
+        Ok(released.into_iter().map(StorageId).collect())
     }
 }
 
@@ -137,23 +421,118 @@ mod tests {
         let size: i64 = 10420;
         let content_type = "image/png".to_string();
 
+        let sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string();
+
         // Add file
         let inserted = repo
-            .create_file(&story.id, &storage_id, name, size, content_type)
+            .create_file(
+                &story.id,
+                &storage_id,
+                name.clone(),
+                size,
+                content_type.clone(),
+                sha256.clone(),
+            )
             .await
             .unwrap();
 
         // Get file
         let file = repo.fetch_file(&story.id, &inserted.id).await.unwrap();
         assert_eq!(file.storage_id, storage_id);
+        assert_eq!(file.sha256, Some(sha256.clone()));
+
+        // Files wait for a malware scan
+        assert_eq!(file.scan_status, ScanStatus::Pending);
+        let pending = repo.list_pending_scans(Duration::zero(), 10).await.unwrap();
+        assert_eq!(pending, vec![storage_id.clone()]);
+        repo.update_scan_status(&storage_id, ScanStatus::Clean)
+            .await
+            .unwrap();
+        let file = repo.fetch_file(&story.id, &inserted.id).await.unwrap();
+        assert_eq!(file.scan_status, ScanStatus::Clean);
+        assert!(repo
+            .list_pending_scans(Duration::zero(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Failed scans are retried after a delay
+        repo.update_scan_status(&storage_id, ScanStatus::Pending)
+            .await
+            .unwrap();
+        let attempts = repo
+            .record_scan_failure(&storage_id, "timed out", Duration::zero())
+            .await
+            .unwrap();
+        assert_eq!(attempts, 1);
+        let pending = repo.list_pending_scans(Duration::zero(), 10).await.unwrap();
+        assert_eq!(pending, vec![storage_id.clone()]);
+        let attempts = repo
+            .record_scan_failure(&storage_id, "timed out", Duration::minutes(5))
+            .await
+            .unwrap();
+        assert_eq!(attempts, 2);
+        assert!(repo
+            .list_pending_scans(Duration::zero(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        repo.update_scan_status(&storage_id, ScanStatus::Clean)
+            .await
+            .unwrap();
+
+        // Only unknown storage ids are unreferenced
+        let unknown = StorageId(Uuid::new_v4());
+        let ids = [storage_id.clone(), unknown.clone()];
+        let unreferenced = repo.filter_unreferenced(&ids).await.unwrap();
+        assert_eq!(unreferenced, vec![unknown]);
+
+        // Rename and describe the file
+        let file = repo
+            .update_file(
+                &file.id,
+                "Diagrams.png".to_string(),
+                file.content_type,
+                Some("Login flow".to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(file.name, "Diagrams.png");
+        assert_eq!(file.description.as_deref(), Some("Login flow"));
+        assert!(file.updated_at > file.created_at);
+
+        // Copy the file to another story, sharing its contents, then move the copy back
+        let other = repo.create_story("Archive".to_string()).await.unwrap();
+        let copy = repo.copy_file(&file, &other.id).await.unwrap();
+        assert_eq!((&copy.story_id, &copy.storage_id), (&other.id, &storage_id));
+        assert_eq!(repo.story_usage(&other.id).await.unwrap().files, 1);
+        let copy = repo.move_file(&copy.id, &story.id).await.unwrap();
+        assert_eq!(copy.story_id, story.id);
+        assert!(repo.delete_file(copy).await.unwrap().is_empty());
+        repo.delete_story(&other.id).await.unwrap();
 
         // List files
         let files = repo.list_files(&story.id).await.unwrap();
         assert_eq!(files.len(), 1);
         assert!(files.contains(&file));
 
-        // Delete file
-        repo.delete_file(file).await.unwrap();
+        // Add a duplicate file, which shares the stored contents of the first
+        let duplicate = repo
+            .create_file(
+                &story.id,
+                &StorageId(Uuid::new_v4()),
+                name,
+                size,
+                content_type,
+                sha256,
+            )
+            .await
+            .unwrap();
+        assert_eq!(duplicate.storage_id, storage_id);
+
+        // Delete files, releasing the stored contents with the last reference
+        assert!(repo.delete_file(duplicate).await.unwrap().is_empty());
+        assert_eq!(repo.delete_file(file).await.unwrap(), vec![storage_id]);
         let files = repo.list_files(&story.id).await.unwrap();
         assert!(files.is_empty());
 
@@ -0,0 +1,132 @@
+use super::Repo;
+use crate::{
+    domain::{PendingMigration, StorageId},
+    Result,
+};
+use uuid::Uuid;
+
+// Extend repo with queries related to migrating contents between storage backends.
+impl Repo {
+    /// List a page of stored file contents, file versions, thumbnails and upload chunks that
+    /// have not been migrated to a destination yet, ordered by storage id.
+    pub async fn list_pending_migrations(
+        &self,
+        destination: &str,
+        after: Option<&StorageId>,
+        limit: i64,
+    ) -> Result<Vec<PendingMigration>> {
+        let after = after.map(|id| id.0).unwrap_or(Uuid::nil());
+        let records = sqlx::query!(
+            r#"SELECT c.storage_id AS "storage_id!", max(c.size) AS "size!", max(c.sha256) AS sha256
+            FROM (
+                SELECT storage_id, size, sha256 FROM story_files
+                UNION ALL SELECT storage_id, size, sha256 FROM file_versions
+                UNION ALL SELECT storage_id, size, sha256 FROM thumbnails
+                UNION ALL SELECT storage_id, size, NULL FROM upload_chunks
+            ) c
+            WHERE c.storage_id > $2 AND NOT EXISTS (
+                SELECT 1 FROM storage_migrations m
+                WHERE m.destination = $1 AND m.storage_id = c.storage_id
+            )
+            GROUP BY c.storage_id
+            ORDER BY c.storage_id
+            LIMIT $3"#,
+            destination,
+            after,
+            limit,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        let pending = records
+            .into_iter()
+            .map(|r| PendingMigration {
+                storage_id: StorageId(r.storage_id),
+                size: r.size,
+                sha256: r.sha256,
+            })
+            .collect();
+        Ok(pending)
+    }
+
+    /// Record that stored contents were copied to a destination and verified.
+    pub async fn complete_migration(
+        &self,
+        destination: &str,
+        &StorageId(storage_id): &StorageId,
+        size: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO storage_migrations (destination, storage_id, size) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING"#,
+            destination,
+            storage_id,
+            size,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Create a story with two files sharing contents
+        let story = repo.create_story("Attachments").await.unwrap();
+        let storage_id = StorageId(Uuid::new_v4());
+        let sha256 = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".to_string();
+        for name in ["foo.txt", "bar.txt"] {
+            repo.create_file(
+                &story.id,
+                &storage_id,
+                name.into(),
+                3,
+                "text/plain".into(),
+                sha256.clone(),
+            )
+            .await
+            .unwrap();
+        }
+
+        // Shared contents are only migrated once
+        let pending = repo
+            .list_pending_migrations("dest", None, 10)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].storage_id, storage_id);
+        assert_eq!(pending[0].size, 3);
+        assert_eq!(pending[0].sha256, Some(sha256));
+
+        // Pages start after a storage id
+        let after = Some(&storage_id);
+        let pending = repo.list_pending_migrations("dest", after, 10).await;
+        assert!(pending.unwrap().is_empty());
+
+        // Migrated contents are no longer pending for that destination
+        repo.complete_migration("dest", &storage_id, 3)
+            .await
+            .unwrap();
+        let pending = repo.list_pending_migrations("dest", None, 10).await;
+        assert!(pending.unwrap().is_empty());
+        let pending = repo.list_pending_migrations("other", None, 10).await;
+        assert_eq!(pending.unwrap().len(), 1);
+
+        // Cleanup
+        repo.delete_story(&story.id).await.unwrap();
+    }
+}
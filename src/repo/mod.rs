@@ -2,9 +2,17 @@ use crate::Error;
 use sqlx::postgres::PgPool;
 use std::sync::Arc;
 
+mod blob;
+mod deletion;
 mod file;
+mod migration;
+mod presign;
+mod repair;
 mod story;
 mod task;
+mod thumbnail;
+mod upload;
+mod version;
 
 /// Database abstraction layer.
 pub struct Repo {
@@ -53,7 +61,7 @@ mod tests {
         let pool = PgPoolOptions::new()
             .max_connections(1)
             .min_connections(1)
-            .connect(&connection_string)
+            .connect(connection_string)
             .await
             .unwrap();
 
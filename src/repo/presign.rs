@@ -0,0 +1,321 @@
+use super::{blob, deletion, file, thumbnail, Repo};
+use crate::{
+    domain::{StorageId, StoryFile, StoryId},
+    Error, Result,
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Delete the presigned uploads for a story, within the transaction that deletes the story.
+/// Returns the storage ids they were issued for, which are queued for deletion.
+pub(super) async fn release_story(conn: &mut PgConnection, story_id: Uuid) -> Result<Vec<Uuid>> {
+    let storage_ids = sqlx::query_scalar!(
+        "DELETE FROM presigned_uploads WHERE story_id = $1 RETURNING storage_id",
+        story_id,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    deletion::enqueue(conn, &storage_ids).await?;
+    Ok(storage_ids)
+}
+
+/// Claim an unexpired presigned upload for a story within a transaction, so it can only be
+/// completed once, by the story it was issued for. Returns when its url expires.
+async fn claim(conn: &mut PgConnection, story_id: Uuid, storage_id: Uuid) -> Result<DateTime<Utc>> {
+    let expires_at = sqlx::query_scalar!(
+        r#"DELETE FROM presigned_uploads
+        WHERE storage_id = $1 AND story_id = $2 AND expires_at > now()
+        AND NOT EXISTS (SELECT 1 FROM storage_deletions d WHERE d.storage_id = $1)
+        RETURNING expires_at"#,
+        storage_id,
+        story_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+    expires_at.ok_or_else(|| Error::not_found(format!("upload not found: {storage_id}")))
+}
+
+// Extend repo with queries related to presigned uploads.
+impl Repo {
+    /// Record a storage id issued for a presigned upload to a story, until it expires.
+    pub async fn create_presigned_upload(
+        &self,
+        &StoryId(story_id): &StoryId,
+        &StorageId(storage_id): &StorageId,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO presigned_uploads (storage_id, story_id, expires_at)
+            VALUES ($1, $2, $3)"#,
+            storage_id,
+            story_id,
+            expires_at,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// Claim an unexpired presigned upload for a story and record it as a file, in one
+    /// transaction, so it can only be completed once. The contents are queued to be hashed once
+    /// the presigned url expires, since they can be overwritten until then.
+    pub async fn complete_presigned_upload(
+        &self,
+        &StoryId(story_id): &StoryId,
+        &StorageId(storage_id): &StorageId,
+        name: String,
+        size: i64,
+        content_type: String,
+    ) -> Result<StoryFile> {
+        let mut tx = self.db.begin().await?;
+        let expires_at = claim(&mut tx, story_id, storage_id).await?;
+        let file = file::insert(
+            &mut tx,
+            story_id,
+            storage_id,
+            name,
+            size,
+            content_type,
+            None,
+        )
+        .await?;
+        sqlx::query!(
+            "INSERT INTO pending_hashes (storage_id, next_attempt_at) VALUES ($1, $2)",
+            storage_id,
+            expires_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    /// Claim an unexpired presigned upload for a story whose contents were rejected, queueing
+    /// them for deletion. Returns the storage id to purge.
+    pub async fn reject_presigned_upload(
+        &self,
+        &StoryId(story_id): &StoryId,
+        &StorageId(storage_id): &StorageId,
+    ) -> Result<StorageId> {
+        let mut tx = self.db.begin().await?;
+        claim(&mut tx, story_id, storage_id).await?;
+        deletion::enqueue(&mut tx, &[storage_id]).await?;
+        tx.commit().await?;
+        Ok(StorageId(storage_id))
+    }
+
+    /// Lease a batch of completed presigned uploads that are due to be hashed, hiding them from
+    /// other workers for a while. The lease doubles with each attempt.
+    pub async fn lease_pending_hashes(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<StorageId>> {
+        let storage_ids = sqlx::query_scalar!(
+            r#"UPDATE pending_hashes
+            SET attempts = attempts + 1,
+            next_attempt_at = now() + make_interval(secs => $2 * power(2, least(attempts, 10)))
+            WHERE storage_id IN (
+                SELECT storage_id FROM pending_hashes WHERE next_attempt_at <= now()
+                ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING storage_id"#,
+            limit,
+            lease.num_seconds() as f64,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        Ok(storage_ids.into_iter().map(StorageId).collect())
+    }
+
+    /// Record the content hash of a completed presigned upload, for the files and versions
+    /// referencing it. When the content already exists, they are pointed to the existing blob
+    /// instead. Returns the storage ids that are no longer referenced, which are queued for
+    /// deletion.
+    pub async fn record_hash(
+        &self,
+        &StorageId(storage_id): &StorageId,
+        sha256: &str,
+        size: i64,
+    ) -> Result<Vec<StorageId>> {
+        let mut tx = self.db.begin().await?;
+        let pending = sqlx::query_scalar!(
+            "DELETE FROM pending_hashes WHERE storage_id = $1 RETURNING storage_id",
+            storage_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if pending.is_none() {
+            // Already hashed, or the contents were deleted
+            return Ok(Vec::new());
+        }
+        let blob_id = blob::acquire(&mut tx, sha256, storage_id, size).await?;
+        let files = sqlx::query!(
+            r#"UPDATE story_files SET storage_id = $2, sha256 = $3
+            WHERE storage_id = $1 AND sha256 IS NULL"#,
+            storage_id,
+            blob_id,
+            sha256,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let versions = sqlx::query!(
+            r#"UPDATE file_versions SET storage_id = $2, sha256 = $3
+            WHERE storage_id = $1 AND sha256 IS NULL"#,
+            storage_id,
+            blob_id,
+            sha256,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // Take a blob reference for each file and version, e.g. after restoring a version
+        let refs = files.rows_affected() + versions.rows_affected();
+        if refs == 0 {
+            blob::release(&mut tx, sha256).await?;
+        }
+        for _ in 1..refs {
+            blob::acquire(&mut tx, sha256, storage_id, size).await?;
+        }
+        let mut released = Vec::new();
+        if refs > 0 && blob_id != storage_id {
+            released.push(storage_id);
+            released.extend(thumbnail::release(&mut tx, storage_id).await?);
+        }
+        deletion::enqueue(&mut tx, &released).await?;
+        tx.commit().await?;
+        Ok(released.into_iter().map(StorageId).collect())
+    }
+
+    /// Delete a batch of expired presigned uploads, queueing their storage ids for deletion.
+    /// Returns the number of uploads deleted.
+    pub async fn expire_presigned_uploads(&self, limit: i64) -> Result<usize> {
+        let mut tx = self.db.begin().await?;
+        let storage_ids = sqlx::query_scalar!(
+            r#"DELETE FROM presigned_uploads WHERE storage_id IN (
+                SELECT storage_id FROM presigned_uploads WHERE expires_at <= now()
+                ORDER BY expires_at LIMIT $1 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING storage_id"#,
+            limit,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        deletion::enqueue(&mut tx, &storage_ids).await?;
+        tx.commit().await?;
+        Ok(storage_ids.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+    use chrono::Duration;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Presigned uploads can only be completed once, by the story they were issued for
+        let story = repo.create_story("Presigned".to_string()).await.unwrap();
+        let other = repo.create_story("Other".to_string()).await.unwrap();
+        let storage_id = StorageId(Uuid::new_v4());
+        let expires_at = Utc::now() + Duration::minutes(15);
+        repo.create_presigned_upload(&story.id, &storage_id, expires_at)
+            .await
+            .unwrap();
+        let complete = |story_id| {
+            let name = "upload.txt".to_string();
+            let content_type = "text/plain".to_string();
+            repo.complete_presigned_upload(story_id, &storage_id, name, 4, content_type)
+        };
+        assert!(complete(&other.id).await.is_err());
+        let (first, second) = tokio::join!(complete(&story.id), complete(&story.id));
+        let file = first.or(second).unwrap();
+        assert_eq!(file.sha256, None);
+        assert_eq!(repo.list_files(&story.id).await.unwrap().len(), 1);
+
+        // Hashes are due once the presigned url expires, and point duplicates to the blob
+        assert!(repo
+            .lease_pending_hashes(10, Duration::minutes(1))
+            .await
+            .unwrap()
+            .is_empty());
+        sqlx::query!("UPDATE pending_hashes SET next_attempt_at = now()")
+            .execute(repo.db_ref())
+            .await
+            .unwrap();
+        let leased = repo
+            .lease_pending_hashes(10, Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(leased, vec![storage_id.clone()]);
+        let sha256 = "88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589";
+        let duplicate = repo
+            .create_file(
+                &other.id,
+                &StorageId(Uuid::new_v4()),
+                "duplicate.txt".to_string(),
+                4,
+                "text/plain".to_string(),
+                sha256.to_string(),
+            )
+            .await
+            .unwrap();
+        let released = repo.record_hash(&storage_id, sha256, 4).await.unwrap();
+        assert_eq!(released, vec![storage_id.clone()]);
+        let hashed = repo.fetch_file(&story.id, &file.id).await.unwrap();
+        assert_eq!(hashed.storage_id, duplicate.storage_id);
+        assert_eq!(hashed.sha256.as_deref(), Some(sha256));
+        assert!(repo
+            .record_hash(&storage_id, sha256, 4)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Rejected uploads can't be completed, and are queued for deletion
+        let rejected = StorageId(Uuid::new_v4());
+        repo.create_presigned_upload(&story.id, &rejected, expires_at)
+            .await
+            .unwrap();
+        repo.reject_presigned_upload(&story.id, &rejected)
+            .await
+            .unwrap();
+        assert!(repo
+            .reject_presigned_upload(&story.id, &rejected)
+            .await
+            .is_err());
+
+        // Expired uploads can't be completed, and are queued for deletion
+        let expired = StorageId(Uuid::new_v4());
+        let expires_at = Utc::now() - Duration::seconds(1);
+        repo.create_presigned_upload(&story.id, &expired, expires_at)
+            .await
+            .unwrap();
+        assert!(repo
+            .reject_presigned_upload(&story.id, &expired)
+            .await
+            .is_err());
+        assert_eq!(repo.expire_presigned_uploads(10).await.unwrap(), 1);
+        let leased = repo
+            .lease_deletions(10, Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(leased.iter().any(|d| d.storage_id == rejected));
+        assert!(leased.iter().any(|d| d.storage_id == expired));
+
+        // Cleanup
+        repo.delete_story(&story.id).await.unwrap();
+        repo.delete_story(&other.id).await.unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use super::Repo;
+use crate::{
+    domain::{Repair, RepairQueue, StorageId, StorageRepair},
+    Error, Result,
+};
+use chrono::Duration;
+use std::str::FromStr;
+
+// Queue repairs between storage replicas in the database, so they survive restarts and can be
+// shared between instances.
+#[async_trait::async_trait]
+impl RepairQueue for Repo {
+    async fn queue_repair(
+        &self,
+        replica: &str,
+        &StorageId(storage_id): &StorageId,
+        repair: Repair,
+        delay: Duration,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO storage_repairs (replica, storage_id, repair, next_attempt_at)
+            VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+            ON CONFLICT (replica, storage_id) DO UPDATE
+            SET repair = excluded.repair, attempts = 0, next_attempt_at = excluded.next_attempt_at,
+            queued_at = clock_timestamp(), last_error = NULL, failed_at = NULL"#,
+            replica,
+            storage_id,
+            repair.to_string(),
+            delay.num_seconds() as f64,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_repair(&self, replica: &str, &StorageId(storage_id): &StorageId) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM storage_repairs WHERE replica = $1 AND storage_id = $2",
+            replica,
+            storage_id,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn lease_repairs(
+        &self,
+        replica: &str,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<StorageRepair>> {
+        let records = sqlx::query!(
+            r#"UPDATE storage_repairs
+            SET attempts = attempts + 1,
+            next_attempt_at = now() + make_interval(secs => $3 * power(2, least(attempts, 10)))
+            WHERE replica = $1 AND storage_id IN (
+                SELECT storage_id FROM storage_repairs
+                WHERE replica = $1 AND next_attempt_at <= now() AND failed_at IS NULL
+                ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING storage_id, repair, attempts, queued_at"#,
+            replica,
+            limit,
+            lease.num_seconds() as f64,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        records
+            .into_iter()
+            .map(|r| {
+                let repair = Repair::from_str(&r.repair).map_err(|_| {
+                    Error::internal(format!("unknown storage repair: {}", r.repair))
+                })?;
+                Ok(StorageRepair {
+                    storage_id: StorageId(r.storage_id),
+                    repair,
+                    attempts: r.attempts,
+                    queued_at: r.queued_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn complete_repair(&self, replica: &str, repair: &StorageRepair) -> Result<()> {
+        let StorageId(storage_id) = repair.storage_id;
+        sqlx::query!(
+            r#"DELETE FROM storage_repairs
+            WHERE replica = $1 AND storage_id = $2 AND queued_at = $3"#,
+            replica,
+            storage_id,
+            repair.queued_at,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn fail_repair(
+        &self,
+        replica: &str,
+        repair: &StorageRepair,
+        error: &str,
+        give_up: bool,
+    ) -> Result<()> {
+        let StorageId(storage_id) = repair.storage_id;
+        sqlx::query!(
+            r#"UPDATE storage_repairs
+            SET last_error = $4, failed_at = CASE WHEN $5 THEN now() END
+            WHERE replica = $1 AND storage_id = $2 AND queued_at = $3"#,
+            replica,
+            storage_id,
+            repair.queued_at,
+            error,
+            give_up,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+    use uuid::Uuid;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Queued repairs are leased once due, and hidden while leased
+        let storage_id = StorageId(Uuid::new_v4());
+        let lease = Duration::seconds(60);
+        repo.queue_repair("dr", &storage_id, Repair::CopyToSecondary, Duration::zero())
+            .await
+            .unwrap();
+        assert!(repo
+            .lease_repairs("other", 10, lease)
+            .await
+            .unwrap()
+            .is_empty());
+        let repairs = repo.lease_repairs("dr", 10, lease).await.unwrap();
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].repair, Repair::CopyToSecondary);
+        assert!(repo
+            .lease_repairs("dr", 10, lease)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A repair queued meanwhile replaces the leased one, and isn't completed by it
+        repo.queue_repair("dr", &storage_id, Repair::DeleteSecondary, Duration::zero())
+            .await
+            .unwrap();
+        repo.complete_repair("dr", &repairs[0]).await.unwrap();
+        let repairs = repo.lease_repairs("dr", 10, lease).await.unwrap();
+        assert_eq!(repairs[0].repair, Repair::DeleteSecondary);
+        repo.complete_repair("dr", &repairs[0]).await.unwrap();
+
+        // Failed repairs are retried after a longer lease, until given up on
+        let lease = Duration::zero();
+        repo.queue_repair("dr", &storage_id, Repair::CopyToSecondary, lease)
+            .await
+            .unwrap();
+        let repairs = repo.lease_repairs("dr", 10, lease).await.unwrap();
+        assert_eq!(repairs[0].attempts, 1);
+        repo.fail_repair("dr", &repairs[0], "storage is down", false)
+            .await
+            .unwrap();
+        let repairs = repo.lease_repairs("dr", 10, lease).await.unwrap();
+        assert_eq!(repairs[0].attempts, 2);
+        repo.fail_repair("dr", &repairs[0], "storage is down", true)
+            .await
+            .unwrap();
+        assert!(repo
+            .lease_repairs("dr", 10, lease)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Queueing another repair revives a failed one
+        repo.queue_repair("dr", &storage_id, Repair::DeleteSecondary, lease)
+            .await
+            .unwrap();
+        let repairs = repo.lease_repairs("dr", 10, lease).await.unwrap();
+        assert_eq!(repairs[0].attempts, 1);
+        repo.complete_repair("dr", &repairs[0]).await.unwrap();
+
+        // Delayed repairs aren't due until later, and can be cancelled
+        let delay = Duration::minutes(15);
+        repo.queue_repair("dr", &storage_id, Repair::CopyToSecondary, delay)
+            .await
+            .unwrap();
+        assert!(repo
+            .lease_repairs("dr", 10, lease)
+            .await
+            .unwrap()
+            .is_empty());
+        repo.cancel_repair("dr", &storage_id).await.unwrap();
+    }
+}
@@ -1,6 +1,6 @@
-use super::Repo;
+use super::{blob, deletion, presign, upload, version, Repo};
 use crate::{
-    domain::{Story, StoryId},
+    domain::{StorageId, Story, StoryId},
     Error, Result,
 };
 use chrono::{DateTime, Utc};
@@ -87,17 +87,29 @@ impl Repo {
         Ok(Story::from(entity))
     }
 
-    /// Delete a story, child files, and child tasks.
-    pub async fn delete_story(&self, &StoryId(story_id): &StoryId) -> Result<()> {
+    /// Delete a story, child files, and child tasks. Returns the storage ids of file contents
+    /// that are no longer referenced, which are also queued for deletion.
+    pub async fn delete_story(&self, &StoryId(story_id): &StoryId) -> Result<Vec<StorageId>> {
         let mut tx = self.db.begin().await?;
 
         sqlx::query!("DELETE FROM tasks WHERE story_id = $1", story_id)
             .execute(&mut *tx)
             .await?;
 
-        sqlx::query!("DELETE FROM story_files WHERE story_id = $1", story_id)
-            .execute(&mut *tx)
-            .await?;
+        let mut storage_ids = version::release_story(&mut tx, story_id).await?;
+        let files = sqlx::query!(
+            "DELETE FROM story_files WHERE story_id = $1 RETURNING storage_id, sha256",
+            story_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for file in files {
+            let sha256 = file.sha256.as_deref();
+            storage_ids.extend(blob::release_contents(&mut tx, file.storage_id, sha256).await?);
+        }
+        storage_ids.extend(upload::release_story(&mut tx, story_id).await?);
+        storage_ids.extend(presign::release_story(&mut tx, story_id).await?);
+        deletion::enqueue(&mut tx, &storage_ids).await?;
 
         sqlx::query!("DELETE FROM stories WHERE id = $1", story_id)
             .execute(&mut *tx)
@@ -105,7 +117,7 @@ impl Repo {
 
         tx.commit().await?;
 
-        Ok(())
+        Ok(storage_ids.into_iter().map(StorageId).collect())
     }
 }
 
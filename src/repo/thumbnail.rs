@@ -0,0 +1,92 @@
+use super::Repo;
+use crate::{
+    domain::{StorageId, Thumbnail},
+    Error, Result,
+};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// The thumbnail entity object - used for query validation against the database.
+struct ThumbnailEntity {
+    source_id: Uuid,
+    storage_id: Uuid,
+    content_type: String,
+    size: i64,
+    sha256: String,
+}
+
+// The repo should map the entity to the domain object in public functions.
+impl From<ThumbnailEntity> for Thumbnail {
+    fn from(entity: ThumbnailEntity) -> Self {
+        Self {
+            source_id: StorageId(entity.source_id),
+            storage_id: StorageId(entity.storage_id),
+            content_type: entity.content_type,
+            size: entity.size,
+            sha256: entity.sha256,
+        }
+    }
+}
+
+/// Remove the thumbnail rendered from released contents.
+/// Returns the storage id of the thumbnail, if there was one.
+pub(super) async fn release(conn: &mut PgConnection, source_id: Uuid) -> Result<Option<Uuid>> {
+    let storage_id = sqlx::query_scalar!(
+        "DELETE FROM thumbnails WHERE source_id = $1 RETURNING storage_id",
+        source_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(storage_id)
+}
+
+// Extend repo with queries related to thumbnails.
+impl Repo {
+    /// Select the thumbnail rendered from stored contents, if any.
+    pub async fn fetch_thumbnail(
+        &self,
+        &StorageId(source_id): &StorageId,
+    ) -> Result<Option<Thumbnail>> {
+        let query = sqlx::query_as!(
+            ThumbnailEntity,
+            r#"SELECT source_id, storage_id, content_type, size, sha256
+            FROM thumbnails WHERE source_id = $1"#,
+            source_id,
+        );
+        let entity = query.fetch_optional(self.db_ref()).await?;
+        Ok(entity.map(Thumbnail::from))
+    }
+
+    /// Record a rendered thumbnail, while its source contents are still referenced by a file.
+    /// Returns the thumbnail already recorded when another was rendered first.
+    pub async fn create_thumbnail(&self, thumbnail: Thumbnail) -> Result<Thumbnail> {
+        let StorageId(source_id) = thumbnail.source_id;
+        let mut tx = self.db.begin().await?;
+        sqlx::query!(
+            r#"INSERT INTO thumbnails (source_id, storage_id, content_type, size, sha256)
+            SELECT $1, $2, $3, $4, $5
+            WHERE EXISTS (SELECT 1 FROM story_files WHERE storage_id = $1)
+            ON CONFLICT (source_id) DO NOTHING"#,
+            source_id,
+            thumbnail.storage_id.0,
+            thumbnail.content_type,
+            thumbnail.size,
+            thumbnail.sha256,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let entity = sqlx::query_as!(
+            ThumbnailEntity,
+            r#"SELECT source_id, storage_id, content_type, size, sha256
+            FROM thumbnails WHERE source_id = $1"#,
+            source_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        match entity {
+            Some(entity) => Ok(Thumbnail::from(entity)),
+            None => Err(Error::not_found(format!("contents not found: {source_id}"))),
+        }
+    }
+}
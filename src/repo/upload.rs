@@ -0,0 +1,399 @@
+use super::{deletion, file, Repo};
+use crate::{
+    domain::{StorageId, StoryFile, StoryId, Upload, UploadId},
+    Error, Result,
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// The upload entity object - used for query validation against the database.
+struct UploadEntity {
+    id: Uuid,
+    story_id: Uuid,
+    name: String,
+    content_type: String,
+    upload_length: i64,
+    upload_offset: i64,
+    expires_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+// The repo should map the entity to the domain object in public functions.
+impl From<UploadEntity> for Upload {
+    fn from(entity: UploadEntity) -> Self {
+        Self {
+            id: UploadId(entity.id),
+            story_id: StoryId(entity.story_id),
+            name: entity.name,
+            content_type: entity.content_type,
+            length: entity.upload_length,
+            offset: entity.upload_offset,
+            expires_at: entity.expires_at,
+            created_at: entity.created_at,
+            updated_at: entity.updated_at,
+        }
+    }
+}
+
+/// Delete uploads and their chunks, queueing the stored chunks for deletion.
+/// Returns the storage ids of the chunks.
+async fn release(conn: &mut PgConnection, upload_ids: &[Uuid]) -> Result<Vec<Uuid>> {
+    let storage_ids = sqlx::query_scalar!(
+        "DELETE FROM upload_chunks WHERE upload_id = ANY($1) RETURNING storage_id",
+        upload_ids,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    sqlx::query!("DELETE FROM uploads WHERE id = ANY($1)", upload_ids)
+        .execute(&mut *conn)
+        .await?;
+    deletion::enqueue(conn, &storage_ids).await?;
+    Ok(storage_ids)
+}
+
+/// Delete the uploads for a story, within the transaction that deletes the story.
+/// Returns the storage ids of their chunks, which are queued for deletion.
+pub(super) async fn release_story(conn: &mut PgConnection, story_id: Uuid) -> Result<Vec<Uuid>> {
+    let upload_ids = sqlx::query_scalar!("SELECT id FROM uploads WHERE story_id = $1", story_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    release(conn, &upload_ids).await
+}
+
+// Extend repo with queries related to resumable uploads.
+impl Repo {
+    /// Start a resumable upload for a story.
+    pub async fn create_upload(
+        &self,
+        &StoryId(story_id): &StoryId,
+        name: String,
+        content_type: String,
+        length: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Upload> {
+        if length <= 0 {
+            return Err(Error::invalid_args("upload length must be > 0"));
+        }
+        let query = sqlx::query_as!(
+            UploadEntity,
+            r#"INSERT INTO uploads (story_id, name, content_type, upload_length, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, story_id, name, content_type, upload_length, upload_offset, expires_at,
+            created_at, updated_at"#,
+            story_id,
+            name,
+            content_type,
+            length,
+            expires_at,
+        );
+        let entity = query.fetch_one(self.db_ref()).await?;
+        Ok(Upload::from(entity))
+    }
+
+    /// Select an unexpired upload by id and story id.
+    pub async fn fetch_upload(
+        &self,
+        &StoryId(story_id): &StoryId,
+        &UploadId(upload_id): &UploadId,
+    ) -> Result<Upload> {
+        let query = sqlx::query_as!(
+            UploadEntity,
+            r#"SELECT id, story_id, name, content_type, upload_length, upload_offset, expires_at,
+            created_at, updated_at FROM uploads
+            WHERE id = $1 AND story_id = $2 AND expires_at > now()"#,
+            upload_id,
+            story_id,
+        );
+        match query.fetch_optional(self.db_ref()).await? {
+            Some(entity) => Ok(Upload::from(entity)),
+            None => Err(Error::not_found(format!("upload not found: {upload_id}"))),
+        }
+    }
+
+    /// Record a stored chunk of an upload, advancing its offset. Fails when the offset has
+    /// moved since the chunk was sent, e.g. when another request appended a chunk first.
+    pub async fn append_upload_chunk(
+        &self,
+        &UploadId(upload_id): &UploadId,
+        offset: i64,
+        &StorageId(storage_id): &StorageId,
+        size: i64,
+    ) -> Result<Upload> {
+        let mut tx = self.db.begin().await?;
+        let entity = sqlx::query_as!(
+            UploadEntity,
+            r#"UPDATE uploads SET upload_offset = upload_offset + $3, updated_at = now()
+            WHERE id = $1 AND upload_offset = $2 AND upload_offset + $3 <= upload_length
+            RETURNING id, story_id, name, content_type, upload_length, upload_offset, expires_at,
+            created_at, updated_at"#,
+            upload_id,
+            offset,
+            size,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(entity) = entity else {
+            return Err(Error::conflict(format!(
+                "upload offset is no longer {offset}: {upload_id}"
+            )));
+        };
+        sqlx::query!(
+            r#"INSERT INTO upload_chunks (upload_id, upload_offset, storage_id, size)
+            VALUES ($1, $2, $3, $4)"#,
+            upload_id,
+            offset,
+            storage_id,
+            size,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Upload::from(entity))
+    }
+
+    /// Claim a complete upload to assemble it, so that concurrent requests can't assemble it
+    /// twice. Claims older than the lease are assumed abandoned, and can be claimed again.
+    pub async fn claim_upload(
+        &self,
+        &UploadId(upload_id): &UploadId,
+        lease: Duration,
+    ) -> Result<Upload> {
+        let query = sqlx::query_as!(
+            UploadEntity,
+            r#"UPDATE uploads SET claimed_at = now(), updated_at = now()
+            WHERE id = $1 AND upload_offset = upload_length
+            AND (claimed_at IS NULL OR claimed_at <= now() - make_interval(secs => $2))
+            RETURNING id, story_id, name, content_type, upload_length, upload_offset, expires_at,
+            created_at, updated_at"#,
+            upload_id,
+            lease.num_seconds() as f64,
+        );
+        match query.fetch_optional(self.db_ref()).await? {
+            Some(entity) => Ok(Upload::from(entity)),
+            None => Err(Error::conflict(format!(
+                "upload is incomplete or already being assembled: {upload_id}"
+            ))),
+        }
+    }
+
+    /// Release the claim on an upload that failed to assemble, so it can be retried.
+    pub async fn unclaim_upload(&self, &UploadId(upload_id): &UploadId) -> Result<()> {
+        sqlx::query!(
+            "UPDATE uploads SET claimed_at = NULL, updated_at = now() WHERE id = $1",
+            upload_id,
+        )
+        .execute(self.db_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// List the storage ids of the chunks of an upload, in order.
+    pub async fn list_upload_chunks(
+        &self,
+        &UploadId(upload_id): &UploadId,
+    ) -> Result<Vec<StorageId>> {
+        let storage_ids = sqlx::query_scalar!(
+            r#"SELECT storage_id FROM upload_chunks WHERE upload_id = $1
+            ORDER BY upload_offset"#,
+            upload_id,
+        )
+        .fetch_all(self.db_ref())
+        .await?;
+        Ok(storage_ids.into_iter().map(StorageId).collect())
+    }
+
+    /// Record the file assembled from a claimed upload, deleting the upload in the same
+    /// transaction, so it can't be assembled twice. Returns the file, and the storage ids of
+    /// the upload chunks, which are queued for deletion.
+    pub async fn assemble_upload(
+        &self,
+        &UploadId(upload_id): &UploadId,
+        &StorageId(storage_id): &StorageId,
+        size: i64,
+        content_type: String,
+        sha256: String,
+    ) -> Result<(StoryFile, Vec<StorageId>)> {
+        let mut tx = self.db.begin().await?;
+        let upload = sqlx::query!(
+            "SELECT story_id, name FROM uploads WHERE id = $1 AND claimed_at IS NOT NULL FOR UPDATE",
+            upload_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(upload) = upload else {
+            return Err(Error::conflict(format!(
+                "upload is no longer claimed: {upload_id}"
+            )));
+        };
+        let storage_ids = release(&mut tx, &[upload_id]).await?;
+        let file = file::insert(
+            &mut tx,
+            upload.story_id,
+            storage_id,
+            upload.name,
+            size,
+            content_type,
+            Some(sha256),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok((file, storage_ids.into_iter().map(StorageId).collect()))
+    }
+
+    /// Delete an upload, returning the storage ids of its chunks, which are queued for deletion.
+    pub async fn delete_upload(&self, &UploadId(upload_id): &UploadId) -> Result<Vec<StorageId>> {
+        let mut tx = self.db.begin().await?;
+        let storage_ids = release(&mut tx, &[upload_id]).await?;
+        tx.commit().await?;
+        Ok(storage_ids.into_iter().map(StorageId).collect())
+    }
+
+    /// Delete a batch of expired uploads, queueing their chunks for deletion.
+    /// Returns the number of uploads deleted.
+    pub async fn expire_uploads(&self, limit: i64) -> Result<usize> {
+        let mut tx = self.db.begin().await?;
+        let upload_ids = sqlx::query_scalar!(
+            r#"SELECT id FROM uploads WHERE expires_at <= now()
+            ORDER BY expires_at LIMIT $1 FOR UPDATE SKIP LOCKED"#,
+            limit,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        release(&mut tx, &upload_ids).await?;
+        tx.commit().await?;
+        Ok(upload_ids.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Start an upload
+        let story = repo.create_story("Uploads").await.unwrap();
+        let expires_at = Utc::now() + Duration::hours(1);
+        let upload = repo
+            .create_upload(
+                &story.id,
+                "big.csv".into(),
+                "text/csv".into(),
+                10,
+                expires_at,
+            )
+            .await
+            .unwrap();
+        assert_eq!(upload.offset, 0);
+
+        // Append chunks, which must start at the current offset
+        let first = StorageId(Uuid::new_v4());
+        let upload = repo
+            .append_upload_chunk(&upload.id, 0, &first, 6)
+            .await
+            .unwrap();
+        assert_eq!(upload.offset, 6);
+        let stale = repo.append_upload_chunk(&upload.id, 0, &first, 6).await;
+        assert!(stale.is_err());
+        let overflow = repo.append_upload_chunk(&upload.id, 6, &first, 5).await;
+        assert!(overflow.is_err());
+        let second = StorageId(Uuid::new_v4());
+        let upload = repo
+            .append_upload_chunk(&upload.id, 6, &second, 4)
+            .await
+            .unwrap();
+        assert!(upload.is_complete());
+        let chunks = repo.list_upload_chunks(&upload.id).await.unwrap();
+        assert_eq!(chunks, vec![first.clone(), second.clone()]);
+
+        // Only one of several concurrent completes can claim the upload to assemble it
+        let lease = Duration::hours(1);
+        let (claimed, raced) = tokio::join!(
+            repo.claim_upload(&upload.id, lease),
+            repo.claim_upload(&upload.id, lease)
+        );
+        assert!(claimed.is_ok() ^ raced.is_ok());
+        assert!(repo.claim_upload(&upload.id, lease).await.is_err());
+        repo.unclaim_upload(&upload.id).await.unwrap();
+        assert!(repo.claim_upload(&upload.id, lease).await.is_ok());
+
+        // Uploads reserve their length in the story usage until they are assembled
+        let usage = repo.story_usage(&story.id).await.unwrap();
+        assert_eq!((usage.bytes, usage.files), (10, 1));
+
+        // Assembling the upload records the file and releases its chunks at once
+        let assembled = StorageId(Uuid::new_v4());
+        let sha256 = "0".repeat(64);
+        let (file, released) = repo
+            .assemble_upload(
+                &upload.id,
+                &assembled,
+                10,
+                "text/csv".into(),
+                sha256.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(file.name, "big.csv");
+        assert_eq!(file.storage_id, assembled);
+        assert_eq!(released, vec![first, second]);
+        assert!(repo.fetch_upload(&story.id, &upload.id).await.is_err());
+        let again = repo
+            .assemble_upload(&upload.id, &assembled, 10, "text/csv".into(), sha256)
+            .await;
+        assert!(matches!(again, Err(Error::Conflict { .. })));
+        let usage = repo.story_usage(&story.id).await.unwrap();
+        assert_eq!((usage.bytes, usage.files), (10, 1));
+        repo.delete_file(file).await.unwrap();
+
+        // Deleting an upload releases its chunks
+        let abandoned = repo
+            .create_upload(
+                &story.id,
+                "draft.csv".into(),
+                "text/csv".into(),
+                10,
+                expires_at,
+            )
+            .await
+            .unwrap();
+        let chunk = StorageId(Uuid::new_v4());
+        repo.append_upload_chunk(&abandoned.id, 0, &chunk, 4)
+            .await
+            .unwrap();
+        let released = repo.delete_upload(&abandoned.id).await.unwrap();
+        assert_eq!(released, vec![chunk]);
+        assert!(repo.fetch_upload(&story.id, &abandoned.id).await.is_err());
+
+        // Expired uploads are hidden, then deleted
+        let expires_at = Utc::now() - Duration::seconds(1);
+        let expired = repo
+            .create_upload(
+                &story.id,
+                "old.csv".into(),
+                "text/csv".into(),
+                10,
+                expires_at,
+            )
+            .await
+            .unwrap();
+        assert!(repo.fetch_upload(&story.id, &expired.id).await.is_err());
+        assert_eq!(repo.expire_uploads(10).await.unwrap(), 1);
+
+        // Cleanup
+        repo.delete_story(&story.id).await.unwrap();
+    }
+}
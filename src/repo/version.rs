@@ -0,0 +1,297 @@
+use super::{blob, file::StoryFileEntity, Repo};
+use crate::{
+    domain::{FileVersion, ScanStatus, StorageId, StoryFile, StoryFileId},
+    Error, Result,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The file version entity object - used for query validation against the database.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct FileVersionEntity {
+    pub file_id: Uuid,
+    pub version: i32,
+    pub storage_id: Uuid,
+    pub size: i64,
+    pub content_type: String,
+    pub sha256: Option<String>,
+    pub scan_status: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+// The repo should map the entity to the domain object in public functions.
+impl From<FileVersionEntity> for FileVersion {
+    fn from(entity: FileVersionEntity) -> Self {
+        Self {
+            file_id: StoryFileId(entity.file_id),
+            version: entity.version,
+            storage_id: StorageId(entity.storage_id),
+            size: entity.size,
+            content_type: entity.content_type,
+            sha256: entity.sha256,
+            scan_status: ScanStatus::from_str(&entity.scan_status).unwrap_or_default(),
+            replaced_at: entity.replaced_at,
+        }
+    }
+}
+
+/// Delete the prior versions of a file, within the transaction that deletes the file.
+/// Returns the storage ids that are no longer referenced.
+pub(super) async fn release_file(conn: &mut PgConnection, file_id: Uuid) -> Result<Vec<Uuid>> {
+    let versions = sqlx::query!(
+        "DELETE FROM file_versions WHERE file_id = $1 RETURNING storage_id, sha256",
+        file_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut storage_ids = Vec::new();
+    for version in versions {
+        let sha256 = version.sha256.as_deref();
+        storage_ids.extend(blob::release_contents(conn, version.storage_id, sha256).await?);
+    }
+    Ok(storage_ids)
+}
+
+/// Delete the prior versions of files for a story, within the transaction that deletes the
+/// story. Returns the storage ids that are no longer referenced.
+pub(super) async fn release_story(conn: &mut PgConnection, story_id: Uuid) -> Result<Vec<Uuid>> {
+    let file_ids = sqlx::query_scalar!(
+        r#"SELECT DISTINCT v.file_id FROM file_versions v
+        JOIN story_files f ON f.id = v.file_id WHERE f.story_id = $1"#,
+        story_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut storage_ids = Vec::new();
+    for file_id in file_ids {
+        storage_ids.extend(release_file(conn, file_id).await?);
+    }
+    Ok(storage_ids)
+}
+
+/// Keep the current contents of a file as a prior version, then replace them. The file row is
+/// locked, so concurrent replacements are numbered one after the other.
+async fn replace_contents(
+    conn: &mut PgConnection,
+    file_id: Uuid,
+    storage_id: Uuid,
+    size: i64,
+    content_type: String,
+    sha256: Option<String>,
+    scan_status: ScanStatus,
+) -> Result<StoryFile> {
+    let kept = sqlx::query!(
+        r#"INSERT INTO file_versions
+        (file_id, version, storage_id, size, content_type, sha256, scan_status)
+        SELECT id, version, storage_id, size, content_type, sha256, scan_status
+        FROM story_files WHERE id = $1 FOR UPDATE"#,
+        file_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if kept.rows_affected() == 0 {
+        return Err(Error::not_found(format!("file not found: {file_id}")));
+    }
+    let query = sqlx::query_as!(
+        StoryFileEntity,
+        r#"UPDATE story_files SET storage_id = $2, size = $3, content_type = $4, sha256 = $5,
+        scan_status = $6, version = version + 1, updated_at = now() WHERE id = $1
+        RETURNING id, story_id, storage_id, name, size, content_type, sha256, scan_status,
+        version, description, created_at, updated_at"#,
+        file_id,
+        storage_id,
+        size,
+        content_type,
+        sha256,
+        scan_status.to_string(),
+    );
+    let entity = query.fetch_one(conn).await?;
+    Ok(StoryFile::from(entity))
+}
+
+// Extend repo with queries related to file versions.
+impl Repo {
+    /// Upload new contents for a file, keeping the current contents as a prior version. When the
+    /// content already exists, the file points to the existing blob instead of the given one.
+    pub async fn create_version(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+        &StorageId(storage_id): &StorageId,
+        size: i64,
+        content_type: String,
+        sha256: String,
+    ) -> Result<StoryFile> {
+        if size <= 0 {
+            return Err(Error::invalid_args("file size must be > 0"));
+        }
+        let mut tx = self.db.begin().await?;
+        let storage_id = blob::acquire(&mut tx, &sha256, storage_id, size).await?;
+        let file = replace_contents(
+            &mut tx,
+            file_id,
+            storage_id,
+            size,
+            content_type,
+            Some(sha256),
+            ScanStatus::Pending,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    /// Make a prior version current again, as the newest version of the file. The replaced
+    /// contents are kept as a prior version, so nothing is lost.
+    pub async fn restore_version(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+        version: i32,
+    ) -> Result<StoryFile> {
+        let mut tx = self.db.begin().await?;
+        let query = sqlx::query_as!(
+            FileVersionEntity,
+            r#"SELECT file_id, version, storage_id, size, content_type, sha256, scan_status,
+            replaced_at FROM file_versions WHERE file_id = $1 AND version = $2"#,
+            file_id,
+            version,
+        );
+        let Some(prior) = query.fetch_optional(&mut *tx).await? else {
+            return Err(Error::not_found(format!(
+                "file version not found: {version}"
+            )));
+        };
+        // The restored contents take another reference to their blob
+        if let Some(sha256) = &prior.sha256 {
+            blob::acquire(&mut tx, sha256, prior.storage_id, prior.size).await?;
+        }
+        let status = ScanStatus::from_str(&prior.scan_status).unwrap_or_default();
+        let file = replace_contents(
+            &mut tx,
+            file_id,
+            prior.storage_id,
+            prior.size,
+            prior.content_type,
+            prior.sha256,
+            status,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(file)
+    }
+
+    /// List the prior versions of a file, newest first.
+    pub async fn list_versions(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+    ) -> Result<Vec<FileVersion>> {
+        let query = sqlx::query_as!(
+            FileVersionEntity,
+            r#"SELECT file_id, version, storage_id, size, content_type, sha256, scan_status,
+            replaced_at FROM file_versions WHERE file_id = $1 ORDER BY version DESC"#,
+            file_id,
+        );
+        let versions = query.fetch_all(self.db_ref()).await?;
+        Ok(versions.into_iter().map(FileVersion::from).collect())
+    }
+
+    /// Select a prior version of a file by number.
+    pub async fn fetch_version(
+        &self,
+        &StoryFileId(file_id): &StoryFileId,
+        version: i32,
+    ) -> Result<FileVersion> {
+        let query = sqlx::query_as!(
+            FileVersionEntity,
+            r#"SELECT file_id, version, storage_id, size, content_type, sha256, scan_status,
+            replaced_at FROM file_versions WHERE file_id = $1 AND version = $2"#,
+            file_id,
+            version,
+        );
+        match query.fetch_optional(self.db_ref()).await? {
+            Some(entity) => Ok(FileVersion::from(entity)),
+            None => Err(Error::not_found(format!(
+                "file version not found: {version}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::tests;
+
+    use testcontainers::{runners::AsyncRunner, ImageExt};
+    use testcontainers_modules::postgres::Postgres;
+
+    #[ignore]
+    #[tokio::test]
+    async fn integration_test() {
+        // Set up postgres test container backed repo
+        let image = Postgres::default().with_tag(tests::PG_VERSION_TAG);
+        let container = image.start().await.unwrap();
+        let pool = tests::setup_pg_pool(&container).await;
+        let repo = Repo::new(pool);
+
+        // Create a story with a file
+        let story = repo
+            .create_story("Release Notes".to_string())
+            .await
+            .unwrap();
+        let first = StorageId(Uuid::new_v4());
+        let file = repo
+            .create_file(
+                &story.id,
+                &first,
+                "notes.txt".to_string(),
+                10,
+                "text/plain".to_string(),
+                "a".repeat(64),
+            )
+            .await
+            .unwrap();
+        assert_eq!(file.version, 1);
+
+        // Upload a new version, keeping the first
+        let second = StorageId(Uuid::new_v4());
+        let file = repo
+            .create_version(
+                &file.id,
+                &second,
+                20,
+                "text/plain".to_string(),
+                "b".repeat(64),
+            )
+            .await
+            .unwrap();
+        assert_eq!((file.version, &file.storage_id), (2, &second));
+        assert_eq!(file.scan_status, ScanStatus::Pending);
+        let versions = repo.list_versions(&file.id).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!((versions[0].version, &versions[0].storage_id), (1, &first));
+
+        // Prior versions count towards usage, and keep their contents referenced
+        assert_eq!(repo.story_usage(&story.id).await.unwrap().bytes, 30);
+        let ids = [first.clone(), second.clone()];
+        assert!(repo.filter_unreferenced(&ids).await.unwrap().is_empty());
+
+        // Restore the first version as the third
+        let file = repo.restore_version(&file.id, 1).await.unwrap();
+        assert_eq!((file.version, &file.storage_id), (3, &first));
+        let version = repo.fetch_version(&file.id, 2).await.unwrap();
+        assert_eq!(version.storage_id, second);
+        assert!(repo.restore_version(&file.id, 3).await.is_err());
+
+        // Deleting the file releases every version
+        let mut released = repo.delete_file(file).await.unwrap();
+        released.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(released, expected);
+
+        // Cleanup
+        repo.delete_story(&story.id).await.unwrap();
+    }
+}
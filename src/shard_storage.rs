@@ -0,0 +1,52 @@
+use dotenvy::dotenv;
+use sqlx_todos::{config::Config, driver::storage::fs::FileStorage};
+use std::{env, error::Error};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Move files stored flat by older versions of file storage into shard dirs. Safe to run while
+/// the server is up, and to re-run. Storage settings can be read from env vars with a prefix,
+/// e.g. for a file storage replica. With --remove-temp, temp files left by interrupted writes
+/// are also removed once unmodified for an hour.
+///
+/// Usage: shard-storage [--prefix <env var prefix>] [--remove-temp]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Load env vars and tracing subscriber
+    dotenv().ok();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    // Parse args
+    let mut prefix = None;
+    let mut remove_temp = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => prefix = Some(args.next().ok_or("prefix not set")?),
+            "--remove-temp" => remove_temp = true,
+            _ => return Err(format!("unknown argument: {arg}").into()),
+        }
+    }
+
+    // Set up file storage
+    let config = match &prefix {
+        Some(prefix) => Config::load_with_storage_prefix(prefix),
+        None => Config::default(),
+    };
+    if config.storage_type != "file" {
+        return Err(format!("{} storage isn't sharded", config.storage_type).into());
+    }
+    let storage = FileStorage::new(config.storage_bucket.clone()).validate()?;
+
+    // Shard files, then clean up temp files
+    let moved = storage.shard_flat_files().await?;
+    println!("moved {moved} files into shard dirs");
+    if remove_temp {
+        let removed = storage.remove_stale_temp_files().await?;
+        println!("removed {removed} stale temp files");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use crate::{
+    action::{hash::HashPendingFiles, scan::ScanPendingFiles, storage::RetryDeletions},
+    api::Ctx,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+// How often to check for queued storage deletions and expired uploads.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+// The max number of queued storage deletions, expired uploads, pending scans or pending hashes
+// per poll.
+const BATCH_SIZE: i64 = 100;
+
+// How often to check for files still waiting for a malware scan.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long a file waits for its upload scan before the worker scans it.
+const SCAN_GRACE_SECS: i64 = 300;
+
+// How often to check for presigned uploads waiting to be hashed.
+const HASH_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Retries failed storage deletions in the background until they succeed, and deletes expired
+/// resumable and presigned uploads.
+pub struct DeletionWorker {
+    ctx: Arc<Ctx>,
+}
+
+impl DeletionWorker {
+    /// Create a new deletion worker with context pointer state.
+    pub fn new(ctx: Arc<Ctx>) -> Self {
+        Self { ctx }
+    }
+
+    /// Run the worker as a background task.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    /// Poll the deletion queue forever.
+    async fn run(self) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.ctx.repo.expire_uploads(BATCH_SIZE).await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {} resumable uploads", expired),
+                Err(err) => tracing::error!("unable to expire resumable uploads: {}", err),
+            }
+            match self.ctx.repo.expire_presigned_uploads(BATCH_SIZE).await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {} presigned uploads", expired),
+                Err(err) => tracing::error!("unable to expire presigned uploads: {}", err),
+            }
+            match RetryDeletions::execute(Arc::clone(&self.ctx), BATCH_SIZE).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("purged {} queued deletions from storage", purged),
+                Err(err) => tracing::error!("unable to retry storage deletions: {}", err),
+            }
+        }
+    }
+}
+
+/// Scans files left waiting for a malware scan in the background.
+pub struct ScanWorker {
+    ctx: Arc<Ctx>,
+}
+
+impl ScanWorker {
+    /// Create a new scan worker with context pointer state.
+    pub fn new(ctx: Arc<Ctx>) -> Self {
+        Self { ctx }
+    }
+
+    /// Run the worker as a background task.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    /// Poll for pending scans forever.
+    async fn run(self) {
+        let mut interval = tokio::time::interval(SCAN_POLL_INTERVAL);
+        let grace = chrono::Duration::seconds(SCAN_GRACE_SECS);
+        loop {
+            interval.tick().await;
+            match ScanPendingFiles::execute(Arc::clone(&self.ctx), grace, BATCH_SIZE).await {
+                Ok(0) => {}
+                Ok(scanned) => tracing::info!("scanned {} files left pending", scanned),
+                Err(err) => tracing::error!("unable to scan pending files: {}", err),
+            }
+        }
+    }
+}
+
+/// Computes the checksums of completed presigned uploads in the background.
+pub struct HashWorker {
+    ctx: Arc<Ctx>,
+}
+
+impl HashWorker {
+    /// Create a new hash worker with context pointer state.
+    pub fn new(ctx: Arc<Ctx>) -> Self {
+        Self { ctx }
+    }
+
+    /// Run the worker as a background task.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    /// Poll for pending hashes forever.
+    async fn run(self) {
+        let mut interval = tokio::time::interval(HASH_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            match HashPendingFiles::execute(Arc::clone(&self.ctx), BATCH_SIZE).await {
+                Ok(0) => {}
+                Ok(hashed) => tracing::info!("hashed {} presigned uploads", hashed),
+                Err(err) => tracing::error!("unable to hash presigned uploads: {}", err),
+            }
+        }
+    }
+}